@@ -0,0 +1,99 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// Define the AffinityTable struct: the replica remembered for each client prefix.
+// Entries expire after the window, and the oldest ones are evicted when the table is full.
+pub struct AffinityTable {
+    entries: HashMap<String, (String, Instant)>,
+    // Keys with their expiry time, in insertion order, for evicting the oldest entry
+    order: VecDeque<(String, Instant)>,
+    window: Duration,
+    max_entries: usize,
+}
+
+impl AffinityTable {
+    // This function is used to create an empty affinity table
+    pub fn new(window: Duration, max_entries: usize) -> Self {
+        AffinityTable {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            window,
+            max_entries,
+        }
+    }
+
+    // This function returns the replica remembered for the key, if it hasn't expired
+    pub fn get(&mut self, key: &str) -> Option<String> {
+        match self.entries.get(key) {
+            Some((replica, expires)) if *expires > Instant::now() => Some(replica.clone()),
+            Some(_) => {
+                self.entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // This function remembers the replica chosen for the key for the affinity window
+    pub fn insert(&mut self, key: &str, replica: &str) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let now = Instant::now();
+
+        // Drop expired entries from the front, then the oldest ones while the table is full
+        while let Some((_, expires)) = self.order.front() {
+            if *expires > now && self.entries.len() < self.max_entries {
+                break;
+            }
+            let (oldest, expires) = self.order.pop_front().unwrap();
+            // The key may have been inserted again since, only remove the entry this item was pushed for
+            if let Some((_, current)) = self.entries.get(&oldest) {
+                if *current == expires {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+
+        let expires = now + self.window;
+        self.entries.insert(key.to_string(), (replica.to_string(), expires));
+        self.order.push_back((key.to_string(), expires));
+
+        // Inserting a key again leaves its previous item in the queue. Drop those items once they
+        // outnumber the entries, so the queue stays within twice the size of the table.
+        if self.order.len() > 2 * self.max_entries {
+            let entries = &self.entries;
+            self.order
+                .retain(|(key, expires)| entries.get(key).is_some_and(|(_, current)| current == expires));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reinserting_a_key_keeps_the_queue_bounded() {
+        let mut table = AffinityTable::new(Duration::from_secs(60), 4);
+        for i in 0..1000 {
+            table.insert("10.0.0.0/24", &format!("replica-{}", i));
+            table.insert(&format!("10.0.{}.0/24", i % 3 + 1), "other");
+        }
+        assert!(table.order.len() <= 8);
+        assert_eq!(table.entries.len(), 4);
+        assert_eq!(table.get("10.0.0.0/24"), Some("replica-999".to_string()));
+    }
+
+    #[test]
+    fn evicts_the_oldest_entry_when_full() {
+        let mut table = AffinityTable::new(Duration::from_secs(60), 2);
+        table.insert("a", "1");
+        table.insert("b", "2");
+        table.insert("a", "3");
+        table.insert("c", "4");
+        assert_eq!(table.get("b"), None);
+        assert_eq!(table.get("a"), Some("3".to_string()));
+        assert_eq!(table.get("c"), Some("4".to_string()));
+    }
+}
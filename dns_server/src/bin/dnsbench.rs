@@ -0,0 +1,631 @@
+// Load generator and latency benchmark for a local instance of the DNS server.
+// It builds its own queries, so it doesn't depend on the modules of the server.
+use clap::{Arg, ArgAction, Command};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, UdpSocket};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::timeout;
+
+// UDP payload size advertised in the queries
+const EDNS_PAYLOAD_SIZE: u16 = 1232;
+// Open-loop senders wake up this often and send every query that is due
+const TICK: Duration = Duration::from_millis(1);
+
+// Define the QuerySpec struct: one entry of the query mix
+#[derive(Clone)]
+struct QuerySpec {
+    name: String,
+    qtype: u16,
+    weight: u64,
+}
+
+// Define the Bench struct: everything a query needs, shared by the senders
+struct Bench {
+    server: SocketAddr,
+    mix: Vec<QuerySpec>,
+    total_weight: u64,
+    // Simulated clients, each with its UDP socket
+    sources: Vec<UdpSource>,
+    tcp_percent: u64,
+    dnssec_ok: bool,
+    timeout: Duration,
+}
+
+// Query ID -> channel receiving the arrival time and response code of the answer
+type PendingQueries = Arc<Mutex<HashMap<u16, oneshot::Sender<(Instant, u8)>>>>;
+
+// Define the UdpSource struct: a socket bound to one source address, with the queries waiting for an answer
+struct UdpSource {
+    address: Ipv4Addr,
+    socket: Arc<UdpSocket>,
+    next_id: AtomicU16,
+    pending: PendingQueries,
+}
+
+// Define the Outcome enum: what happened to one query
+enum Outcome {
+    Answered { latency: Duration, rcode: u8, tcp: bool },
+    Lost,
+    Failed,
+}
+
+// Define the Stats struct, built by the collector from the outcomes
+#[derive(Default)]
+struct Stats {
+    answered: u64,
+    lost: u64,
+    failed: u64,
+    rcodes: BTreeMap<u8, u64>,
+    // Latencies in microseconds
+    udp_latencies: Vec<u64>,
+    tcp_latencies: Vec<u64>,
+}
+
+// Define the Rng struct: a small xorshift generator, good enough to pick queries
+struct Rng(u64);
+
+impl Rng {
+    // This function is used to create a generator from a seed
+    fn new(seed: u64) -> Self {
+        Rng(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // This function returns a number below n
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n.max(1)
+    }
+}
+
+impl Bench {
+    // This function picks the next query: its source, name, type and transport
+    fn pick(&self, rng: &mut Rng) -> (usize, QuerySpec, bool) {
+        let source = rng.below(self.sources.len() as u64) as usize;
+        let mut point = rng.below(self.total_weight);
+        let mut spec = self.mix[0].clone();
+        for entry in self.mix.iter() {
+            if point < entry.weight {
+                spec = entry.clone();
+                break;
+            }
+            point -= entry.weight;
+        }
+        let tcp = rng.below(100) < self.tcp_percent;
+        (source, spec, tcp)
+    }
+
+    // This function sends one query and waits for its answer
+    async fn run_query(&self, source: usize, spec: &QuerySpec, tcp: bool) -> Outcome {
+        let source = &self.sources[source];
+        if tcp {
+            let wire = build_query(0, &spec.name, spec.qtype, self.dnssec_ok);
+            return self.tcp_query(source.address, wire).await;
+        }
+
+        // Take an ID that isn't waiting for an answer on this socket
+        let (sender, receiver) = oneshot::channel();
+        let mut pending = source.pending.lock().await;
+        let mut id = source.next_id.fetch_add(1, Ordering::Relaxed);
+        while pending.contains_key(&id) {
+            id = source.next_id.fetch_add(1, Ordering::Relaxed);
+        }
+        pending.insert(id, sender);
+        drop(pending);
+
+        let wire = build_query(id, &spec.name, spec.qtype, self.dnssec_ok);
+        let sent_at = Instant::now();
+        if source.socket.send_to(&wire, self.server).await.is_err() {
+            source.pending.lock().await.remove(&id);
+            return Outcome::Failed;
+        }
+        match timeout(self.timeout, receiver).await {
+            Ok(Ok((received_at, rcode))) => Outcome::Answered {
+                latency: received_at - sent_at,
+                rcode,
+                tcp: false,
+            },
+            _ => {
+                source.pending.lock().await.remove(&id);
+                Outcome::Lost
+            }
+        }
+    }
+
+    // This function sends one query over a new TCP connection from the source address.
+    // The latency includes the TCP handshake.
+    async fn tcp_query(&self, address: Ipv4Addr, wire: Vec<u8>) -> Outcome {
+        let sent_at = Instant::now();
+        let exchange = async {
+            let socket = TcpSocket::new_v4().ok()?;
+            socket.bind(SocketAddr::new(IpAddr::V4(address), 0)).ok()?;
+            let mut stream = socket.connect(self.server).await.ok()?;
+            let mut framed = (wire.len() as u16).to_be_bytes().to_vec();
+            framed.extend_from_slice(&wire);
+            stream.write_all(&framed).await.ok()?;
+            let len = stream.read_u16().await.ok()? as usize;
+            let mut buf = vec![0; len];
+            stream.read_exact(&mut buf).await.ok()?;
+            response_rcode(&buf)
+        };
+        match timeout(self.timeout, exchange).await {
+            Ok(Some(rcode)) => Outcome::Answered {
+                latency: sent_at.elapsed(),
+                rcode,
+                tcp: true,
+            },
+            Ok(None) => Outcome::Failed,
+            Err(_) => Outcome::Lost,
+        }
+    }
+}
+
+impl UdpSource {
+    // This function binds a socket to the source address and starts reading its answers
+    async fn bind(address: Ipv4Addr) -> Result<Self, String> {
+        let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(address), 0))
+            .await
+            .map_err(|e| format!("can't bind {}: {}", address, e))?;
+        let socket = Arc::new(socket);
+        let pending: PendingQueries = Arc::new(Mutex::new(HashMap::new()));
+
+        // Spawn worker thread to match the answers with the queries waiting for them
+        let receive_socket = Arc::clone(&socket);
+        let receive_pending = Arc::clone(&pending);
+        tokio::spawn(async move {
+            let mut buf = vec![0; 65535];
+            loop {
+                let amt = match receive_socket.recv(&mut buf).await {
+                    Ok(amt) => amt,
+                    Err(_) => continue,
+                };
+                let received_at = Instant::now();
+                let rcode = match response_rcode(&buf[..amt]) {
+                    Some(rcode) => rcode,
+                    None => continue,
+                };
+                let id = u16::from_be_bytes([buf[0], buf[1]]);
+                if let Some(sender) = receive_pending.lock().await.remove(&id) {
+                    let _ = sender.send((received_at, rcode));
+                }
+            }
+        });
+
+        Ok(UdpSource {
+            address,
+            socket,
+            next_id: AtomicU16::new(0),
+            pending,
+        })
+    }
+}
+
+impl Stats {
+    // This function adds the outcome of one query
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Answered { latency, rcode, tcp } => {
+                self.answered += 1;
+                *self.rcodes.entry(rcode).or_insert(0) += 1;
+                let micros = latency.as_micros() as u64;
+                if tcp {
+                    self.tcp_latencies.push(micros);
+                } else {
+                    self.udp_latencies.push(micros);
+                }
+            }
+            Outcome::Lost => self.lost += 1,
+            Outcome::Failed => self.failed += 1,
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.answered + self.lost + self.failed
+    }
+
+    // This function prints the final report
+    fn report(&mut self, elapsed: Duration) {
+        let total = self.total();
+        let seconds = elapsed.as_secs_f64();
+        println!(
+            "Sent {} queries in {:.1}s: {} answered ({:.1} qps), {} lost ({:.2}%), {} failed",
+            total,
+            seconds,
+            self.answered,
+            per_second(self.answered, elapsed),
+            self.lost,
+            percent(self.lost, total),
+            self.failed
+        );
+        let rcodes: Vec<String> = self
+            .rcodes
+            .iter()
+            .map(|(rcode, count)| format!("{} {}", rcode_name(*rcode), count))
+            .collect();
+        println!("Response codes: {}", rcodes.join(", "));
+        for (transport, latencies) in [("UDP", &mut self.udp_latencies), ("TCP", &mut self.tcp_latencies)] {
+            if latencies.is_empty() {
+                continue;
+            }
+            latencies.sort_unstable();
+            let quantiles: Vec<String> = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p99.9", 0.999), ("max", 1.0)]
+                .iter()
+                .map(|(label, q)| format!("{} {:.3}ms", label, percentile(latencies, *q) as f64 / 1000.0))
+                .collect();
+            println!("{} latency ({} answers): {}", transport, latencies.len(), quantiles.join(" "));
+        }
+    }
+}
+
+// This function is used to parse the command line arguments
+fn parse_arguments() -> clap::ArgMatches {
+    Command::new("DNS benchmark")
+        .about("Sends a query mix to a local DNS server and reports throughput, loss and latency")
+        .arg(
+            Arg::new("server")
+                .short('s')
+                .long("server")
+                .default_value("127.0.0.1:20310")
+                .help("Address of the local DNS server"),
+        )
+        .arg(
+            Arg::new("cdn")
+                .short('n')
+                .default_value("cs5700cdn.example.com")
+                .help("Zone of the server, queried for A when no --query is given"),
+        )
+        .arg(
+            Arg::new("query")
+                .short('q')
+                .long("query")
+                .action(ArgAction::Append)
+                .help("Query of the mix as name[:type[:weight]], such as images.<zone>:A:20 (repeatable)"),
+        )
+        .arg(
+            Arg::new("duration")
+                .short('d')
+                .long("duration")
+                .default_value("10")
+                .help("Seconds of sending"),
+        )
+        .arg(
+            Arg::new("rate")
+                .short('r')
+                .long("rate")
+                .help("Open loop: queries per second, sent on schedule whether or not answers come back"),
+        )
+        .arg(
+            Arg::new("concurrency")
+                .short('c')
+                .long("concurrency")
+                .default_value("10")
+                .help("Closed loop (without --rate): queries in flight, each sent when the previous one is answered"),
+        )
+        .arg(
+            Arg::new("sources")
+                .long("sources")
+                .default_value("1")
+                .help("Number of simulated clients, bound to 127.0.0.1, 127.0.0.2 and so on"),
+        )
+        .arg(
+            Arg::new("tcp")
+                .long("tcp")
+                .default_value("0")
+                .help("Percentage of queries sent over TCP, one connection each"),
+        )
+        .arg(
+            Arg::new("dnssec")
+                .long("dnssec")
+                .action(ArgAction::SetTrue)
+                .help("Set the DO bit"),
+        )
+        .arg(
+            Arg::new("timeout_ms")
+                .long("timeout-ms")
+                .default_value("2000")
+                .help("Time after which a query is counted as lost"),
+        )
+        .get_matches()
+}
+
+#[tokio::main]
+async fn main() {
+    let matches = parse_arguments();
+    let number = |name: &str| -> u64 {
+        let value = matches.get_one::<String>(name).unwrap();
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("Error: --{} must be a number, not {}", name, value))
+    };
+
+    // Only local instances: the simulated clients are loopback addresses, and the load isn't meant for real servers
+    let server: SocketAddr = matches
+        .get_one::<String>("server")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|_| panic!("Error: --server must be an address such as 127.0.0.1:20310"));
+    if !(server.is_ipv4() && server.ip().is_loopback()) {
+        panic!("Error: the benchmark only runs against a local server (127.0.0.0/8)");
+    }
+
+    let cdn = matches.get_one::<String>("cdn").unwrap();
+    let mix: Vec<QuerySpec> = match matches.get_many::<String>("query") {
+        Some(queries) => queries
+            .map(|query| parse_query(query).unwrap_or_else(|e| panic!("Error: {}", e)))
+            .collect(),
+        None => vec![QuerySpec {
+            name: cdn.to_string(),
+            qtype: 1,
+            weight: 1,
+        }],
+    };
+    let total_weight: u64 = mix.iter().map(|spec| spec.weight).sum();
+    if total_weight == 0 {
+        panic!("Error: the query mix has no weight");
+    }
+
+    let source_count = number("sources").clamp(1, 1 << 24) as u32;
+    let mut sources = Vec::new();
+    for i in 0..source_count {
+        let address = Ipv4Addr::from(u32::from(Ipv4Addr::new(127, 0, 0, 1)) + i);
+        sources.push(UdpSource::bind(address).await.unwrap_or_else(|e| panic!("Error: {}", e)));
+    }
+
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+    let mut rng = Rng::new(seed);
+
+    let bench = Arc::new(Bench {
+        server,
+        mix,
+        total_weight,
+        sources,
+        tcp_percent: number("tcp").min(100),
+        dnssec_ok: matches.get_flag("dnssec"),
+        timeout: Duration::from_millis(number("timeout_ms")),
+    });
+    let duration = Duration::from_secs(number("duration"));
+    let rate = matches.get_one::<String>("rate").map(|_| number("rate"));
+    match rate {
+        Some(rate) => println!(
+            "Open loop at {} qps for {}s against {}, from {} sources",
+            rate,
+            duration.as_secs(),
+            server,
+            source_count
+        ),
+        None => println!(
+            "Closed loop with {} queries in flight for {}s against {}, from {} sources",
+            number("concurrency"),
+            duration.as_secs(),
+            server,
+            source_count
+        ),
+    }
+
+    // Every query reports its outcome to the collector, which prints the progress every second
+    let (outcomes, mut received) = mpsc::unbounded_channel::<Outcome>();
+    let collector = tokio::spawn(async move {
+        let mut stats = Stats::default();
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        ticker.tick().await;
+        let mut last = (0, 0);
+        let mut second = 0;
+        loop {
+            tokio::select! {
+                outcome = received.recv() => match outcome {
+                    Some(outcome) => stats.add(outcome),
+                    None => break,
+                },
+                _ = ticker.tick() => {
+                    second += 1;
+                    println!(
+                        "{:>4}s: {} answered, {} lost",
+                        second,
+                        stats.answered - last.0,
+                        stats.lost - last.1
+                    );
+                    last = (stats.answered, stats.lost);
+                }
+            }
+        }
+        stats
+    });
+
+    let start = Instant::now();
+    match rate {
+        // Open loop: send whatever is due every tick, without waiting for answers
+        Some(rate) => {
+            let mut sent: u64 = 0;
+            while start.elapsed() < duration {
+                let due = due_queries(start.elapsed(), rate);
+                while sent < due {
+                    let (source, spec, tcp) = bench.pick(&mut rng);
+                    let bench = Arc::clone(&bench);
+                    let outcomes = outcomes.clone();
+                    tokio::spawn(async move {
+                        let _ = outcomes.send(bench.run_query(source, &spec, tcp).await);
+                    });
+                    sent += 1;
+                }
+                tokio::time::sleep(TICK).await;
+            }
+        }
+        // Closed loop: each worker sends its next query once the previous one is done
+        None => {
+            let mut workers = Vec::new();
+            for i in 0..number("concurrency").max(1) {
+                let bench = Arc::clone(&bench);
+                let outcomes = outcomes.clone();
+                let mut rng = Rng::new(seed.wrapping_add(i.wrapping_mul(0x9e37_79b9_7f4a_7c15)));
+                workers.push(tokio::spawn(async move {
+                    while start.elapsed() < duration {
+                        let (source, spec, tcp) = bench.pick(&mut rng);
+                        let _ = outcomes.send(bench.run_query(source, &spec, tcp).await);
+                    }
+                }));
+            }
+            for worker in workers {
+                let _ = worker.await;
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+
+    // The collector ends once the queries still in flight are answered or lost
+    drop(outcomes);
+    let mut stats = collector.await.unwrap();
+    stats.report(elapsed);
+}
+
+// This function parses one entry of the query mix, name[:type[:weight]]
+fn parse_query(query: &str) -> Result<QuerySpec, String> {
+    let fields: Vec<&str> = query.split(':').collect();
+    let qtype = match fields.get(1) {
+        Some(qtype) => type_code(qtype).ok_or(format!("unknown type in query {}", query))?,
+        None => 1,
+    };
+    let weight = match fields.get(2) {
+        Some(weight) => weight.parse().map_err(|_| format!("bad weight in query {}", query))?,
+        None => 1,
+    };
+    if fields.len() > 3 || fields[0].is_empty() {
+        return Err(format!("query {} isn't name[:type[:weight]]", query));
+    }
+    Ok(QuerySpec {
+        name: fields[0].to_string(),
+        qtype,
+        weight,
+    })
+}
+
+// This function returns the code of a record type, by name or number
+fn type_code(qtype: &str) -> Option<u16> {
+    let code = match qtype.to_ascii_uppercase().as_str() {
+        "A" => 1,
+        "NS" => 2,
+        "CNAME" => 5,
+        "SOA" => 6,
+        "PTR" => 12,
+        "MX" => 15,
+        "TXT" => 16,
+        "AAAA" => 28,
+        "SRV" => 33,
+        "DNSKEY" => 48,
+        "ANY" => 255,
+        other => return other.strip_prefix("TYPE").unwrap_or(other).parse().ok(),
+    };
+    Some(code)
+}
+
+// This function builds a query with an OPT record
+fn build_query(id: u16, name: &str, qtype: u16, dnssec_ok: bool) -> Vec<u8> {
+    let mut wire = Vec::new();
+    wire.extend_from_slice(&id.to_be_bytes());
+    // Flags: RD
+    wire.extend_from_slice(&0x0100_u16.to_be_bytes());
+    wire.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 1]);
+    for label in name.trim_end_matches('.').split('.').filter(|label| !label.is_empty()) {
+        wire.push(label.len() as u8);
+        wire.extend_from_slice(label.as_bytes());
+    }
+    wire.push(0);
+    wire.extend_from_slice(&qtype.to_be_bytes());
+    wire.extend_from_slice(&1_u16.to_be_bytes());
+
+    wire.push(0);
+    wire.extend_from_slice(&41_u16.to_be_bytes());
+    wire.extend_from_slice(&EDNS_PAYLOAD_SIZE.to_be_bytes());
+    wire.extend_from_slice(&(if dnssec_ok { 0x8000_u32 } else { 0 }).to_be_bytes());
+    wire.extend_from_slice(&0_u16.to_be_bytes());
+    wire
+}
+
+// This function returns the response code of a DNS response, or None when it isn't one
+fn response_rcode(buf: &[u8]) -> Option<u8> {
+    if buf.len() < 12 || buf[2] & 0x80 == 0 {
+        return None;
+    }
+    Some(buf[3] & 0x0f)
+}
+
+// This function returns the latency at the given quantile of sorted latencies
+fn percentile(sorted: &[u64], quantile: f64) -> u64 {
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+// This function returns how many queries an open loop at the given rate has sent after the elapsed time
+fn due_queries(elapsed: Duration, rate: u64) -> u64 {
+    (elapsed.as_secs_f64() * rate as f64) as u64
+}
+
+// This function returns the rate of a count over the elapsed time, 0 when no time passed
+fn per_second(count: u64, elapsed: Duration) -> f64 {
+    match elapsed.as_secs_f64() {
+        seconds if seconds > 0.0 => count as f64 / seconds,
+        _ => 0.0,
+    }
+}
+
+// This function returns a part of a total in percent, 0 when the total is 0
+fn percent(part: u64, total: u64) -> f64 {
+    100.0 * part as f64 / total.max(1) as f64
+}
+
+// This function returns the name of a response code
+fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        0 => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        9 => "NOTAUTH".to_string(),
+        other => format!("RCODE{}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_uses_the_nearest_rank() {
+        let sorted: Vec<u64> = (1..=100).collect();
+        assert_eq!(percentile(&sorted, 0.0), 1);
+        assert_eq!(percentile(&sorted, 0.5), 50);
+        assert_eq!(percentile(&sorted, 0.9), 90);
+        assert_eq!(percentile(&sorted, 0.99), 99);
+        assert_eq!(percentile(&sorted, 0.999), 100);
+        assert_eq!(percentile(&sorted, 1.0), 100);
+        assert_eq!(percentile(&[7], 0.5), 7);
+        assert_eq!(percentile(&[1, 2, 3], 0.5), 2);
+    }
+
+    #[test]
+    fn open_loop_sends_on_schedule() {
+        assert_eq!(due_queries(Duration::ZERO, 20_000), 0);
+        assert_eq!(due_queries(Duration::from_millis(1), 20_000), 20);
+        assert_eq!(due_queries(Duration::from_millis(1500), 1000), 1500);
+        assert_eq!(due_queries(Duration::from_millis(999), 1), 0);
+    }
+
+    #[test]
+    fn rates_and_percentages() {
+        assert_eq!(per_second(3000, Duration::from_millis(1500)), 2000.0);
+        assert_eq!(per_second(10, Duration::ZERO), 0.0);
+        assert_eq!(percent(1, 200), 0.5);
+        assert_eq!(percent(0, 0), 0.0);
+    }
+}
@@ -0,0 +1,164 @@
+use std::net::IpAddr;
+
+// Define the CidrTrie struct: a binary radix trie giving the longest-prefix match of an IP address.
+// IPv4 and IPv6 prefixes are kept in separate trees.
+pub struct CidrTrie<T> {
+    v4: Node<T>,
+    v6: Node<T>,
+}
+
+struct Node<T> {
+    value: Option<T>,
+    children: [Option<Box<Node<T>>>; 2],
+}
+
+impl<T> Node<T> {
+    fn new() -> Self {
+        Node {
+            value: None,
+            children: [None, None],
+        }
+    }
+}
+
+impl<T> CidrTrie<T> {
+    // This function is used to create an empty trie
+    pub fn new() -> Self {
+        CidrTrie {
+            v4: Node::new(),
+            v6: Node::new(),
+        }
+    }
+
+    // This function is used to add a prefix such as "10.0.0.0/8" to the trie
+    pub fn insert(&mut self, cidr: &str, value: T) -> Result<(), String> {
+        let (ip, prefix_len) = parse_cidr(cidr)?;
+        let (mut node, bits) = match ip {
+            IpAddr::V4(ip) => (&mut self.v4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (&mut self.v6, ip.octets().to_vec()),
+        };
+        for i in 0..prefix_len as usize {
+            node = node.children[bit(&bits, i)].get_or_insert_with(|| Box::new(Node::new()));
+        }
+        node.value = Some(value);
+        Ok(())
+    }
+
+    // This function returns the value of the longest prefix containing the address, with the prefix length
+    pub fn longest_match(&self, ip: &IpAddr) -> Option<(&T, u8)> {
+        let (mut node, bits) = match ip {
+            IpAddr::V4(ip) => (&self.v4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (&self.v6, ip.octets().to_vec()),
+        };
+        let mut best = node.value.as_ref().map(|value| (value, 0));
+        for i in 0..bits.len() * 8 {
+            node = match &node.children[bit(&bits, i)] {
+                Some(child) => child,
+                None => break,
+            };
+            if let Some(value) = &node.value {
+                best = Some((value, i as u8 + 1));
+            }
+        }
+        best
+    }
+}
+
+// This function is used to parse "address/length"; a bare address is a host prefix
+pub fn parse_cidr(cidr: &str) -> Result<(IpAddr, u8), String> {
+    let (address, prefix_len) = match cidr.split_once('/') {
+        Some((address, prefix_len)) => (address, Some(prefix_len)),
+        None => (cidr, None),
+    };
+    let ip: IpAddr = address
+        .trim()
+        .parse()
+        .map_err(|_| format!("{} isn't a valid CIDR prefix", cidr))?;
+    let max_len = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match prefix_len {
+        Some(len) => len
+            .trim()
+            .parse::<u8>()
+            .map_err(|_| format!("{} isn't a valid CIDR prefix", cidr))?,
+        None => max_len,
+    };
+    if prefix_len > max_len {
+        return Err(format!("{} has a prefix longer than {} bits", cidr, max_len));
+    }
+    Ok((ip, prefix_len))
+}
+
+// This function returns the i-th most significant bit of the address
+fn bit(bytes: &[u8], i: usize) -> usize {
+    ((bytes[i / 8] >> (7 - i % 8)) & 1) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie(cidrs: &[&str]) -> CidrTrie<String> {
+        let mut trie = CidrTrie::new();
+        for cidr in cidrs.iter() {
+            trie.insert(cidr, cidr.to_string()).unwrap();
+        }
+        trie
+    }
+
+    fn longest(trie: &CidrTrie<String>, ip: &str) -> Option<(String, u8)> {
+        trie.longest_match(&ip.parse().unwrap())
+            .map(|(value, len)| (value.clone(), len))
+    }
+
+    #[test]
+    fn matches_the_longest_ipv4_prefix() {
+        let trie = trie(&["10.0.0.0/8", "10.1.0.0/16", "10.1.2.0/24", "192.0.2.7"]);
+        assert_eq!(longest(&trie, "10.1.2.3"), Some(("10.1.2.0/24".to_string(), 24)));
+        assert_eq!(longest(&trie, "10.1.3.3"), Some(("10.1.0.0/16".to_string(), 16)));
+        assert_eq!(longest(&trie, "10.200.0.1"), Some(("10.0.0.0/8".to_string(), 8)));
+        assert_eq!(longest(&trie, "192.0.2.7"), Some(("192.0.2.7".to_string(), 32)));
+        assert_eq!(longest(&trie, "192.0.2.8"), None);
+        assert_eq!(longest(&trie, "11.0.0.1"), None);
+    }
+
+    #[test]
+    fn matches_the_longest_ipv6_prefix() {
+        let trie = trie(&["2001:db8::/32", "2001:db8:1::/48", "::1/128"]);
+        assert_eq!(longest(&trie, "2001:db8:1::5"), Some(("2001:db8:1::/48".to_string(), 48)));
+        assert_eq!(longest(&trie, "2001:db8:2::5"), Some(("2001:db8::/32".to_string(), 32)));
+        assert_eq!(longest(&trie, "::1"), Some(("::1/128".to_string(), 128)));
+        assert_eq!(longest(&trie, "2001:db9::1"), None);
+    }
+
+    #[test]
+    fn keeps_ipv4_and_ipv6_apart() {
+        let trie = trie(&["0.0.0.0/0"]);
+        assert_eq!(longest(&trie, "203.0.113.9"), Some(("0.0.0.0/0".to_string(), 0)));
+        // ::/0 isn't there, and IPv4-mapped addresses aren't IPv4
+        assert_eq!(longest(&trie, "::ffff:203.0.113.9"), None);
+    }
+
+    #[test]
+    fn the_host_bits_of_a_prefix_are_ignored() {
+        let trie = trie(&["10.1.2.3/16"]);
+        assert_eq!(longest(&trie, "10.1.200.1"), Some(("10.1.2.3/16".to_string(), 16)));
+    }
+
+    #[test]
+    fn a_later_insert_replaces_the_same_prefix() {
+        let mut trie = CidrTrie::new();
+        trie.insert("10.0.0.0/8", 1).unwrap();
+        trie.insert("10.0.0.0/8", 2).unwrap();
+        assert_eq!(trie.longest_match(&"10.0.0.1".parse().unwrap()), Some((&2, 8)));
+    }
+
+    #[test]
+    fn rejects_invalid_prefixes() {
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("2001:db8::/129").is_err());
+        assert!(parse_cidr("10.0.0/8").is_err());
+        assert!(parse_cidr("10.0.0.0/x").is_err());
+        assert_eq!(parse_cidr(" 10.0.0.0 / 8 "), Ok(("10.0.0.0".parse().unwrap(), 8)));
+        assert_eq!(parse_cidr("2001:db8::1"), Ok(("2001:db8::1".parse().unwrap(), 128)));
+    }
+}
@@ -0,0 +1,288 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+// Define the Config struct, loaded from the TOML file given with -c
+#[derive(Deserialize, Clone)]
+pub struct Config {
+    // Certificates and ports of the encrypted listeners
+    pub tls: Option<TlsConfig>,
+    // Named groups of replica IP addresses
+    #[serde(default)]
+    pub pools: HashMap<String, Vec<String>>,
+    // Routing overrides for client networks, matched by longest prefix
+    #[serde(default)]
+    pub overrides: Vec<OverrideConfig>,
+    // Country and continent routing policies, evaluated before the distance ranking
+    #[serde(default)]
+    pub policies: Vec<PolicyConfig>,
+    // Content-aware routing of <bucket>.<zone> names
+    pub content_routing: Option<ContentRoutingConfig>,
+    // Session affinity of client prefixes
+    pub affinity: Option<AffinityConfig>,
+    // Canary pool receiving a share of the client prefixes
+    pub canary: Option<CanaryConfig>,
+    // Distance bias, keyed by replica IP address or pool name: the distance to a replica is divided by it
+    // (1.0 when missing, 0 drains). It doesn't split traffic in proportion; it moves the nearest-replica boundary.
+    #[serde(default, alias = "weights")]
+    pub distance_bias: HashMap<String, f64>,
+    // Capacity of the replicas, keyed by IP address
+    #[serde(default)]
+    pub capacity: HashMap<String, CapacityConfig>,
+    // Share of its capacity at which a replica stops getting new clients
+    #[serde(default = "default_capacity_threshold")]
+    pub capacity_threshold: f64,
+    // Other DNS servers to share replica health, load and client geolocations with
+    pub peers: Option<PeersConfig>,
+    // Zone file with the static records of the zone (SOA, NS, MX, TXT, admin hosts...)
+    pub zone_file: Option<String>,
+    // TSIG keys, used to authenticate zone transfers
+    #[serde(default)]
+    pub tsig_keys: Vec<TsigKeyConfig>,
+    // Secondaries allowed to transfer the static records of the zone
+    pub transfer: Option<TransferConfig>,
+    // Dynamic updates (RFC 2136) of the static records
+    pub update: Option<UpdateConfig>,
+    // Only answer the names of the zone: REFUSED outside it and NXDOMAIN for names it doesn't have.
+    // Always on with DNSSEC keys; otherwise every other name gets the replica A record, as before DNSSEC support.
+    #[serde(default)]
+    pub zone_only: bool,
+    // Forwarding of the queries outside the zone to upstream resolvers; they are refused when missing
+    pub forwarding: Option<ForwardingConfig>,
+    // Split-horizon views, selected by the client network
+    #[serde(default)]
+    pub views: Vec<ViewConfig>,
+}
+
+// Define the TlsConfig struct
+#[derive(Deserialize, Clone)]
+pub struct TlsConfig {
+    // PEM file with the certificate chain
+    pub cert: String,
+    // PEM file with the private key
+    pub key: String,
+    // Port of the DNS-over-TLS listener (usually 853), disabled when missing
+    pub dot_port: Option<u16>,
+    // Port of the DNS-over-HTTPS listener (usually 443), disabled when missing
+    pub doh_port: Option<u16>,
+}
+
+// Define the OverrideConfig struct. Exactly one of location, replica or pool should be set.
+// Overrides are also sent to the peers.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OverrideConfig {
+    // Client network, such as "203.0.113.0/24"
+    pub cidr: String,
+    // Location to use for the network instead of geolocating it
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    // Country and continent codes of that location, for routing policies
+    pub country: Option<String>,
+    pub continent: Option<String>,
+    // Replica IP address the network is pinned to
+    pub replica: Option<String>,
+    // Pool the network is pinned to
+    pub pool: Option<String>,
+}
+
+// Define the PolicyConfig struct
+#[derive(Deserialize, Clone, Debug)]
+pub struct PolicyConfig {
+    // Zone (or name below it) the policy applies to, every name when missing
+    pub zone: Option<String>,
+    // ISO 3166 country code of the clients, such as "DE"
+    pub country: Option<String>,
+    // Continent code of the clients: AF, AN, AS, EU, NA, OC or SA
+    pub continent: Option<String>,
+    // Geo-fence: matching clients may only use replicas of these pools
+    #[serde(default)]
+    pub allow_pools: Vec<String>,
+    // Matching clients prefer replicas of these pools, even when another replica is closer
+    #[serde(default)]
+    pub prefer_pools: Vec<String>,
+}
+
+// Define the ContentRoutingConfig struct
+#[derive(Deserialize, Clone)]
+pub struct ContentRoutingConfig {
+    // Bucket label (the label right below the zone) -> content group
+    pub buckets: HashMap<String, String>,
+    // Number of replicas each content group is pinned to
+    #[serde(default = "default_replicas_per_group")]
+    pub replicas_per_group: usize,
+    // Number of closest replicas the pinned ones are chosen from
+    #[serde(default = "default_nearby_replicas")]
+    pub nearby_replicas: usize,
+}
+
+fn default_replicas_per_group() -> usize {
+    2
+}
+
+fn default_nearby_replicas() -> usize {
+    3
+}
+
+// Define the AffinityConfig struct
+#[derive(Deserialize, Clone)]
+pub struct AffinityConfig {
+    // How long a client prefix keeps its replica, in seconds
+    pub window_secs: u64,
+    // CPU usage (percent) above which a client prefix is moved to another replica
+    #[serde(default = "default_affinity_max_load")]
+    pub max_load: f32,
+    // Maximum number of client prefixes remembered
+    #[serde(default = "default_affinity_max_entries")]
+    pub max_entries: usize,
+}
+
+fn default_affinity_max_load() -> f32 {
+    75.0
+}
+
+fn default_affinity_max_entries() -> usize {
+    100_000
+}
+
+// Define the CanaryConfig struct
+#[derive(Deserialize, Clone)]
+pub struct CanaryConfig {
+    // Pool of the canary replicas
+    pub pool: String,
+    // Percentage of client prefixes sent to the canary pool
+    pub percent: f64,
+}
+
+// Define the CapacityConfig struct
+#[derive(Deserialize, Clone)]
+pub struct CapacityConfig {
+    // Requests per second the replica can serve
+    pub rps: Option<f64>,
+    // Egress bandwidth of the replica in Mbps
+    pub mbps: Option<f64>,
+}
+
+fn default_capacity_threshold() -> f64 {
+    0.9
+}
+
+// Define the PeersConfig struct
+#[derive(Deserialize, Clone)]
+pub struct PeersConfig {
+    // Address the peer sync listens on, such as "0.0.0.0:20399"
+    pub listen: String,
+    // Shared secret authenticating the peer messages (HMAC-SHA256)
+    pub secret: String,
+    // Peer sync addresses of the other DNS servers
+    pub addresses: Vec<String>,
+    // Name of this server in the peer messages, the listen address when missing
+    pub node_id: Option<String>,
+    // Seconds between two syncs
+    #[serde(default = "default_peer_interval")]
+    pub interval_secs: u64,
+}
+
+fn default_peer_interval() -> u64 {
+    5
+}
+
+// Define the TsigKeyConfig struct
+#[derive(Deserialize, Clone)]
+pub struct TsigKeyConfig {
+    // Name of the key, such as "transfer.cdn.example"
+    pub name: String,
+    // hmac-sha256, hmac-sha384 or hmac-sha512
+    #[serde(default = "default_tsig_algorithm")]
+    pub algorithm: String,
+    // Base64 shared secret
+    pub secret: String,
+}
+
+fn default_tsig_algorithm() -> String {
+    "hmac-sha256".to_string()
+}
+
+// Define the TransferConfig struct
+#[derive(Deserialize, Clone)]
+pub struct TransferConfig {
+    // Secondaries, as "ip" or "ip:port" (port 53 when missing). They may transfer the zone and get a NOTIFY when it changes.
+    pub secondaries: Vec<String>,
+    // Name of the TSIG key the secondaries sign their requests with
+    pub key: String,
+}
+
+// Define the UpdateConfig struct
+#[derive(Deserialize, Clone)]
+pub struct UpdateConfig {
+    // Names of the TSIG keys allowed to update the zone
+    pub keys: Vec<String>,
+    // File the updates are appended to, and replayed from at startup
+    pub journal: String,
+}
+
+// Define the ForwardingConfig struct
+#[derive(Deserialize, Clone)]
+pub struct ForwardingConfig {
+    // Upstream resolvers, as "ip" or "ip:port" (port 53 when missing), tried in order
+    pub upstreams: Vec<String>,
+    // Time to wait for each upstream, in milliseconds
+    #[serde(default = "default_forward_timeout")]
+    pub timeout_ms: u64,
+    // Number of answers kept in the cache
+    #[serde(default = "default_forward_cache_size")]
+    pub cache_size: usize,
+    // Client networks allowed to use forwarding (loopback and private networks by default)
+    #[serde(default = "default_forward_allow")]
+    pub allow: Vec<String>,
+}
+
+fn default_forward_timeout() -> u64 {
+    1000
+}
+
+fn default_forward_cache_size() -> usize {
+    10000
+}
+
+fn default_forward_allow() -> Vec<String> {
+    ["127.0.0.0/8", "10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "::1/128", "fc00::/7"]
+        .iter()
+        .map(|cidr| cidr.to_string())
+        .collect()
+}
+
+// Define the ViewConfig struct: what the clients of some networks are answered instead of the defaults
+#[derive(Deserialize, Clone, Debug)]
+pub struct ViewConfig {
+    // Name of the view, used in logs
+    pub name: String,
+    // Client networks of the view, matched by longest prefix across all the views
+    pub cidrs: Vec<String>,
+    // Address given out for each replica, keyed by the replica IP address. The view only uses these replicas;
+    // every replica, with its own address, when empty.
+    #[serde(default)]
+    pub addresses: HashMap<String, String>,
+    // Routing policies of the view, replacing the global ones when set
+    pub policies: Option<Vec<PolicyConfig>>,
+    // TTL of the replica answers, 0 when missing
+    #[serde(default)]
+    pub ttl: u32,
+    // TTL of negative answers, 60 when missing
+    pub negative_ttl: Option<u32>,
+    // Zone file whose records replace the static records of the zone, for the names it has
+    pub zone_file: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+impl Config {
+    // This function is used to load the configuration file
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+        toml::from_str(&text).map_err(|e| format!("can't parse {}: {}", path, e))
+    }
+}
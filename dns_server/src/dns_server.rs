@@ -1,14 +1,51 @@
-use bytes::{Bytes, BytesMut};
-use dns_message_parser::rr::{A, RR};
-use dns_message_parser::{Dns, Flags, Opcode, RCode};
+use crate::affinity::AffinityTable;
+use crate::cidr_trie::CidrTrie;
+use crate::config::{Config, OverrideConfig, PolicyConfig};
+use crate::forwarder::Forwarder;
+use crate::hash_ring::{stable_hash, HashRing};
+use crate::peer_sync::{now_millis, PeerRouting, PeerSync};
+use crate::dnssec::ZoneSigner;
+use crate::message::{
+    in_zone, normalize_name, Message, Question, Record, MAX_TCP_MESSAGE, OPCODE_NOTIFY, OPCODE_QUERY, OPCODE_UPDATE,
+    RCODE_FORMERR, RCODE_NOTAUTH, RCODE_NOTIMP, RCODE_NXDOMAIN, RCODE_REFUSED, RCODE_SERVFAIL,
+    TYPE_A, TYPE_AXFR, TYPE_CNAME, TYPE_DNSKEY, TYPE_IXFR, TYPE_NS, TYPE_OPT, TYPE_SOA,
+};
+use crate::secure_listeners::{serve_doh, serve_dot, serve_tcp, tls_acceptor};
+use crate::static_zone::{serial_greater, soa_serial, StaticZone};
+use crate::tsig::{Tsig, TsigKeyring};
+use crate::update::{prepare_update, TYPE_ANY};
 use geoutils::Location;
 use ipgeolocate::{GeoError, Locator, Service};
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
-use std::net::UdpSocket;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr};
+use std::net::{SocketAddr, UdpSocket};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::Mutex;
 
+// TTL of the static records of the zone (SOA, NS, DNSKEY)
+const ZONE_TTL: u32 = 3600;
+// TTL of negative answers, also used as the SOA minimum
+const NEGATIVE_TTL: u32 = 60;
+// Measured RTT is only trusted once a client prefix has this many samples
+const MIN_RTT_SAMPLES: u32 = 10;
+// Measured RTTs not reported again for this long are ignored and dropped, such as the ones of a server that went down
+const LATENCY_TTL: Duration = Duration::from_secs(120);
+// Equivalent distance of one millisecond of RTT: light in fiber covers about 100km per RTT ms,
+// and real paths are about twice as long as the great circle
+const METERS_PER_RTT_MS: f64 = 50_000.0;
+// Points of each CDN server on the content ring
+const RING_VNODES: usize = 100;
+// Records per message of a zone transfer
+const TRANSFER_RECORDS_PER_MESSAGE: usize = 100;
+// Room left in each message of a zone transfer for the header, the question and the TSIG
+const TRANSFER_MESSAGE_OVERHEAD: usize = 1024;
+// Attempts at sending a NOTIFY, each waiting this long for the answer
+const NOTIFY_ATTEMPTS: u32 = 5;
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(3);
 
 // Define the DnsServer struct
 pub struct DnsServer {
@@ -21,11 +58,123 @@ pub struct DnsServer {
     // Port number of the DNS server
     dns_port: String,
     // Cache to store the distance between the seend client and the CDN servers
-    client_distance_cache: Arc<Mutex<HashMap<String, HashMap<String, f64>>>>,
+    client_distance_cache: Arc<Mutex<HashMap<String, ClientGeo>>>,
     // Cache to store the availability of the HTTP servers
     availability: Arc<Mutex<HashMap<String, bool>>>,
+    // Throughput reported by the HTTP servers: requests per second and egress Mbps
+    throughput: Arc<Mutex<HashMap<String, (f64, f64)>>>,
+    // When each HTTP server was last probed (by us or a peer), in milliseconds since the epoch
+    health_updated: Arc<Mutex<HashMap<String, u64>>>,
+    // Client RTT measured by the HTTP servers: client prefix -> server IP -> (samples, RTT in ms, when it was reported)
+    latency: Arc<Mutex<HashMap<String, HashMap<String, (u32, f64, Instant)>>>>,
     // Location of the DNS server
-    location: Location
+    location: Location,
+    // Name of the zone this server is authoritative for
+    zone: String,
+    // Name server published in the NS and SOA records of the zone
+    ns_name: String,
+    // Static records of the zone, from the zone file, with the SOA and its serial
+    static_zone: Arc<Mutex<Arc<StaticZone>>>,
+    // TSIG keys from the config
+    tsig_keys: Arc<TsigKeyring>,
+    // Forwarder of the queries outside the zone, if forwarding is on
+    forwarder: Option<Arc<Forwarder>>,
+    // Directory holding the DNSSEC keys, if the zone is signed
+    key_dir: Option<String>,
+    // Signer built from the keys in key_dir, reloaded in the background
+    signer: Arc<Mutex<Option<Arc<ZoneSigner>>>>,
+    // Settings loaded from the configuration file
+    config: Arc<Config>,
+    // Routing overrides of client networks, keyed by CIDR prefix: ours, and those learned from the peers
+    route_overrides: Arc<Mutex<Arc<RouteOverrides>>>,
+    // Consistent-hash ring of the CDN servers, for content-aware routing
+    content_ring: Arc<HashRing>,
+    // Replica last chosen for each client prefix, for session affinity
+    affinity: Arc<Mutex<AffinityTable>>,
+    // Number of answers per pool of the chosen replica
+    selection_metrics: Arc<Mutex<HashMap<String, u64>>>,
+    // Split-horizon views, and the index of the view of each client network
+    views: Arc<Vec<Arc<View>>>,
+    view_networks: Arc<CidrTrie<usize>>,
+}
+
+// Define the View struct: a split-horizon view built from the config
+struct View {
+    name: String,
+    // Replica IP address -> address given out in the view; every replica with its own address when empty
+    addresses: HashMap<String, Ipv4Addr>,
+    // Routing policies, the global ones when the view doesn't have its own
+    policies: Vec<PolicyConfig>,
+    ttl: u32,
+    negative_ttl: u32,
+    // Records replacing the static records of the zone for the names they have, reloaded with the zone file
+    static_zone: Option<Mutex<Arc<StaticZone>>>,
+}
+
+// Define the GeoInfo struct: the geolocation of a client
+#[derive(Clone, Debug)]
+pub(crate) struct GeoInfo {
+    location: Location,
+    // ISO 3166 country code, such as "DE"
+    country: Option<String>,
+    // Continent code, such as "EU"
+    continent: Option<String>,
+}
+
+// Define the ClientGeo struct: what we cache about a client, also shared with the peers
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ClientGeo {
+    // Distance from the client to each CDN server
+    distances: HashMap<String, f64>,
+    country: Option<String>,
+    continent: Option<String>,
+}
+
+// Define the IpApiResponse struct, the JSON answer of ip-api.com
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IpApiResponse {
+    status: String,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    country_code: Option<String>,
+    continent_code: Option<String>,
+}
+
+// Define the RouteOverride enum: what a client network is pinned to
+#[derive(Clone, Debug)]
+pub(crate) enum RouteOverride {
+    // Use this location instead of geolocating the client
+    Location(GeoInfo),
+    // Send the client to this replica while it's available
+    Replica(String),
+    // Send the client to the closest available replica of this pool
+    Pool(String),
+}
+
+// Routing overrides by client network, with the CIDR prefix each one was given for
+pub(crate) type RouteOverrides = CidrTrie<(String, RouteOverride)>;
+
+// This function turns an override, from the config or from a peer, into what it pins the network to.
+// It must name a location, one of our replicas or one of our pools.
+pub(crate) fn route_override(
+    entry: &OverrideConfig,
+    pools: &HashMap<String, Vec<String>>,
+    replicas: &HashMap<String, bool>,
+) -> Result<RouteOverride, String> {
+    match (entry.latitude, entry.longitude, &entry.replica, &entry.pool) {
+        (Some(latitude), Some(longitude), None, None) => Ok(RouteOverride::Location(GeoInfo {
+            location: Location::new(latitude, longitude),
+            country: entry.country.clone(),
+            continent: entry.continent.clone(),
+        })),
+        (None, None, Some(replica), None) if replicas.contains_key(replica) => Ok(RouteOverride::Replica(replica.clone())),
+        (None, None, None, Some(pool)) if pools.contains_key(pool) => Ok(RouteOverride::Pool(pool.clone())),
+        _ => Err(format!(
+            "override {} needs either a location, a known replica or a known pool",
+            entry.cidr
+        )),
+    }
 }
 
 // Define the CdnServerInfo struct
@@ -37,7 +186,7 @@ struct CdnServerInfo {
 
 impl DnsServer {
     // This function is used to create a new instance of the DnsServer struct
-    pub fn new(port: &str) -> Self {
+    pub fn new(port: &str, zone: &str, ns_name: &str, config: Config) -> Self {
         let mut availability: HashMap<String, bool> = HashMap::new();
         availability.insert("45.33.55.171".to_string(), true);
         availability.insert("170.187.142.220".to_string(), true);
@@ -47,6 +196,130 @@ impl DnsServer {
         availability.insert("192.53.123.145".to_string(), true);
         availability.insert("192.46.221.203".to_string(), true);
 
+        // Build the override trie from the config
+        let mut route_overrides = CidrTrie::new();
+        for entry in config.overrides.iter() {
+            let route = route_override(entry, &config.pools, &availability).unwrap_or_else(|e| panic!("Error: {}", e));
+            route_overrides
+                .insert(&entry.cidr, (entry.cidr.clone(), route))
+                .unwrap_or_else(|e| panic!("Error: {}", e));
+        }
+
+        // Routing policies, global or of a view, must name a country or a continent, and known pools
+        let view_policies = config.views.iter().filter_map(|view| view.policies.as_ref());
+        for policies in std::iter::once(&config.policies).chain(view_policies) {
+            for (i, policy) in policies.iter().enumerate() {
+                if policy.country.is_none() && policy.continent.is_none() {
+                    panic!("Error: policy #{} needs a country or a continent", i);
+                }
+                for pool in policy.allow_pools.iter().chain(policy.prefer_pools.iter()) {
+                    if !config.pools.contains_key(pool) {
+                        panic!("Error: policy #{} uses unknown pool {}", i, pool);
+                    }
+                }
+            }
+        }
+
+        if let Some(canary) = &config.canary {
+            if !config.pools.contains_key(&canary.pool) {
+                panic!("Error: canary pool {} isn't defined", canary.pool);
+            }
+            if !(0.0..=100.0).contains(&canary.percent) {
+                panic!("Error: canary percent must be between 0 and 100, not {}", canary.percent);
+            }
+        }
+        for (name, bias) in config.distance_bias.iter() {
+            if !bias.is_finite() || *bias < 0.0 {
+                panic!("Error: distance bias of {} must be a number of at least 0, not {}", name, bias);
+            }
+        }
+
+        let affinity = match &config.affinity {
+            Some(affinity) => AffinityTable::new(Duration::from_secs(affinity.window_secs), affinity.max_entries),
+            None => AffinityTable::new(Duration::ZERO, 0),
+        };
+
+        // TSIG keys, and the secondaries allowed to transfer the zone
+        let tsig_keys = TsigKeyring::new(&config.tsig_keys).unwrap_or_else(|e| panic!("Error: {}", e));
+        if let Some(transfer) = &config.transfer {
+            if !tsig_keys.contains(&transfer.key) {
+                panic!("Error: transfer key {} isn't defined", transfer.key);
+            }
+            for secondary in transfer.secondaries.iter() {
+                if server_address(secondary).is_none() {
+                    panic!("Error: secondary {} isn't an IP address", secondary);
+                }
+            }
+        }
+        if let Some(update) = &config.update {
+            for key in update.keys.iter() {
+                if !tsig_keys.contains(key) {
+                    panic!("Error: update key {} isn't defined", key);
+                }
+            }
+        }
+
+        // Upstream resolvers of the forwarding mode
+        let forwarder = config.forwarding.as_ref().map(|forwarding| {
+            let upstreams = forwarding
+                .upstreams
+                .iter()
+                .map(|upstream| {
+                    server_address(upstream).unwrap_or_else(|| panic!("Error: upstream {} isn't an IP address", upstream))
+                })
+                .collect();
+            let forwarder = Forwarder::new(
+                upstreams,
+                Duration::from_millis(forwarding.timeout_ms),
+                &forwarding.allow,
+                forwarding.cache_size,
+            );
+            Arc::new(forwarder.unwrap_or_else(|e| panic!("Error: {}", e)))
+        });
+
+        // Static records of the zone
+        let serial = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        let static_zone = StaticZone::new(
+            zone,
+            default_soa(zone, ns_name, serial),
+            config.zone_file.clone(),
+            config.update.as_ref().map(|update| update.journal.clone()),
+        )
+        .load()
+        .unwrap_or_else(|e| panic!("Error: {}", e));
+
+        // Split-horizon views
+        let mut views = Vec::new();
+        let mut view_networks = CidrTrie::new();
+        for (i, entry) in config.views.iter().enumerate() {
+            for cidr in entry.cidrs.iter() {
+                view_networks
+                    .insert(cidr, i)
+                    .unwrap_or_else(|e| panic!("Error: view {}: {}", entry.name, e));
+            }
+            let mut addresses = HashMap::new();
+            for (replica, address) in entry.addresses.iter() {
+                match address.parse::<Ipv4Addr>() {
+                    Ok(address) => addresses.insert(replica.clone(), address),
+                    Err(_) => panic!("Error: view {} gives {} the invalid address {}", entry.name, replica, address),
+                };
+            }
+            let view_zone = entry.zone_file.as_ref().map(|zone_file| {
+                let view_zone = StaticZone::new(zone, default_soa(zone, ns_name, serial), Some(zone_file.clone()), None)
+                    .load()
+                    .unwrap_or_else(|e| panic!("Error: view {}: {}", entry.name, e));
+                Mutex::new(Arc::new(view_zone))
+            });
+            views.push(Arc::new(View {
+                name: entry.name.clone(),
+                addresses,
+                policies: entry.policies.clone().unwrap_or(config.policies.clone()),
+                ttl: entry.ttl,
+                negative_ttl: entry.negative_ttl.unwrap_or(NEGATIVE_TTL),
+                static_zone: view_zone,
+            }));
+        }
+
         let dns_server = DnsServer {
             cdn_server: HashMap::new(),
             socket: UdpSocket::bind(format!("0.0.0.0:{port}")).unwrap(), // bind to 0.0.0.0 so that it can listen on all available ip addresses on the machine
@@ -55,11 +328,33 @@ impl DnsServer {
             dns_port: port.to_string(),
             client_distance_cache: Arc::new(Mutex::new(HashMap::new())),
             availability: Arc::new(Mutex::new(availability)),
+            throughput: Arc::new(Mutex::new(HashMap::new())),
+            health_updated: Arc::new(Mutex::new(HashMap::new())),
+            latency: Arc::new(Mutex::new(HashMap::new())),
             location: Location::new(40.8229, -74.4592),
+            zone: normalize_name(zone),
+            ns_name: normalize_name(ns_name),
+            static_zone: Arc::new(Mutex::new(Arc::new(static_zone))),
+            tsig_keys: Arc::new(tsig_keys),
+            forwarder,
+            key_dir: None,
+            signer: Arc::new(Mutex::new(None)),
+            config: Arc::new(config),
+            route_overrides: Arc::new(Mutex::new(Arc::new(route_overrides))),
+            content_ring: Arc::new(HashRing::new(&[], 0)),
+            affinity: Arc::new(Mutex::new(affinity)),
+            selection_metrics: Arc::new(Mutex::new(HashMap::new())),
+            views: Arc::new(views),
+            view_networks: Arc::new(view_networks),
         };
         dns_server
     }
 
+    // This function is used to sign the zone with the keys found in the given directory
+    pub fn enable_dnssec(&mut self, key_dir: &str) {
+        self.key_dir = Some(key_dir.to_string());
+    }
+
     // This function is used to init a CDN server information to the cdn_server hashmap
     pub async fn init_cdn_geolocation(&mut self) {
         let mut cpu = self.cpu_usage.lock().await;
@@ -133,6 +428,89 @@ impl DnsServer {
         // Get the geo location of the CDN servers
         self.init_cdn_geolocation().await;
 
+        // Place the CDN servers on the content ring
+        let cdn_ips: Vec<String> = self.cdn_server.keys().cloned().collect();
+        self.content_ring = Arc::new(HashRing::new(&cdn_ips, RING_VNODES));
+
+        // Load the DNSSEC keys, and reload them every minute so a key rollover only needs renaming key files.
+        // The first load must work: a server given keys never answers unsigned.
+        if let Some(key_dir) = self.key_dir.clone() {
+            let signer = ZoneSigner::load(&self.zone, &key_dir)
+                .unwrap_or_else(|e| panic!("Error: can't load DNSSEC keys: {}", e));
+            let mut published_ds = signer.ds_records();
+            for record in published_ds.iter() {
+                println!("{}", record);
+            }
+            *self.signer.lock().await = Some(Arc::new(signer));
+
+            let signer_ptr = Arc::clone(&self.signer);
+            let zone = self.zone.clone();
+            tokio::spawn(async move {
+                loop {
+                    // Sleep for 60 seconds
+                    tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+
+                    match ZoneSigner::load(&zone, &key_dir) {
+                        Ok(signer) => {
+                            // Print the DS records whenever the key set changes
+                            let ds = signer.ds_records();
+                            if ds != published_ds {
+                                for record in ds.iter() {
+                                    println!("{}", record);
+                                }
+                                published_ds = ds;
+                            }
+                            *signer_ptr.lock().await = Some(Arc::new(signer));
+                        }
+                        Err(e) => {
+                            // Keep signing with the last good key set
+                            dbg!(format!("Error: can't load DNSSEC keys: {}", e));
+                        }
+                    }
+                }
+            });
+        }
+
+        for (ip, cdn_server) in self.cdn_server.iter() {
+            let latency_ptr = Arc::clone(&self.latency);
+            let port = self.dns_port.clone();
+            let domain = cdn_server.domain_name.clone();
+            let copy_ip = ip.to_string();
+
+            // Spawn worker thread to collect the measured client RTTs of each HTTP server every 30 seconds
+            tokio::spawn(async move {
+                loop {
+                    if let Ok(report) = DnsServer::get_latency(domain.clone(), port.clone()).await {
+                        let mut latency = latency_ptr.lock().await;
+                        // The report replaces the previous one of the server, and the expired entries of every server are dropped
+                        for measured in latency.values_mut() {
+                            measured.retain(|cdn_ip, (_, _, reported)| {
+                                *cdn_ip != copy_ip && reported.elapsed() < LATENCY_TTL
+                            });
+                        }
+                        latency.retain(|_, measured| !measured.is_empty());
+                        // Each line of the report is "prefix samples rtt_ms"
+                        for line in report.lines() {
+                            let fields: Vec<&str> = line.split_whitespace().collect();
+                            if fields.len() != 3 {
+                                continue;
+                            }
+                            if let (Ok(samples), Ok(rtt)) = (fields[1].parse::<u32>(), fields[2].parse::<f64>()) {
+                                latency
+                                    .entry(fields[0].to_string())
+                                    .or_insert_with(HashMap::new)
+                                    .insert(copy_ip.clone(), (samples, rtt, Instant::now()));
+                            }
+                        }
+                        drop(latency);
+                    }
+
+                    // Sleep for 30 seconds
+                    tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
+                }
+            });
+        }
+
         for (ip, cdn_server) in self.cdn_server.iter() {
             // let cache_ptr = Arc::clone(&self.cache);
             let cpu_usage_ptr = Arc::clone(&self.cpu_usage);
@@ -140,11 +518,15 @@ impl DnsServer {
             let domain = cdn_server.domain_name.clone();
             let copy_ip = ip.to_string();
             let availability_ptr = Arc::clone(&self.availability);
+            let throughput_ptr = Arc::clone(&self.throughput);
+            let health_updated_ptr = Arc::clone(&self.health_updated);
 
             // Spawn worker thread to probe each HTTP server every 5 second
             tokio::spawn(async move {
                 loop {
-                    match DnsServer::get_usage(domain.clone(), port.clone()).await {
+                    let probe = DnsServer::get_usage(domain.clone(), port.clone()).await;
+                    health_updated_ptr.lock().await.insert(copy_ip.clone(), now_millis());
+                    match probe {
                         Ok(res) => {
                             // Update the availability of the HTTP server
                             let mut availability = availability_ptr.lock().await;
@@ -166,6 +548,17 @@ impl DnsServer {
                                 },
                             );
                             drop(cpu_usage);
+
+                            // Update the throughput of the HTTP server, reported as "rps mbps"
+                            if let Ok(res) = DnsServer::get_throughput(domain.clone(), port.clone()).await {
+                                let fields: Vec<f64> = res
+                                    .split_whitespace()
+                                    .filter_map(|x| x.parse::<f64>().ok())
+                                    .collect();
+                                if fields.len() == 2 {
+                                    throughput_ptr.lock().await.insert(copy_ip.clone(), (fields[0], fields[1]));
+                                }
+                            }
                         }
                         Err(_) => {
                             // Http server didn't respond
@@ -182,6 +575,113 @@ impl DnsServer {
             });
         }
 
+        // Spawn worker thread to print the selection metrics, per pool, every 60 seconds
+        let metrics_ptr = Arc::clone(&self.selection_metrics);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+                let metrics = metrics_ptr.lock().await;
+                let mut pools: Vec<(&String, &u64)> = metrics.iter().collect();
+                pools.sort();
+                for (pool, answers) in pools {
+                    println!("Metrics: pool={} answers={}", pool, answers);
+                }
+                drop(metrics);
+            }
+        });
+
+        // Share replica health, load, overrides and client geolocations with the other DNS servers
+        if let Some(peers) = self.config.peers.clone() {
+            let routing = PeerRouting {
+                digest: self.routing_digest(),
+                overrides: self.config.overrides.clone(),
+                pools: self.config.pools.clone(),
+                route_overrides: Arc::clone(&self.route_overrides),
+            };
+            let peer_sync = PeerSync::new(
+                peers,
+                routing,
+                Arc::clone(&self.availability),
+                Arc::clone(&self.cpu_usage),
+                Arc::clone(&self.throughput),
+                Arc::clone(&self.health_updated),
+                Arc::clone(&self.client_distance_cache),
+            );
+            tokio::spawn(peer_sync.run());
+        }
+
+        // NOTIFY the secondaries at startup. Reload the zone files every minute, and NOTIFY them again when the
+        // zone changed. The zone files of the views aren't transferred.
+        let cloned = self.clone();
+        let reload = self.config.zone_file.is_some() || self.config.views.iter().any(|view| view.zone_file.is_some());
+        tokio::spawn(async move {
+            cloned.notify_secondaries().await;
+            if !reload {
+                return;
+            }
+            loop {
+                // Sleep for 60 seconds
+                tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+
+                // Hold the lock while reloading, so an UPDATE committed in the meantime isn't lost
+                let mut static_zone = cloned.static_zone.lock().await;
+                match static_zone.reload() {
+                    Ok(Some(reloaded)) => {
+                        println!("Zone {} reloaded, serial {}", cloned.zone, reloaded.serial());
+                        *static_zone = Arc::new(reloaded);
+                        drop(static_zone);
+                        cloned.notify_secondaries().await;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        // Keep serving the last good zone
+                        dbg!(format!("Error: can't reload the zone file: {}", e));
+                    }
+                }
+
+                for view in cloned.views.iter() {
+                    let view_zone = match &view.static_zone {
+                        Some(view_zone) => view_zone,
+                        None => continue,
+                    };
+                    let mut view_zone = view_zone.lock().await;
+                    match view_zone.reload() {
+                        Ok(Some(reloaded)) => {
+                            println!("Zone file of view {} reloaded", view.name);
+                            *view_zone = Arc::new(reloaded);
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            dbg!(format!("Error: can't reload the zone file of view {}: {}", view.name, e));
+                        }
+                    }
+                }
+            }
+        });
+
+        // Start the TCP listener, used for large answers and zone transfers
+        tokio::spawn(serve_tcp(self.clone(), self.dns_port.parse().unwrap()));
+
+        // Start the DNS-over-TLS and DNS-over-HTTPS listeners
+        if let Some(tls) = self.config.tls.clone() {
+            if let Some(port) = tls.dot_port {
+                match tls_acceptor(&tls, b"dot") {
+                    Ok(acceptor) => {
+                        tokio::spawn(serve_dot(self.clone(), acceptor, port));
+                    }
+                    Err(e) => panic!("Error: can't start the DoT listener: {}", e),
+                }
+            }
+            if let Some(port) = tls.doh_port {
+                match tls_acceptor(&tls, b"http/1.1") {
+                    Ok(acceptor) => {
+                        tokio::spawn(serve_doh(self.clone(), acceptor, port));
+                    }
+                    Err(e) => panic!("Error: can't start the DoH listener: {}", e),
+                }
+            }
+        }
+
         loop {
             // Read the message from the udp socket
             let (client_address, dns_question) = self.get_question_domain_name();
@@ -190,32 +690,32 @@ impl DnsServer {
 
             // Spawn worker thread to respond the dig request
             tokio::spawn(async move {
-                // String of client address, for sending response
-                let client_address_str = client_address.to_string();
-                // Remove port number from the source address
-                let client_ip = client_address_str.split(":").collect::<Vec<&str>>()[0];
-                let sorted_cdn_servers = cloned.get_sorted_cdn_servers(&client_ip, " ").await;
-                let ans;
-
-                // When all the HTTP servers are down, route the client to my AWS ec2 instance
-                if sorted_cdn_servers.is_empty() {
-                    ans = cloned.generate_response_when_all_cdnservers_down(
-                        &dns_question,
-                        "3.129.217.143",
-                        "ec2-3-129-217-143.us-east-2.compute.amazonaws.com",
-                    )
-                } else {
-                    let closest_cdn_server: &str = sorted_cdn_servers[0].1.as_ref();
-                    ans = cloned.generate_response(&dns_question, closest_cdn_server);
-                }
-
-                dbg!(&client_address);
+                let response = cloned.handle_query(&client_address, &dns_question).await;
+                let ans = response.encode_for_udp(dns_question.udp_payload_size());
 
                 cloned.socket.send_to(&ans, &client_address).unwrap();
             });
         }
     }
 
+    // This function returns a digest of the routing config (pools, policies and views), to compare it with the peers.
+    // Overrides are left out: they are exchanged with the peers.
+    fn routing_digest(&self) -> String {
+        let pools: BTreeMap<&String, &Vec<String>> = self.config.pools.iter().collect();
+        let views: Vec<String> = self
+            .config
+            .views
+            .iter()
+            .map(|view| {
+                let addresses: BTreeMap<&String, &String> = view.addresses.iter().collect();
+                format!("{} {:?} {:?} {:?}", view.name, view.cidrs, addresses, view.policies)
+            })
+            .collect();
+        let routing = format!("{:?} {:?} {:?}", pools, self.config.policies, views);
+        let hash = digest::digest(&digest::SHA256, routing.as_bytes());
+        hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
     // This function is used to clone the DnsServer struct
     pub fn clone(&self) -> Self {
         let cloned = DnsServer {
@@ -225,37 +725,73 @@ impl DnsServer {
             dns_port: self.dns_port.clone(),
             client_distance_cache: Arc::clone(&self.client_distance_cache),
             availability: Arc::clone(&self.availability),
+            throughput: Arc::clone(&self.throughput),
+            health_updated: Arc::clone(&self.health_updated),
+            latency: Arc::clone(&self.latency),
             location: Location::new(40.8229, -74.4592),
+            zone: self.zone.clone(),
+            ns_name: self.ns_name.clone(),
+            static_zone: Arc::clone(&self.static_zone),
+            tsig_keys: Arc::clone(&self.tsig_keys),
+            forwarder: self.forwarder.clone(),
+            key_dir: self.key_dir.clone(),
+            signer: Arc::clone(&self.signer),
+            config: Arc::clone(&self.config),
+            route_overrides: Arc::clone(&self.route_overrides),
+            content_ring: Arc::clone(&self.content_ring),
+            affinity: Arc::clone(&self.affinity),
+            selection_metrics: Arc::clone(&self.selection_metrics),
+            views: Arc::clone(&self.views),
+            view_networks: Arc::clone(&self.view_networks),
         };
 
         cloned
     }
 
     // This function will read from the request and get the dns question and src ip
-    pub fn get_question_domain_name(&self) -> (String, Dns) {
-        // Read the message from the udp socket
-        let mut buf = [0; 1024];
-        let (amt, src) = self.socket.recv_from(&mut buf).unwrap();
-        // Use dns parser to decode the message
-        let bytes = Bytes::copy_from_slice(&buf[..amt]);
-        let dns = Dns::decode(bytes).unwrap();
-
-        (src.to_string(), dns)
+    pub fn get_question_domain_name(&self) -> (String, Message) {
+        loop {
+            // Read the message from the udp socket
+            let mut buf = [0; 1024];
+            let (amt, src) = self.socket.recv_from(&mut buf).unwrap();
+            // Decode the message, ignoring anything that isn't a valid query
+            match Message::decode(&buf[..amt]) {
+                Ok(dns) if !dns.is_response() => return (src.to_string(), dns),
+                _ => dbg!(format!("Error: can't decode the query from {}", src)),
+            };
+        }
     }
 
-    // This function is used to get the geolocation of an IP address
-    async fn get_geolocation(&self, ip: &str) -> Result<Locator, GeoError> {
-        let service = Service::IpApi;
-        let backup_service = Service::FreeGeoIp;
+    // This function is used to get the geolocation of an IP address, with its country and continent when known
+    async fn get_geolocation(&self, ip: &str) -> Result<GeoInfo, GeoError> {
+        // ip-api gives country and continent codes, but ipgeolocate doesn't expose them, so ask it directly
+        if let Ok(response) = reqwest::get(format!(
+            "http://ip-api.com/json/{}?fields=status,lat,lon,countryCode,continentCode",
+            ip
+        ))
+        .await
+        {
+            if let Ok(info) = response.json::<IpApiResponse>().await {
+                if let (true, Some(lat), Some(lon)) = (info.status == "success", info.lat, info.lon) {
+                    return Ok(GeoInfo {
+                        location: Location::new(lat, lon),
+                        country: info.country_code,
+                        continent: info.continent_code,
+                    });
+                }
+            }
+        }
 
-        let locator = match Locator::get(ip, service).await {
-            Ok(locator) => locator,
-            Err(_) => match Locator::get(ip, backup_service).await {
-                Ok(locator2) => locator2,
-                Err(e) => return Err(e),
-            },
-        };
-        Ok(locator)
+        let backup_service = Service::FreeGeoIp;
+        let locator = Locator::get(ip, backup_service).await?;
+        Ok(GeoInfo {
+            location: Location::new(
+                locator.latitude.parse::<f64>().unwrap(),
+                locator.longitude.parse::<f64>().unwrap(),
+            ),
+            country: None,
+            continent: None,
+        })
     }
 
     // This function is used to get the distance between two IP addresses
@@ -264,50 +800,338 @@ impl DnsServer {
         distance.meters()
     }
 
-    // This function gets a sorted list of distance from the client to the CDN servers in ascending order
+    // This function is used to compute the distances from a client location to the CDN servers
+    async fn client_geo_at(&self, geo: GeoInfo) -> ClientGeo {
+        let mut client_to_server: HashMap<String, f64> = HashMap::new();
+        for cdn_ip in self.cdn_server.keys() {
+            let distance = self
+                .get_distance_from_ip(&geo.location, &self.cdn_server.get(cdn_ip).unwrap().geolocation)
+                .await;
+            client_to_server.insert(cdn_ip.clone(), distance);
+        }
+        ClientGeo {
+            distances: client_to_server,
+            country: geo.country,
+            continent: geo.continent,
+        }
+    }
+
+    // This function gets a sorted list of distance from the client to the CDN servers in ascending order.
+    // It returns None when a geo-fence forbids every available replica for this client.
     async fn get_sorted_cdn_servers(
         &mut self,
         client_ip: &str,
+        qname: &str,
         content: &str,
-    ) -> Vec<(f64, String)> {
-        let mut cdn_servers = vec![];
-        let mut client_to_server: HashMap<String, f64> = HashMap::new();
-        let mut d_cache = self.client_distance_cache.lock().await;
-
-        // If client cache exist, use it
-        if d_cache.contains_key(client_ip) {
-            client_to_server = d_cache.get(client_ip).unwrap().clone();
-        } else { // If client cache doesn't exist, create calculate the distance
-
-            // Get client ip geolocation
-            let mut client_ip_geolocation = self.location.clone();
-
-            // Get the GEO location of client
-            match self.get_geolocation(client_ip).await {
-                Ok(client_ip_geolocator) => {
-                    client_ip_geolocation = Location::new(
-                        client_ip_geolocator.latitude.parse::<f64>().unwrap(),
-                        client_ip_geolocator.longitude.parse::<f64>().unwrap(),
-                    );
+        view: Option<&View>,
+    ) -> Option<Vec<(f64, String)>> {
+        // Look for an override of the client network before geolocating it
+        let route_overrides = self.route_overrides.lock().await.clone();
+        let route_override = match client_ip.parse::<IpAddr>() {
+            Ok(ip) => route_overrides.longest_match(&ip).map(|(entry, _)| entry.clone()),
+            Err(_) => None,
+        };
+        if let Some((cidr, route)) = &route_override {
+            dbg!(format!("Explain: {} matched override {} -> {:?}", client_ip, cidr, route));
+        }
+
+        let client_geo = match &route_override {
+            // An override location only applies to this answer, so it never reaches the cache shared with peers
+            Some((_, RouteOverride::Location(geo))) => self.client_geo_at(geo.clone()).await,
+            _ => {
+                let mut d_cache = self.client_distance_cache.lock().await;
+
+                // If client cache exist, use it
+                let client_geo = if d_cache.contains_key(client_ip) {
+                    d_cache.get(client_ip).unwrap().clone()
+                } else { // If client cache doesn't exist, create calculate the distance
+
+                    // Get client ip geolocation
+                    let mut client_ip_geolocation = GeoInfo {
+                        location: self.location.clone(),
+                        country: None,
+                        continent: None,
+                    };
+
+                    // Get the GEO location of client
+                    match self.get_geolocation(client_ip).await {
+                        Ok(geo) => client_ip_geolocation = geo,
+                        Err(_) => {}
+                    }
+
+                    let client_geo = self.client_geo_at(client_ip_geolocation).await;
+                    d_cache.insert(client_ip.to_string(), client_geo.clone());
+                    client_geo
+                };
+                drop(d_cache);
+                client_geo
+            }
+        };
+
+        // Apply the routing policies matching the client's country or continent, those of its view if it has one
+        let policies = match view {
+            Some(view) => &view.policies,
+            None => &self.config.policies,
+        };
+        let mut fence: Option<Vec<String>> = None;
+        let mut preferred: Vec<String> = vec![];
+        for (i, policy) in policies.iter().enumerate() {
+            if !self.policy_matches(policy, qname, &client_geo) {
+                continue;
+            }
+            dbg!(format!(
+                "Explain: {} ({:?}/{:?}) matched policy #{}",
+                client_ip, client_geo.country, client_geo.continent, i
+            ));
+            if !policy.allow_pools.is_empty() {
+                let members = self.pool_members(&policy.allow_pools);
+                fence = Some(match fence {
+                    Some(fence) => fence.into_iter().filter(|ip| members.contains(ip)).collect(),
+                    None => members,
+                });
+            }
+            preferred.extend(self.pool_members(&policy.prefer_pools));
+        }
+
+        // Restrict the candidates to the pinned replica or pool, inside the geo-fence
+        let pinned = match &route_override {
+            Some((_, RouteOverride::Replica(replica))) => Some(vec![replica.clone()]),
+            Some((_, RouteOverride::Pool(pool))) => self.config.pools.get(pool).cloned(),
+            _ => None,
+        };
+        let allowed = match (&pinned, &fence) {
+            (Some(pinned), Some(fence)) => {
+                Some(pinned.iter().filter(|ip| fence.contains(ip)).cloned().collect())
+            }
+            (Some(pinned), None) => Some(pinned.clone()),
+            (None, fence) => fence.clone(),
+        };
+        let mut distances = self.apply_measured_latency(client_ip, &client_geo.distances).await;
+        // A view with its own addresses only uses the replicas it has an address for
+        if let Some(view) = view.filter(|view| !view.addresses.is_empty()) {
+            distances.retain(|cdn_ip, _| view.addresses.contains_key(cdn_ip));
+        }
+        let distances = self.apply_distance_bias(distances);
+        let mut cdn_servers = self.rank_cdn_servers(&distances, allowed.as_ref()).await;
+        if cdn_servers.is_empty() && pinned.is_some() {
+            dbg!(format!("Explain: no available replica for the override of {}, using all replicas", client_ip));
+            cdn_servers = self.rank_cdn_servers(&distances, fence.as_ref()).await;
+        }
+        if cdn_servers.is_empty() && fence.is_some() {
+            dbg!(format!("Explain: no available replica inside the geo-fence of {}", client_ip));
+            return None;
+        }
+
+        // Canary split: a stable share of the client prefixes only uses the canary pool, the others never do
+        if let Some(canary) = &self.config.canary {
+            let members = self.pool_members(&[canary.pool.clone()]);
+            let prefix = match client_ip.parse::<IpAddr>() {
+                Ok(ip) => client_prefix(&ip),
+                Err(_) => client_ip.to_string(),
+            };
+            let in_canary = ((stable_hash(&prefix) % 10_000) as f64) < canary.percent * 100.0;
+            let (canary_servers, stable_servers): (Vec<(f64, String)>, Vec<(f64, String)>) =
+                cdn_servers.into_iter().partition(|(_, ip)| members.contains(ip));
+            cdn_servers = if (in_canary && !canary_servers.is_empty()) || stable_servers.is_empty() {
+                canary_servers
+            } else {
+                stable_servers
+            };
+            dbg!(format!("Explain: {} is in the {} cohort", prefix, if in_canary { "canary" } else { "stable" }));
+        }
+
+        // Preferred replicas go first, each group still sorted by distance
+        if !preferred.is_empty() {
+            cdn_servers.sort_by_key(|(_, ip)| !preferred.contains(ip));
+        }
+
+        // Content-aware routing: the ring pins the content group to a few of the nearby replicas
+        if let Some(content_routing) = &self.config.content_routing {
+            if !content.trim().is_empty() {
+                let nearby: Vec<(f64, String)> = cdn_servers
+                    .iter()
+                    .take(content_routing.nearby_replicas)
+                    .cloned()
+                    .collect();
+                let mut pinned: Vec<(f64, String)> = self
+                    .content_ring
+                    .walk(content)
+                    .into_iter()
+                    .filter_map(|ip| nearby.iter().find(|(_, near)| *near == ip).cloned())
+                    .take(content_routing.replicas_per_group)
+                    .collect();
+                pinned.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+                dbg!(format!("Explain: content group {} is pinned to {:?}", content, pinned));
+
+                cdn_servers.retain(|server| !pinned.contains(server));
+                pinned.extend(cdn_servers);
+                cdn_servers = pinned;
+            }
+        }
+
+        Some(cdn_servers)
+    }
+
+    // This function returns the content group of a <bucket>.<zone> name, when content routing knows the bucket
+    fn content_group(&self, qname: &str) -> Option<String> {
+        let content_routing = self.config.content_routing.as_ref()?;
+        let bucket = qname.strip_suffix(&format!(".{}", self.zone))?;
+        if bucket.contains('.') {
+            return None;
+        }
+        content_routing.buckets.get(bucket).cloned()
+    }
+
+    // This function returns the split-horizon view of a client, if its network is in one
+    fn view_of(&self, client_ip: &str) -> Option<Arc<View>> {
+        let ip = client_ip.parse::<IpAddr>().ok()?;
+        let (index, _) = self.view_networks.longest_match(&ip)?;
+        Some(Arc::clone(&self.views[*index]))
+    }
+
+    // This function replaces the distance to a server with its measured RTT, converted to an equivalent
+    // distance, when the server recently reported enough RTT samples for the client prefix.
+    async fn apply_measured_latency(
+        &self,
+        client_ip: &str,
+        distances: &HashMap<String, f64>,
+    ) -> HashMap<String, f64> {
+        let mut distances = distances.clone();
+        let prefix = match client_ip.parse::<IpAddr>() {
+            Ok(ip) => client_prefix(&ip),
+            Err(_) => return distances,
+        };
+
+        let latency = self.latency.lock().await;
+        if let Some(measured) = latency.get(&prefix) {
+            for (cdn_ip, (samples, rtt, reported)) in measured.iter() {
+                let recent = reported.elapsed() < LATENCY_TTL;
+                if *samples >= MIN_RTT_SAMPLES && recent && distances.contains_key(cdn_ip) {
+                    distances.insert(cdn_ip.clone(), rtt * METERS_PER_RTT_MS);
+                    dbg!(format!("Explain: {} uses measured RTT {:.1}ms to {}", prefix, rtt, cdn_ip));
                 }
-                Err(_) => {}
             }
+        }
+        drop(latency);
+
+        distances
+    }
+
+    // This function checks if the server's reported throughput is close to its configured capacity
+    async fn near_capacity(&self, cdn_ip: &str) -> bool {
+        let capacity = match self.config.capacity.get(cdn_ip) {
+            Some(capacity) => capacity,
+            None => return false,
+        };
+        let throughput = self.throughput.lock().await;
+        let (rps, mbps) = match throughput.get(cdn_ip) {
+            Some(rates) => *rates,
+            None => return false,
+        };
+        drop(throughput);
+
+        let threshold = self.config.capacity_threshold;
+        let full = capacity.rps.map_or(false, |max| rps >= max * threshold)
+            || capacity.mbps.map_or(false, |max| mbps >= max * threshold);
+        if full {
+            dbg!(format!("Explain: {} is near capacity ({:.1} rps, {:.1} Mbps)", cdn_ip, rps, mbps));
+        }
+        full
+    }
+
+    // This function divides the distance to each server by its distance bias, so a bias of 2 attracts
+    // clients from twice as far. It only moves the boundary between the areas of nearby servers, each client
+    // still gets its nearest one: it doesn't split traffic in proportion. Servers with a bias of 0 are drained.
+    fn apply_distance_bias(&self, mut distances: HashMap<String, f64>) -> HashMap<String, f64> {
+        if self.config.distance_bias.is_empty() {
+            return distances;
+        }
+        distances.retain(|cdn_ip, distance| {
+            let bias = self.server_bias(cdn_ip);
+            *distance /= bias;
+            bias > 0.0
+        });
+        distances
+    }
 
-            for cdn_ip in self.cdn_server.keys() {
-                let distance = self
-                    .get_distance_from_ip(
-                        &client_ip_geolocation,
-                        &self.cdn_server.get(cdn_ip).unwrap().geolocation,
-                    )
-                    .await;
-                client_to_server.insert(cdn_ip.clone(), distance);
+    // This function returns the distance bias of a server: its own, or the one of the first biased pool it belongs to
+    fn server_bias(&self, cdn_ip: &str) -> f64 {
+        if let Some(bias) = self.config.distance_bias.get(cdn_ip) {
+            return *bias;
+        }
+        let mut pools: Vec<&String> = self.config.pools.keys().collect();
+        pools.sort();
+        for pool in pools {
+            if self.config.pools[pool].iter().any(|ip| ip == cdn_ip) {
+                if let Some(bias) = self.config.distance_bias.get(pool) {
+                    return *bias;
+                }
             }
-            d_cache.insert(client_ip.to_string(), client_to_server.clone());
         }
-        drop(d_cache);
+        1.0
+    }
+
+    // This function counts an answer in the selection metrics of every pool the server belongs to
+    async fn record_selection(&self, cdn_ip: &str) {
+        let mut pools: Vec<String> = self
+            .config
+            .pools
+            .iter()
+            .filter(|(_, members)| members.iter().any(|ip| ip == cdn_ip))
+            .map(|(pool, _)| pool.clone())
+            .collect();
+        if pools.is_empty() {
+            pools.push("unpooled".to_string());
+        }
+        let mut metrics = self.selection_metrics.lock().await;
+        for pool in pools {
+            *metrics.entry(pool).or_insert(0) += 1;
+        }
+    }
+
+    // This function checks if a routing policy applies to the client and the queried name
+    fn policy_matches(&self, policy: &PolicyConfig, qname: &str, client_geo: &ClientGeo) -> bool {
+        if let Some(zone) = &policy.zone {
+            if !in_zone(qname, zone) {
+                return false;
+            }
+        }
+        let matches = |wanted: &Option<String>, actual: &Option<String>| match (wanted, actual) {
+            (None, _) => true,
+            (Some(wanted), Some(actual)) => wanted.eq_ignore_ascii_case(actual),
+            (Some(_), None) => false,
+        };
+        matches(&policy.country, &client_geo.country) && matches(&policy.continent, &client_geo.continent)
+    }
+
+    // This function lists the replica IP addresses of the given pools
+    fn pool_members(&self, pools: &[String]) -> Vec<String> {
+        pools
+            .iter()
+            .filter_map(|pool| self.config.pools.get(pool))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    // This function sorts the available CDN servers by distance, optionally keeping only the allowed ones
+    async fn rank_cdn_servers(
+        &self,
+        client_to_server: &HashMap<String, f64>,
+        allowed: Option<&Vec<String>>,
+    ) -> Vec<(f64, String)> {
+        let mut cdn_servers = vec![];
+        // Servers close to their capacity, only used when no other server is left
+        let mut full_servers = vec![];
 
         // Get the distance from the client to each CDN server
         for (cdn_ip, _) in self.cdn_server.iter() {
+            if let Some(allowed) = allowed {
+                if !allowed.contains(cdn_ip) {
+                    continue;
+                }
+            }
+
             // Check availability
             let availability = self.availability.lock().await;
             let ava = *availability.get(cdn_ip).unwrap();
@@ -324,130 +1148,534 @@ impl DnsServer {
                 continue;
             }
 
-            let distance = *client_to_server.get(cdn_ip).unwrap();
+            // Drained servers have no distance
+            let distance = match client_to_server.get(cdn_ip) {
+                Some(distance) => *distance,
+                None => continue,
+            };
+
+            // Check the throughput against the capacity
+            if self.near_capacity(cdn_ip).await {
+                full_servers.push((distance, cdn_ip.to_string()));
+                continue;
+            }
+
             cdn_servers.push((distance, cdn_ip.to_string()));
         }
 
         cdn_servers.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        full_servers.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        cdn_servers.extend(full_servers);
 
         cdn_servers
     }
 
-    // This function will generate DNS response
-    pub fn generate_response(&self, dns_question: &Dns, closest_cdn_server: &str) -> BytesMut {
-        // Create a new DNS response
-        // Fill out the fields of the DNS response
-        let id = dns_question.id;
-        let flags = Flags {
-            qr: true,
-            opcode: Opcode::Query,
-            aa: false,
-            tc: false,
-            rd: true,
-            ra: false,
-            ad: false,
-            cd: false,
-            rcode: RCode::NoError,
+    // This function runs a query through the pipeline shared by the UDP, DoT and DoH listeners
+    pub async fn handle_query(&mut self, client_address: &str, query: &Message) -> Message {
+        // Remove port number from the source address
+        let client_ip = match client_address.parse::<SocketAddr>() {
+            Ok(address) => address.ip().to_string(),
+            Err(_) => client_address.to_string(),
+        };
+        // Signed queries get a signed response, or NOTAUTH when the signature doesn't verify
+        let key_name = match &query.tsig {
+            Some(tsig) => match self.tsig_keys.verify(tsig) {
+                Ok(key_name) => Some(key_name),
+                Err(error) => {
+                    dbg!(format!("Error: TSIG of {} failed with error {}", client_address, error));
+                    return self.tsig_keys.error_response(query, tsig, error);
+                }
+            },
+            None => None,
         };
-        let questions = dns_question.questions.clone();
-        let authorities = vec![];
-        let additionals = vec![];
-        // Add the CDN server IP address to the answer
-        let domain_name = self
-            .cdn_server
-            .get(closest_cdn_server)
-            .unwrap()
-            .domain_name
-            .to_string()
-            .parse()
-            .unwrap();
-        let mut answer = Vec::new();
-        // Turn string into ipv4 address
-        let ip_vec = closest_cdn_server
-            .split(".")
-            .map(|x| x.parse::<u8>().unwrap())
-            .collect::<Vec<u8>>();
-        let ipv4_addr = Ipv4Addr::new(ip_vec[0], ip_vec[1], ip_vec[2], ip_vec[3]);
-        answer.push(RR::A(A {
-            domain_name,
-            ttl: 0,
-            ipv4_addr,
-        }));
-
-        let dns_response = Dns {
-            id,
-            flags,
-            questions,
-            answers: answer,
-            authorities,
-            additionals,
+        let mut response = match query.opcode() {
+            OPCODE_UPDATE => self.apply_update(client_address, query, key_name).await,
+            _ => self.answer_query(&client_ip, query).await,
         };
+        // Recursion is available to the clients allowed to use the forwarder
+        if let (Some(forwarder), Ok(ip)) = (&self.forwarder, client_ip.parse::<IpAddr>()) {
+            response.set_recursion_available(forwarder.allows(&ip));
+        }
+        if let Some(tsig) = &query.tsig {
+            response.tsig = Some(self.tsig_keys.sign_response(tsig, 0));
+        }
 
-        // Encode the DNS response
-        let response = dns_response.encode().unwrap();
+        dbg!(&client_address);
 
         response
     }
 
-    fn generate_response_when_all_cdnservers_down(
-        &self,
-        dns_question: &Dns,
-        closest_cdn_server: &str,
-        domain_name: &str,
-    ) -> BytesMut {
-        let id = dns_question.id;
-        let flags = Flags {
-            qr: true,
-            opcode: Opcode::Query,
-            aa: false,
-            tc: false,
-            rd: true,
-            ra: false,
-            ad: false,
-            cd: false,
-            rcode: RCode::NoError,
+    // This function builds the response to one DNS query
+    async fn answer_query(&mut self, client_ip: &str, query: &Message) -> Message {
+        let mut response = Message::response_to(query);
+        if query.edns().is_some() {
+            response.add_edns(query.dnssec_ok());
+        }
+
+        if query.opcode() != OPCODE_QUERY {
+            response.set_rcode(RCODE_NOTIMP);
+            return response;
+        }
+        let question = match query.questions.first() {
+            Some(question) => question.clone(),
+            None => {
+                response.set_rcode(RCODE_FORMERR);
+                return response;
+            }
+        };
+        // Without DNSSEC keys or zone_only, the names we don't serve get the replica A record as they always did
+        let zone_only = self.config.zone_only || self.key_dir.is_some();
+
+        // We are only authoritative for our own zone. Other names are forwarded when forwarding is on,
+        // for allowed clients asking for recursion.
+        if !in_zone(&question.name, &self.zone) {
+            let forwarder = match (&self.forwarder, client_ip.parse::<IpAddr>()) {
+                (Some(forwarder), Ok(ip)) if forwarder.allows(&ip) && query.recursion_desired() => forwarder,
+                _ if !zone_only => return self.legacy_answer(client_ip, &question, response).await,
+                _ => {
+                    response.set_rcode(RCODE_REFUSED);
+                    return response;
+                }
+            };
+            match forwarder.forward(query).await {
+                Some(answer) => {
+                    response.set_rcode(answer.rcode());
+                    response.answers = answer.answers;
+                    response.authorities = answer.authorities;
+                    response
+                        .additionals
+                        .extend(answer.additionals.into_iter().filter(|rr| rr.rtype != TYPE_OPT));
+                }
+                None => response.set_rcode(RCODE_SERVFAIL),
+            }
+            return response;
+        }
+        response.set_authoritative(true);
+
+        // The view of the client network, if any, changes the replica addresses, policies, TTLs and static records
+        let view = self.view_of(client_ip);
+        if let Some(view) = &view {
+            dbg!(format!("Explain: {} is in view {}", client_ip, view.name));
+        }
+        let answer_ttl = view.as_ref().map_or(0, |view| view.ttl);
+        let negative_ttl = view.as_ref().map_or(NEGATIVE_TTL, |view| view.negative_ttl);
+
+        // Only sign when the client asked for it and we have keys
+        let signer = match query.dnssec_ok() {
+            true => self.signer.lock().await.clone(),
+            false => None,
         };
 
-        let questions = dns_question.questions.clone();
-        let authorities = vec![];
-        let additionals = vec![];
-
-        // Add the CDN server IP address to the answer
-        let domain_name = domain_name.to_string().parse().unwrap();
-        let mut answer = Vec::new();
-        // Turn string into ipv4 address
-        let ip_vec = closest_cdn_server
-            .split(".")
-            .map(|x| x.parse::<u8>().unwrap())
-            .collect::<Vec<u8>>();
-        let ipv4_addr = Ipv4Addr::new(ip_vec[0], ip_vec[1], ip_vec[2], ip_vec[3]);
-        answer.push(RR::A(A {
-            domain_name,
-            ttl: 0,
-            ipv4_addr,
-        }));
-
-        let dns_response = Dns {
-            id,
-            flags,
-            questions,
-            answers: answer,
-            authorities,
-            additionals,
+        let qname = normalize_name(&question.name);
+        let static_zone = self.static_zone.lock().await.clone();
+        let view_zone = match view.as_ref().and_then(|view| view.static_zone.as_ref()) {
+            Some(view_zone) => Some(view_zone.lock().await.clone()),
+            None => None,
+        };
+        // Names in the zone file of the view hide the static records of the zone
+        let static_records = match &view_zone {
+            Some(view_zone) if !view_zone.lookup(&qname).is_empty() => view_zone.lookup(&qname),
+            _ => static_zone.lookup(&qname),
         };
+        let static_name =
+            static_zone.has_name(&qname) || view_zone.as_ref().is_some_and(|view_zone| view_zone.has_name(&qname));
+        // Types that exist at the name, used to build the NSEC record of negative answers
+        let mut types = vec![];
+        if qname == self.zone {
+            types = vec![TYPE_A, TYPE_NS, TYPE_SOA];
+            types.extend(static_records.iter().map(|rr| rr.rtype));
+            match question.qtype {
+                TYPE_A => match self.select_replica(client_ip, &qname, "", view.as_deref()).await {
+                    Some(replica) => response.answers.push(Record::a(&question.name, answer_ttl, replica)),
+                    None => {
+                        response.set_rcode(RCODE_SERVFAIL);
+                        return response;
+                    }
+                },
+                TYPE_NS => response.answers.extend(self.ns_records(&static_zone)),
+                // Zone transfers only run over TCP; over UDP, IXFR gets the SOA so the secondary retries over TCP
+                TYPE_SOA | TYPE_IXFR => response.answers.push(static_zone.soa()),
+                TYPE_AXFR if !zone_only => return self.legacy_answer(client_ip, &question, response).await,
+                TYPE_AXFR => {
+                    response.set_rcode(RCODE_REFUSED);
+                    return response;
+                }
+                qtype => response
+                    .answers
+                    .extend(static_records.iter().filter(|rr| rr.rtype == qtype).cloned()),
+            }
+            if let Some(signer) = &signer {
+                types.push(TYPE_DNSKEY);
+                if question.qtype == TYPE_DNSKEY {
+                    response.answers.extend(signer.dnskey_records(ZONE_TTL));
+                }
+            }
+        } else if let Some(group) = self.content_group(&qname) {
+            // <bucket>.<zone> names are routed by the content group of the bucket
+            types = vec![TYPE_A];
+            if question.qtype == TYPE_A {
+                match self.select_replica(client_ip, &qname, &group, view.as_deref()).await {
+                    Some(replica) => response.answers.push(Record::a(&question.name, answer_ttl, replica)),
+                    None => {
+                        response.set_rcode(RCODE_SERVFAIL);
+                        return response;
+                    }
+                }
+            }
+        } else if static_name {
+            // Static records from the zone file; a CNAME answers every type
+            types = static_records.iter().map(|rr| rr.rtype).collect();
+            let cname: Vec<Record> = static_records.iter().filter(|rr| rr.rtype == TYPE_CNAME).cloned().collect();
+            if !cname.is_empty() {
+                response.answers.extend(cname);
+            } else {
+                response
+                    .answers
+                    .extend(static_records.iter().filter(|rr| rr.rtype == question.qtype).cloned());
+            }
+        }
+
+        if response.answers.is_empty() && types.is_empty() && !zone_only {
+            return self.legacy_answer(client_ip, &question, response).await;
+        }
+
+        // Negative answer: the SOA, plus a "black lie" NSEC saying the name has no such type.
+        // Signed names always exist, so NXDOMAIN is only used for unsigned answers.
+        if response.answers.is_empty() {
+            let mut soa = static_zone.soa();
+            soa.ttl = negative_ttl;
+            response.authorities.push(soa);
+            match &signer {
+                Some(signer) => response
+                    .authorities
+                    .push(signer.black_lie(&qname, &types, negative_ttl)),
+                None if types.is_empty() => response.set_rcode(RCODE_NXDOMAIN),
+                None => {}
+            }
+        }
+
+        if let Some(signer) = &signer {
+            let answer_signatures = signer.sign_records(&response.answers);
+            response.answers.extend(answer_signatures);
+            let authority_signatures = signer.sign_records(&response.authorities);
+            response.authorities.extend(authority_signatures);
+        }
+
+        response
+    }
+
+    // This function answers a name we don't serve with the replica A record, whatever the query type,
+    // as the server did before it only answered its own zone
+    async fn legacy_answer(&mut self, client_ip: &str, question: &Question, mut response: Message) -> Message {
+        let view = self.view_of(client_ip);
+        let answer_ttl = view.as_ref().map_or(0, |view| view.ttl);
+        let qname = normalize_name(&question.name);
+        match self.select_replica(client_ip, &qname, "", view.as_deref()).await {
+            Some(replica) => response.answers.push(Record::a(&question.name, answer_ttl, replica)),
+            None => response.set_rcode(RCODE_SERVFAIL),
+        }
+        response
+    }
+
+    // This function picks the replica to send the client to, or None when a geo-fence leaves no replica.
+    // Clients of a view get the address of the replica in that view.
+    async fn select_replica(
+        &mut self,
+        client_ip: &str,
+        qname: &str,
+        content: &str,
+        view: Option<&View>,
+    ) -> Option<Ipv4Addr> {
+        let sorted_cdn_servers = self.get_sorted_cdn_servers(&client_ip, qname, content, view).await?;
+
+        // When all the HTTP servers are down, route the client to my AWS ec2 instance
+        if sorted_cdn_servers.is_empty() {
+            *self.selection_metrics.lock().await.entry("fallback".to_string()).or_insert(0) += 1;
+            return Some(Ipv4Addr::new(3, 129, 217, 143));
+        }
+        let mut chosen = sorted_cdn_servers[0].1.clone();
+
+        // Keep sending the client prefix to its previous replica while it's still a candidate and not too loaded.
+        // Like the ranking, a replica near its capacity is only kept when the best candidate is too.
+        if let Some(affinity_config) = &self.config.affinity {
+            let key = match client_ip.parse::<IpAddr>() {
+                Ok(ip) => format!("{} {}", client_prefix(&ip), content),
+                Err(_) => format!("{} {}", client_ip, content),
+            };
+            let mut affinity = self.affinity.lock().await;
+            match affinity.get(&key) {
+                Some(previous) if sorted_cdn_servers.iter().any(|(_, ip)| *ip == previous) => {
+                    let cpu_usage = self.cpu_usage.lock().await;
+                    let usage = *cpu_usage.get(&previous).unwrap_or(&0_f32);
+                    drop(cpu_usage);
+                    let full = self.near_capacity(&previous).await && !self.near_capacity(&chosen).await;
+                    if usage <= affinity_config.max_load && !full {
+                        dbg!(format!("Explain: {} sticks to {}", key, previous));
+                        chosen = previous;
+                    } else {
+                        affinity.insert(&key, &chosen);
+                    }
+                }
+                _ => affinity.insert(&key, &chosen),
+            }
+            drop(affinity);
+        }
+
+        self.record_selection(&chosen).await;
+        match view.and_then(|view| view.addresses.get(&chosen)) {
+            Some(address) => Some(*address),
+            None => Some(chosen.parse().unwrap()),
+        }
+    }
 
-        // Encode the DNS response
-        let response = dns_response.encode().unwrap();
+    // This function applies an RFC 2136 UPDATE to the static records. Only updates signed with one of the
+    // update keys are accepted; the change is journaled, bumps the serial, and is announced to the secondaries.
+    async fn apply_update(&mut self, client_address: &str, query: &Message, key_name: Option<String>) -> Message {
+        let mut response = Message::response_to(query);
+        let allowed = match (&self.config.update, &key_name) {
+            (Some(update), Some(key_name)) => update.keys.iter().any(|key| normalize_name(key) == *key_name),
+            _ => false,
+        };
+        if !allowed {
+            dbg!(format!("Error: refused the update from {}", client_address));
+            response.set_rcode(RCODE_REFUSED);
+            return response;
+        }
+        // The zone section holds the SOA of the zone
+        match query.questions.first() {
+            Some(question) if query.questions.len() == 1 && question.qtype == TYPE_SOA => {
+                if normalize_name(&question.name) != self.zone {
+                    response.set_rcode(RCODE_NOTAUTH);
+                    return response;
+                }
+            }
+            _ => {
+                response.set_rcode(RCODE_FORMERR);
+                return response;
+            }
+        }
 
+        // Names answered by the CDN selection are protected: the A record of the apex, and the bucket names
+        let dynamic = |name: &str, rtype: u16| {
+            (name == self.zone && (rtype == TYPE_A || rtype == TYPE_ANY)) || self.content_group(name).is_some()
+        };
+        // Hold the lock so updates are applied one at a time
+        let mut static_zone = self.static_zone.lock().await;
+        match prepare_update(&static_zone, &self.zone, query, &dynamic) {
+            Ok((removed, added)) if removed.is_empty() && added.is_empty() => {}
+            Ok((removed, added)) => match static_zone.commit(&removed, &added) {
+                Ok(updated) => {
+                    println!(
+                        "Zone {} updated by {}: {} removed, {} added, serial {}",
+                        self.zone,
+                        key_name.unwrap_or_default(),
+                        removed.len(),
+                        added.len(),
+                        updated.serial()
+                    );
+                    *static_zone = Arc::new(updated);
+                    drop(static_zone);
+                    self.notify_secondaries().await;
+                }
+                Err(e) => {
+                    dbg!(format!("Error: can't apply the update: {}", e));
+                    response.set_rcode(RCODE_SERVFAIL);
+                }
+            },
+            Err(rcode) => response.set_rcode(rcode),
+        }
         response
     }
 
+    // This function returns the NS records of the zone: those of the zone file, or our own name server
+    fn ns_records(&self, static_zone: &StaticZone) -> Vec<Record> {
+        let ns: Vec<Record> = static_zone
+            .lookup(&self.zone)
+            .into_iter()
+            .filter(|rr| rr.rtype == TYPE_NS)
+            .collect();
+        if ns.is_empty() {
+            return vec![Record::ns(&self.zone, ZONE_TTL, &self.ns_name)];
+        }
+        ns
+    }
+
+    // This function answers AXFR and IXFR queries, received over TCP, with the encoded messages of the transfer.
+    // Only the SOA, NS and static records are transferred: CDN answers depend on the client.
+    pub async fn transfer(&mut self, client_address: &str, query: &Message) -> Vec<Vec<u8>> {
+        let client_ip = client_address.parse::<SocketAddr>().map(|address| address.ip()).ok();
+        let mut response = Message::response_to(query);
+
+        // Only the configured secondaries, signing with the transfer key, may transfer the zone
+        let key_name = match &query.tsig {
+            Some(tsig) => match self.tsig_keys.verify(tsig) {
+                Ok(key_name) => Some(key_name),
+                Err(error) => return vec![self.tsig_keys.error_response(query, tsig, error).encode()],
+            },
+            None => None,
+        };
+        let mut tsig: Option<Tsig> = query.tsig.as_ref().map(|tsig| self.tsig_keys.sign_response(tsig, 0));
+        let allowed = match &self.config.transfer {
+            Some(transfer) => {
+                key_name == Some(normalize_name(&transfer.key))
+                    && transfer
+                        .secondaries
+                        .iter()
+                        .any(|secondary| server_address(secondary).map(|address| address.ip()) == client_ip)
+            }
+            None => false,
+        };
+        let question = query.questions[0].clone();
+        if !allowed || normalize_name(&question.name) != self.zone {
+            dbg!(format!("Error: refused the zone transfer to {}", client_address));
+            response.set_rcode(RCODE_REFUSED);
+            response.tsig = tsig;
+            return vec![response.encode()];
+        }
+        response.set_authoritative(true);
+
+        let static_zone = self.static_zone.lock().await.clone();
+        let soa = static_zone.soa();
+        let mut full = vec![soa.clone()];
+        if static_zone.lookup(&self.zone).iter().all(|rr| rr.rtype != TYPE_NS) {
+            full.extend(self.ns_records(&static_zone));
+        }
+        full.extend(static_zone.records().iter().cloned());
+        full.push(soa.clone());
+
+        let records = if question.qtype == TYPE_IXFR {
+            // The secondary sends its SOA in the authority section
+            match query.authorities.iter().find(|rr| rr.rtype == TYPE_SOA).and_then(soa_serial) {
+                // Already up to date
+                Some(serial) if !serial_greater(static_zone.serial(), serial) => vec![soa],
+                Some(serial) => match static_zone.changes_since(serial) {
+                    Some(changes) => {
+                        let mut records = vec![soa.clone()];
+                        for change in changes.into_iter() {
+                            records.push(change.old_soa);
+                            records.extend(change.removed);
+                            records.push(change.new_soa);
+                            records.extend(change.added);
+                        }
+                        records.push(soa);
+                        records
+                    }
+                    // Too old for our history: send the whole zone
+                    None => full,
+                },
+                None => {
+                    response.set_rcode(RCODE_FORMERR);
+                    response.tsig = tsig;
+                    return vec![response.encode()];
+                }
+            }
+        } else {
+            full
+        };
+
+        // Split the records over several messages, each small enough for TCP and TSIG chained to the previous one
+        let mut chunks: Vec<Vec<Record>> = Vec::new();
+        let mut size = 0;
+        for record in records {
+            // Uncompressed size: the name, then type, class, TTL and rdata length
+            let record_size = record.name.len() + 12 + record.rdata.len();
+            match chunks.last_mut() {
+                Some(chunk)
+                    if chunk.len() < TRANSFER_RECORDS_PER_MESSAGE
+                        && size + record_size <= MAX_TCP_MESSAGE - TRANSFER_MESSAGE_OVERHEAD =>
+                {
+                    size += record_size;
+                    chunk.push(record);
+                }
+                _ => {
+                    size = record_size;
+                    chunks.push(vec![record]);
+                }
+            }
+        }
+        let mut messages = Vec::new();
+        for chunk in chunks {
+            let mut message = response.clone();
+            message.answers = chunk;
+            let mut wire = message.encode();
+            if let Some(signer) = &tsig {
+                let mac = signer.sign(&mut wire);
+                tsig = Some(signer.next(mac));
+            }
+            messages.push(wire);
+        }
+        messages
+    }
+
+    // This function sends a NOTIFY for the zone to every secondary
+    async fn notify_secondaries(&self) {
+        let transfer = match &self.config.transfer {
+            Some(transfer) => transfer.clone(),
+            None => return,
+        };
+        let soa = self.static_zone.lock().await.soa();
+        for secondary in transfer.secondaries.iter() {
+            let address = match server_address(secondary) {
+                Some(address) => address,
+                None => continue,
+            };
+            let zone = self.zone.clone();
+            let soa = soa.clone();
+            let tsig = self.tsig_keys.sign_request(&transfer.key);
+
+            // Spawn worker thread to notify the secondary, retrying until it answers
+            tokio::spawn(async move {
+                let mut id = [0_u8; 2];
+                SystemRandom::new().fill(&mut id).unwrap();
+                let mut notify = Message::request(u16::from_be_bytes(id), OPCODE_NOTIFY, &zone, TYPE_SOA);
+                notify.set_authoritative(true);
+                notify.answers.push(soa);
+                notify.tsig = tsig;
+
+                let bind_address = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                let socket = match tokio::net::UdpSocket::bind(bind_address).await {
+                    Ok(socket) => socket,
+                    Err(_) => return,
+                };
+                let mut buf = [0; 1024];
+                for _ in 0..NOTIFY_ATTEMPTS {
+                    if socket.send_to(&notify.encode(), address).await.is_err() {
+                        break;
+                    }
+                    if let Ok(Ok((amt, _))) = tokio::time::timeout(NOTIFY_TIMEOUT, socket.recv_from(&mut buf)).await {
+                        if let Ok(answer) = Message::decode(&buf[..amt]) {
+                            if answer.id == notify.id && answer.is_response() {
+                                return;
+                            }
+                        }
+                    }
+                }
+                dbg!(format!("Error: secondary {} didn't answer the NOTIFY", address));
+            });
+        }
+    }
+
     // This function is used to probe the HTTP server's CPU usage.
     pub async fn get_usage(domain: String, port: String) -> Result<String, ()> {
+        DnsServer::get_api(domain, port, "getUsage").await
+    }
+
+    // This function is used to get the throughput of the HTTP server.
+    pub async fn get_throughput(domain: String, port: String) -> Result<String, ()> {
+        DnsServer::get_api(domain, port, "getThroughput").await
+    }
+
+    // This function is used to get the client RTT table measured by the HTTP server.
+    pub async fn get_latency(domain: String, port: String) -> Result<String, ()> {
+        DnsServer::get_api(domain, port, "getLatency").await
+    }
+
+    // This function is used to call an API of the HTTP server and get its text answer.
+    async fn get_api(domain: String, port: String, api: &str) -> Result<String, ()> {
         let client = reqwest::Client::new();
 
         match client
-            .get(&format!("http://{}:{}/api/getUsage", domain, port))
+            .get(&format!("http://{}:{}/api/{}", domain, port, api))
             .send()
             .await
         {
@@ -468,3 +1696,251 @@ impl DnsServer {
         }
     }
 }
+
+// This function returns the /24 (IPv4) or /48 (IPv6) prefix of an address, as reported by the HTTP servers
+fn client_prefix(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.0/24", o[0], o[1], o[2])
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2])
+        }
+    }
+}
+
+// This function returns the SOA record of the zone when the zone file doesn't have one
+fn default_soa(zone: &str, ns_name: &str, serial: u32) -> Record {
+    let zone = normalize_name(zone);
+    Record::soa(
+        &zone,
+        ZONE_TTL,
+        &normalize_name(ns_name),
+        &format!("hostmaster.{}", zone),
+        serial,
+        NEGATIVE_TTL,
+    )
+}
+
+// This function parses the address of a DNS server, "ip" or "ip:port", with port 53 by default
+fn server_address(server: &str) -> Option<SocketAddr> {
+    match server.parse::<SocketAddr>() {
+        Ok(address) => Some(address),
+        Err(_) => server.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 53)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONDON: &str = "213.168.249.157";
+    const TORONTO: &str = "192.53.123.145";
+    const ATLANTA: &str = "170.187.142.220";
+    const TOKYO: &str = "139.162.82.207";
+    const MUMBAI: &str = "45.79.124.209";
+
+    // This function builds a server from a TOML config, with the CDN servers of init_cdn_geolocation
+    async fn server(config: &str) -> DnsServer {
+        let config: Config = toml::from_str(config).unwrap();
+        let mut server = DnsServer::new("0", "cdn.example", "ns1.cdn.example", config);
+        server.init_cdn_geolocation().await;
+        server
+    }
+
+    // This function places a client, so it isn't geolocated
+    async fn locate(server: &DnsServer, client_ip: &str, country: &str, continent: &str) {
+        let geo = GeoInfo {
+            location: Location::new(51.5, -0.12),
+            country: Some(country.to_string()),
+            continent: Some(continent.to_string()),
+        };
+        let client_geo = server.client_geo_at(geo).await;
+        server.client_distance_cache.lock().await.insert(client_ip.to_string(), client_geo);
+    }
+
+    async fn ranked(server: &mut DnsServer, client_ip: &str, qname: &str) -> Option<Vec<String>> {
+        let sorted = server.get_sorted_cdn_servers(client_ip, qname, "", None).await?;
+        Some(sorted.into_iter().map(|(_, ip)| ip).collect())
+    }
+
+    const POLICIES: &str = r#"
+        [pools]
+        asia = ["139.162.82.207", "45.79.124.209"]
+        us = ["170.187.142.220"]
+
+        [[policies]]
+        country = "DE"
+        allow_pools = ["asia"]
+
+        [[policies]]
+        continent = "EU"
+        prefer_pools = ["us"]
+
+        [[policies]]
+        zone = "video.cdn.example"
+        country = "FR"
+        allow_pools = ["us"]
+    "#;
+
+    #[tokio::test]
+    async fn policies_match_country_continent_and_zone() {
+        let server = server(POLICIES).await;
+        let geo = |country: Option<&str>, continent: Option<&str>| ClientGeo {
+            distances: HashMap::new(),
+            country: country.map(|c| c.to_string()),
+            continent: continent.map(|c| c.to_string()),
+        };
+        let policies = &server.config.policies;
+
+        assert!(server.policy_matches(&policies[0], "cdn.example.", &geo(Some("de"), None)));
+        assert!(!server.policy_matches(&policies[0], "cdn.example.", &geo(Some("FR"), Some("EU"))));
+        // A client whose country isn't known doesn't match a country policy
+        assert!(!server.policy_matches(&policies[0], "cdn.example.", &geo(None, Some("EU"))));
+        assert!(server.policy_matches(&policies[1], "cdn.example.", &geo(Some("FR"), Some("EU"))));
+        // Zone policies only apply to the names of their zone
+        assert!(server.policy_matches(&policies[2], "a.video.cdn.example.", &geo(Some("FR"), Some("EU"))));
+        assert!(!server.policy_matches(&policies[2], "cdn.example.", &geo(Some("FR"), Some("EU"))));
+    }
+
+    #[tokio::test]
+    async fn preferred_pools_go_first() {
+        let mut server = server(POLICIES).await;
+        locate(&server, "198.51.100.1", "GB", "EU").await;
+
+        let order = ranked(&mut server, "198.51.100.1", "cdn.example.").await.unwrap();
+        assert_eq!(order[..3], [ATLANTA, LONDON, TORONTO]);
+        assert_eq!(order.len(), 7);
+    }
+
+    #[tokio::test]
+    async fn geo_fence_keeps_allowed_pools() {
+        let mut server = server(POLICIES).await;
+        locate(&server, "198.51.100.2", "DE", "EU").await;
+
+        // The fence wins over the preference for a pool outside it
+        let order = ranked(&mut server, "198.51.100.2", "cdn.example.").await.unwrap();
+        assert_eq!(order, [MUMBAI, TOKYO]);
+
+        // No answer rather than a replica outside the fence
+        server.availability.lock().await.insert(MUMBAI.to_string(), false);
+        server.cpu_usage.lock().await.insert(TOKYO.to_string(), 95.0);
+        assert!(ranked(&mut server, "198.51.100.2", "cdn.example.").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn zone_fence_only_applies_in_its_zone() {
+        let mut server = server(POLICIES).await;
+        locate(&server, "198.51.100.3", "FR", "EU").await;
+
+        let ranked_video = ranked(&mut server, "198.51.100.3", "a.video.cdn.example.").await.unwrap();
+        assert_eq!(ranked_video, [ATLANTA]);
+        let ranked_apex = ranked(&mut server, "198.51.100.3", "cdn.example.").await.unwrap();
+        assert_eq!(ranked_apex.len(), 7);
+    }
+
+    const CAPACITY: &str = r#"
+        capacity_threshold = 0.8
+
+        [capacity."213.168.249.157"]
+        rps = 1000.0
+        mbps = 500.0
+
+        [capacity."192.53.123.145"]
+        rps = 1000.0
+    "#;
+
+    #[tokio::test]
+    async fn near_capacity_at_the_threshold() {
+        let server = server(CAPACITY).await;
+        // No report yet, or no capacity configured
+        assert!(!server.near_capacity(LONDON).await);
+        server.throughput.lock().await.insert(ATLANTA.to_string(), (1e6, 1e6));
+        assert!(!server.near_capacity(ATLANTA).await);
+
+        server.throughput.lock().await.insert(LONDON.to_string(), (799.0, 399.0));
+        assert!(!server.near_capacity(LONDON).await);
+        server.throughput.lock().await.insert(LONDON.to_string(), (800.0, 0.0));
+        assert!(server.near_capacity(LONDON).await);
+        // Either limit is enough
+        server.throughput.lock().await.insert(LONDON.to_string(), (0.0, 400.0));
+        assert!(server.near_capacity(LONDON).await);
+    }
+
+    #[tokio::test]
+    async fn full_replicas_spill_to_the_next_nearest() {
+        let mut server = server(CAPACITY).await;
+        locate(&server, "198.51.100.4", "GB", "EU").await;
+        assert_eq!(ranked(&mut server, "198.51.100.4", "cdn.example.").await.unwrap()[..2], [LONDON, TORONTO]);
+
+        server.throughput.lock().await.insert(LONDON.to_string(), (900.0, 10.0));
+        let order = ranked(&mut server, "198.51.100.4", "cdn.example.").await.unwrap();
+        assert_eq!(order[..2], [TORONTO, ATLANTA]);
+        // Full replicas are still used, last, when nothing else is left
+        assert_eq!(order.last().unwrap(), LONDON);
+
+        server.throughput.lock().await.insert(TORONTO.to_string(), (950.0, 10.0));
+        let order = ranked(&mut server, "198.51.100.4", "cdn.example.").await.unwrap();
+        assert_eq!(order[0], ATLANTA);
+        assert_eq!(order[5..], [LONDON, TORONTO]);
+    }
+
+    const VIEWS: &str = r#"
+        [pools]
+        asia = ["139.162.82.207", "45.79.124.209"]
+
+        [[policies]]
+        continent = "EU"
+        allow_pools = ["asia"]
+
+        [[views]]
+        name = "office"
+        cidrs = ["10.0.0.0/8"]
+        ttl = 30
+        policies = []
+
+        [views.addresses]
+        "213.168.249.157" = "10.0.0.7"
+        "192.53.123.145" = "10.0.0.8"
+
+        [[views]]
+        name = "lab"
+        cidrs = ["10.1.0.0/16"]
+    "#;
+
+    // This function asks for the A record of the zone apex and returns the answer address and TTL
+    async fn apex_a(server: &mut DnsServer, client_ip: &str) -> (Vec<u8>, u32) {
+        let query = Message::request(1, OPCODE_QUERY, "cdn.example.", TYPE_A);
+        let response = server.answer_query(client_ip, &query).await;
+        assert_eq!(response.answers.len(), 1);
+        (response.answers[0].rdata.clone(), response.answers[0].ttl)
+    }
+
+    #[tokio::test]
+    async fn views_are_selected_by_longest_prefix() {
+        let server = server(VIEWS).await;
+        assert_eq!(server.view_of("10.1.2.3").unwrap().name, "lab");
+        assert_eq!(server.view_of("10.2.3.4").unwrap().name, "office");
+        assert!(server.view_of("192.0.2.1").is_none());
+        assert!(server.view_of("not-an-ip").is_none());
+    }
+
+    #[tokio::test]
+    async fn view_addresses_ttl_and_policies_apply() {
+        let mut server = server(VIEWS).await;
+        for client_ip in ["10.2.3.4", "10.1.2.3", "192.0.2.1"] {
+            locate(&server, client_ip, "GB", "EU").await;
+        }
+
+        // The office view gives out its own addresses, only for its replicas, without the global fence
+        assert_eq!(apex_a(&mut server, "10.2.3.4").await, (vec![10, 0, 0, 7], 30));
+        server.availability.lock().await.insert(LONDON.to_string(), false);
+        assert_eq!(apex_a(&mut server, "10.2.3.4").await, (vec![10, 0, 0, 8], 30));
+
+        // The lab view has the global policies and the public addresses, like clients outside the views
+        assert_eq!(apex_a(&mut server, "10.1.2.3").await, (vec![45, 79, 124, 209], 0));
+        assert_eq!(apex_a(&mut server, "192.0.2.1").await, (vec![45, 79, 124, 209], 0));
+    }
+}
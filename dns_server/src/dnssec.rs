@@ -0,0 +1,383 @@
+use crate::message::{
+    label_count, normalize_name, read_name, write_name, Record, TYPE_CNAME, TYPE_DNSKEY, TYPE_MX, TYPE_NS, TYPE_NSEC,
+    TYPE_PTR, TYPE_RRSIG, TYPE_SOA,
+};
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// DNSSEC algorithm numbers
+const ALGORITHM_ECDSAP256SHA256: u8 = 13;
+const ALGORITHM_ED25519: u8 = 15;
+
+// DNSKEY flags for a combined signing key (zone key + secure entry point)
+const DNSKEY_FLAGS_CSK: u16 = 257;
+
+// Signatures are valid from one hour in the past (clock skew) until two days ahead
+const INCEPTION_OFFSET: u32 = 3600;
+const VALIDITY_PERIOD: u32 = 2 * 86400;
+
+// State of a key during a rollover, taken from the key file name: <name>.<state>.pk8
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyState {
+    // Published in the DNSKEY set, but not signing yet
+    Published,
+    // Published and signing
+    Active,
+    // Still published so cached signatures stay valid, but no longer signing
+    Retired,
+}
+
+enum SigningKey {
+    Ecdsa(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+// Define the ZoneKey struct
+struct ZoneKey {
+    state: KeyState,
+    algorithm: u8,
+    key_tag: u16,
+    dnskey_rdata: Vec<u8>,
+    signing_key: SigningKey,
+}
+
+// Define the ZoneSigner struct, which signs the answers of one zone on the fly
+pub struct ZoneSigner {
+    zone: String,
+    keys: Vec<ZoneKey>,
+    rng: SystemRandom,
+}
+
+impl ZoneSigner {
+    // This function is used to load every key of the zone from the key directory
+    pub fn load(zone: &str, key_dir: &str) -> Result<Self, String> {
+        let rng = SystemRandom::new();
+        let mut keys = Vec::new();
+
+        let entries = fs::read_dir(key_dir).map_err(|e| format!("can't read {}: {}", key_dir, e))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let state = match key_state(&path) {
+                Some(state) => state,
+                None => continue,
+            };
+            let der = fs::read(&path).map_err(|e| format!("can't read {:?}: {}", path, e))?;
+            let (algorithm, signing_key, public_key) = parse_key(&der, &rng)
+                .ok_or(format!("{:?} isn't an Ed25519 or ECDSA P-256 PKCS#8 key", path))?;
+
+            let mut dnskey_rdata = Vec::new();
+            dnskey_rdata.extend_from_slice(&DNSKEY_FLAGS_CSK.to_be_bytes());
+            dnskey_rdata.push(3);
+            dnskey_rdata.push(algorithm);
+            dnskey_rdata.extend_from_slice(&public_key);
+
+            keys.push(ZoneKey {
+                state,
+                algorithm,
+                key_tag: key_tag(&dnskey_rdata),
+                dnskey_rdata,
+                signing_key,
+            });
+        }
+
+        if !keys.iter().any(|key| key.state == KeyState::Active) {
+            return Err(format!("no active key found in {}", key_dir));
+        }
+        keys.sort_by_key(|key| key.key_tag);
+
+        Ok(ZoneSigner {
+            zone: normalize_name(zone),
+            keys,
+            rng,
+        })
+    }
+
+    // This function returns the DNSKEY set of the zone: every published, active and retired key
+    pub fn dnskey_records(&self, ttl: u32) -> Vec<Record> {
+        self.keys
+            .iter()
+            .map(|key| Record::new(&self.zone, TYPE_DNSKEY, ttl, key.dnskey_rdata.clone()))
+            .collect()
+    }
+
+    // This function returns the DS records (SHA-256) a parent or a trust anchor needs, in presentation format
+    pub fn ds_records(&self) -> Vec<String> {
+        self.keys
+            .iter()
+            .filter(|key| key.state != KeyState::Retired)
+            .map(|key| {
+                let mut data = Vec::new();
+                write_name(&mut data, &self.zone);
+                data.extend_from_slice(&key.dnskey_rdata);
+                let hash = digest::digest(&digest::SHA256, &data);
+                let hex: String = hash.as_ref().iter().map(|b| format!("{:02X}", b)).collect();
+                format!("{}. IN DS {} {} 2 {} ; {:?}", self.zone, key.key_tag, key.algorithm, hex, key.state)
+            })
+            .collect()
+    }
+
+    // This function creates the RRSIG records for every RRset found in the given records
+    pub fn sign_records(&self, records: &[Record]) -> Vec<Record> {
+        let mut rrsets: Vec<Vec<&Record>> = Vec::new();
+        for record in records.iter().filter(|rr| rr.rtype != TYPE_RRSIG) {
+            match rrsets.iter_mut().find(|set| {
+                set[0].rtype == record.rtype && set[0].name.eq_ignore_ascii_case(&record.name)
+            }) {
+                Some(set) => set.push(record),
+                None => rrsets.push(vec![record]),
+            }
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        let mut signatures = Vec::new();
+        for rrset in rrsets.iter() {
+            for key in self.keys.iter().filter(|key| key.state == KeyState::Active) {
+                let validity = (now - INCEPTION_OFFSET, now + VALIDITY_PERIOD);
+                if let Some(rrsig) = self.sign_rrset(key, rrset, validity) {
+                    signatures.push(rrsig);
+                }
+            }
+        }
+        signatures
+    }
+
+    // This function creates an NSEC "black lie" for the name: it claims the name exists
+    // with only the given types, and that the next name is right after it.
+    pub fn black_lie(&self, name: &str, types: &[u16], ttl: u32) -> Record {
+        let name = normalize_name(name);
+        let mut rdata = Vec::new();
+        write_name(&mut rdata, &format!("\u{0}.{}", name));
+        let mut all_types = types.to_vec();
+        all_types.push(TYPE_RRSIG);
+        all_types.push(TYPE_NSEC);
+        rdata.extend_from_slice(&type_bitmap(&all_types));
+        Record::new(&name, TYPE_NSEC, ttl, rdata)
+    }
+
+    // This function signs one RRset with one key, for the given inception and expiration times
+    fn sign_rrset(&self, key: &ZoneKey, rrset: &[&Record], (inception, expiration): (u32, u32)) -> Option<Record> {
+        let first = rrset[0];
+        let owner = normalize_name(&first.name);
+
+        // RRSIG rdata without the signature
+        let mut rdata = Vec::new();
+        rdata.extend_from_slice(&first.rtype.to_be_bytes());
+        rdata.push(key.algorithm);
+        rdata.push(label_count(&owner) as u8);
+        rdata.extend_from_slice(&first.ttl.to_be_bytes());
+        rdata.extend_from_slice(&expiration.to_be_bytes());
+        rdata.extend_from_slice(&inception.to_be_bytes());
+        rdata.extend_from_slice(&key.key_tag.to_be_bytes());
+        write_name(&mut rdata, &self.zone);
+
+        // Signed data is the RRSIG rdata followed by the RRset in canonical form and order
+        let mut sorted: Vec<(&Record, Vec<u8>)> = rrset.iter().map(|rr| (*rr, canonical_rdata(rr))).collect();
+        sorted.sort_by(|a, b| a.1.cmp(&b.1));
+        sorted.dedup_by(|a, b| a.1 == b.1);
+        let mut data = rdata.clone();
+        for (record, record_rdata) in sorted.iter() {
+            write_name(&mut data, &owner);
+            data.extend_from_slice(&record.rtype.to_be_bytes());
+            data.extend_from_slice(&record.class.to_be_bytes());
+            data.extend_from_slice(&first.ttl.to_be_bytes());
+            data.extend_from_slice(&(record_rdata.len() as u16).to_be_bytes());
+            data.extend_from_slice(record_rdata);
+        }
+
+        let signature = match &key.signing_key {
+            SigningKey::Ecdsa(pair) => pair.sign(&self.rng, &data).ok()?.as_ref().to_vec(),
+            SigningKey::Ed25519(pair) => pair.sign(&data).as_ref().to_vec(),
+        };
+        rdata.extend_from_slice(&signature);
+
+        Some(Record::new(&first.name, TYPE_RRSIG, first.ttl, rdata))
+    }
+}
+
+// This function returns the rdata of a record in canonical form (RFC 4034 section 6.2):
+// the domain names it holds (NS, CNAME, PTR, MX and SOA targets) are lowercased
+fn canonical_rdata(record: &Record) -> Vec<u8> {
+    let mut rdata = record.rdata.clone();
+    // Offset of the first name, and how many names follow
+    let (start, names) = match record.rtype {
+        TYPE_NS | TYPE_CNAME | TYPE_PTR => (0, 1),
+        TYPE_MX => (2, 1),
+        TYPE_SOA => (0, 2),
+        _ => return rdata,
+    };
+    let mut end = start;
+    for _ in 0..names {
+        match read_name(&rdata, end) {
+            Ok((_, next)) => end = next,
+            Err(_) => return rdata,
+        }
+    }
+    // Label lengths are below 64, so only the label characters change
+    rdata[start..end].make_ascii_lowercase();
+    rdata
+}
+
+// This function reads the rollover state from a key file name such as "k1.active.pk8"
+fn key_state(path: &Path) -> Option<KeyState> {
+    let file_name = path.file_name()?.to_str()?;
+    let stem = file_name.strip_suffix(".pk8")?;
+    match stem.rsplit('.').next()? {
+        "published" => Some(KeyState::Published),
+        "active" => Some(KeyState::Active),
+        "retired" => Some(KeyState::Retired),
+        _ => None,
+    }
+}
+
+// This function parses a PKCS#8 (DER) private key and returns the algorithm, the key and its DNSKEY public key
+fn parse_key(der: &[u8], rng: &SystemRandom) -> Option<(u8, SigningKey, Vec<u8>)> {
+    if let Ok(pair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der) {
+        let public_key = pair.public_key().as_ref().to_vec();
+        return Some((ALGORITHM_ED25519, SigningKey::Ed25519(pair), public_key));
+    }
+    if let Ok(pair) = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, der, rng) {
+        // DNSKEY carries the uncompressed point without its 0x04 prefix
+        let public_key = pair.public_key().as_ref()[1..].to_vec();
+        return Some((ALGORITHM_ECDSAP256SHA256, SigningKey::Ecdsa(pair), public_key));
+    }
+    None
+}
+
+// This function computes the key tag of a DNSKEY rdata (RFC 4034 Appendix B)
+fn key_tag(rdata: &[u8]) -> u16 {
+    let mut ac: u32 = 0;
+    for (i, byte) in rdata.iter().enumerate() {
+        if i & 1 == 1 {
+            ac += *byte as u32;
+        } else {
+            ac += (*byte as u32) << 8;
+        }
+    }
+    ac += (ac >> 16) & 0xffff;
+    (ac & 0xffff) as u16
+}
+
+// This function encodes the type bitmap of an NSEC record (RFC 4034 section 4.1.2)
+fn type_bitmap(types: &[u16]) -> Vec<u8> {
+    let mut types = types.to_vec();
+    types.sort();
+    types.dedup();
+
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < types.len() {
+        let window = types[i] >> 8;
+        let mut bitmap = [0_u8; 32];
+        let mut length = 0;
+        while i < types.len() && types[i] >> 8 == window {
+            let low = (types[i] & 0xff) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+            length = low / 8 + 1;
+            i += 1;
+        }
+        out.push(window as u8);
+        out.push(length as u8);
+        out.extend_from_slice(&bitmap[..length]);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::TYPE_A;
+
+    // Ed25519 example key of RFC 8080 section 6, as PKCS#8
+    const RFC8080_KEY: &str = "302e020100300506032b6570042204203832323630333834363238303830313232363435313930323034313432323632";
+
+    fn rfc8080_signer() -> ZoneSigner {
+        let der: Vec<u8> = (0..RFC8080_KEY.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&RFC8080_KEY[i..i + 2], 16).unwrap())
+            .collect();
+        let rng = SystemRandom::new();
+        let (algorithm, signing_key, public_key) = parse_key(&der, &rng).unwrap();
+        let mut dnskey_rdata = vec![1, 1, 3, algorithm];
+        dnskey_rdata.extend_from_slice(&public_key);
+        ZoneSigner {
+            zone: "example.com".to_string(),
+            keys: vec![ZoneKey {
+                state: KeyState::Active,
+                algorithm,
+                key_tag: key_tag(&dnskey_rdata),
+                dnskey_rdata,
+                signing_key,
+            }],
+            rng,
+        }
+    }
+
+    fn mx(exchange: &str) -> Record {
+        let mut rdata = 10_u16.to_be_bytes().to_vec();
+        write_name(&mut rdata, exchange);
+        Record::new("example.com", TYPE_MX, 3600, rdata)
+    }
+
+    #[test]
+    fn key_tag_and_ds_match_rfc8080() {
+        let signer = rfc8080_signer();
+        assert_eq!(signer.keys[0].key_tag, 3613);
+        assert_eq!(
+            signer.ds_records(),
+            vec!["example.com. IN DS 3613 15 2 3AA5AB37EFCE57F737FC1627013FEE07BDF241BD10F3B1964AB55C78E79A304B ; Active"]
+        );
+    }
+
+    #[test]
+    fn rrsig_matches_rfc8080() {
+        let signer = rfc8080_signer();
+        let record = mx("mail.example.com");
+        let rrsig = signer
+            .sign_rrset(&signer.keys[0], &[&record], (1438207200, 1440021600))
+            .unwrap();
+
+        let mut expected = vec![0, 15, 15, 2, 0, 0, 0x0e, 0x10];
+        expected.extend_from_slice(&1440021600_u32.to_be_bytes());
+        expected.extend_from_slice(&1438207200_u32.to_be_bytes());
+        expected.extend_from_slice(&3613_u16.to_be_bytes());
+        write_name(&mut expected, "example.com");
+        let signature = concat!(
+            "a0bf64ac9ba7ef17c138859c1878bb99a839fe1759aca5b0d798cf1ab1e98d07",
+            "9102f4ddb3368f0fe40bb377f1f00e0cddedb799167d56b6e932783072ba8d02",
+        );
+        expected.extend((0..signature.len()).step_by(2).map(|i| u8::from_str_radix(&signature[i..i + 2], 16).unwrap()));
+        assert_eq!(rrsig.rtype, TYPE_RRSIG);
+        assert_eq!(rrsig.rdata, expected);
+    }
+
+    #[test]
+    fn rrsig_uses_lowercase_rdata_names() {
+        let signer = rfc8080_signer();
+        let validity = (1438207200, 1440021600);
+        let lower = mx("mail.example.com");
+        let upper = mx("MAIL.Example.COM");
+        assert_eq!(
+            signer.sign_rrset(&signer.keys[0], &[&lower], validity),
+            signer.sign_rrset(&signer.keys[0], &[&upper], validity)
+        );
+    }
+
+    #[test]
+    fn black_lie_denies_other_types() {
+        let signer = rfc8080_signer();
+        let nsec = signer.black_lie("WWW.example.com.", &[TYPE_A], 300);
+        assert_eq!(nsec.name, "www.example.com");
+        assert_eq!(nsec.rtype, TYPE_NSEC);
+        assert_eq!(nsec.ttl, 300);
+
+        // Next name \000.www.example.com, then A, RRSIG and NSEC in window 0
+        let mut expected = Vec::new();
+        write_name(&mut expected, "\u{0}.www.example.com");
+        expected.extend_from_slice(&[0, 6, 0x40, 0, 0, 0, 0, 0x03]);
+        assert_eq!(nsec.rdata, expected);
+    }
+}
@@ -0,0 +1,323 @@
+use crate::cidr_trie::CidrTrie;
+use crate::message::{normalize_name, Message, OPCODE_QUERY, RCODE_NOTIMP, RCODE_REFUSED, RCODE_SERVFAIL, TYPE_OPT, TYPE_SOA};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+// Answers are cached for at most this long, whatever their TTL
+const MAX_CACHE_TTL: u32 = 3600;
+
+// Define the CachedAnswer struct
+struct CachedAnswer {
+    response: Message,
+    stored: Instant,
+    ttl: u32,
+}
+
+// Define the Forwarder struct: relays queries outside the zone to upstream resolvers, with an answer cache
+pub struct Forwarder {
+    upstreams: Vec<SocketAddr>,
+    timeout: Duration,
+    // Clients allowed to use the forwarder
+    allow: CidrTrie<()>,
+    // Upstream that answered last, tried first
+    preferred: AtomicUsize,
+    cache: Mutex<HashMap<String, CachedAnswer>>,
+    // Cache keys in insertion order, to evict the oldest when the cache is full
+    cache_order: Mutex<VecDeque<String>>,
+    cache_size: usize,
+}
+
+impl Forwarder {
+    // This function is used to create the forwarder
+    pub fn new(upstreams: Vec<SocketAddr>, timeout: Duration, allow: &[String], cache_size: usize) -> Result<Self, String> {
+        let mut allowed = CidrTrie::new();
+        for cidr in allow.iter() {
+            allowed.insert(cidr, ())?;
+        }
+        Ok(Forwarder {
+            upstreams,
+            timeout,
+            allow: allowed,
+            preferred: AtomicUsize::new(0),
+            cache: Mutex::new(HashMap::new()),
+            cache_order: Mutex::new(VecDeque::new()),
+            cache_size,
+        })
+    }
+
+    // This function checks whether a client may use the forwarder
+    pub fn allows(&self, client_ip: &IpAddr) -> bool {
+        self.allow.longest_match(client_ip).is_some()
+    }
+
+    // This function answers a query from the cache, or from the first upstream that answers it.
+    // It returns None when every upstream failed.
+    pub async fn forward(&self, query: &Message) -> Option<Message> {
+        let question = query.questions.first()?.clone();
+        let dnssec_ok = query.dnssec_ok();
+        let key = format!(
+            "{} {} {} {}",
+            normalize_name(&question.name),
+            question.qtype,
+            question.qclass,
+            dnssec_ok
+        );
+        if let Some(response) = self.cached(&key).await {
+            return Some(response);
+        }
+
+        let mut id = [0_u8; 2];
+        SystemRandom::new().fill(&mut id).unwrap();
+        let mut request = Message::request(u16::from_be_bytes(id), OPCODE_QUERY, &question.name, question.qtype);
+        request.questions[0].qclass = question.qclass;
+        request.set_recursion_desired(true);
+        request.add_edns(dnssec_ok);
+
+        // Try the upstreams in order, starting with the one that answered last
+        let first = self.preferred.load(Ordering::Relaxed);
+        for i in 0..self.upstreams.len() {
+            let index = (first + i) % self.upstreams.len();
+            let upstream = self.upstreams[index];
+            match self.exchange(upstream, &request).await {
+                Some(response) if ![RCODE_SERVFAIL, RCODE_NOTIMP, RCODE_REFUSED].contains(&response.rcode()) => {
+                    self.preferred.store(index, Ordering::Relaxed);
+                    self.store(&key, &response).await;
+                    return Some(response);
+                }
+                _ => {
+                    dbg!(format!("Error: upstream {} failed for {}", upstream, question.name));
+                }
+            }
+        }
+        None
+    }
+
+    // This function sends the request to one upstream over UDP, and again over TCP when the answer is truncated
+    async fn exchange(&self, upstream: SocketAddr, request: &Message) -> Option<Message> {
+        let wire = request.encode();
+        let bind_address = if upstream.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_address).await.ok()?;
+        socket.send_to(&wire, upstream).await.ok()?;
+
+        let response = timeout(self.timeout, async {
+            let mut buf = vec![0; 65535];
+            loop {
+                let (amt, src) = socket.recv_from(&mut buf).await.ok()?;
+                // Ignore anything that isn't the answer to our request
+                if src != upstream {
+                    continue;
+                }
+                match Message::decode(&buf[..amt]) {
+                    Ok(response) if is_answer(request, &response) => return Some(response),
+                    _ => continue,
+                }
+            }
+        })
+        .await
+        .ok()??;
+        if !response.is_truncated() {
+            return Some(response);
+        }
+
+        timeout(self.timeout, async {
+            let mut stream = TcpStream::connect(upstream).await.ok()?;
+            let mut framed = u16::try_from(wire.len()).ok()?.to_be_bytes().to_vec();
+            framed.extend_from_slice(&wire);
+            stream.write_all(&framed).await.ok()?;
+            let len = stream.read_u16().await.ok()? as usize;
+            let mut buf = vec![0; len];
+            stream.read_exact(&mut buf).await.ok()?;
+            Message::decode(&buf).ok().filter(|response| is_answer(request, response))
+        })
+        .await
+        .ok()?
+    }
+
+    // This function returns a cached answer, with its TTLs decreased by the time it spent in the cache
+    async fn cached(&self, key: &str) -> Option<Message> {
+        let cache = self.cache.lock().await;
+        let entry = cache.get(key)?;
+        let elapsed = entry.stored.elapsed().as_secs() as u32;
+        if elapsed >= entry.ttl {
+            return None;
+        }
+        let mut response = entry.response.clone();
+        drop(cache);
+        for record in response
+            .answers
+            .iter_mut()
+            .chain(response.authorities.iter_mut())
+            .chain(response.additionals.iter_mut())
+            .filter(|rr| rr.rtype != TYPE_OPT)
+        {
+            record.ttl = record.ttl.saturating_sub(elapsed);
+        }
+        Some(response)
+    }
+
+    // This function caches an answer for its smallest TTL (the SOA minimum for negative answers)
+    async fn store(&self, key: &str, response: &Message) {
+        let ttl = match cache_ttl(response) {
+            Some(ttl) if ttl > 0 && self.cache_size > 0 => ttl.min(MAX_CACHE_TTL),
+            _ => return,
+        };
+        let mut cache = self.cache.lock().await;
+        let mut cache_order = self.cache_order.lock().await;
+        if !cache.contains_key(key) {
+            // Make room: drop expired answers first, then the oldest ones
+            if cache.len() >= self.cache_size {
+                cache.retain(|_, entry| entry.stored.elapsed().as_secs() < entry.ttl as u64);
+                cache_order.retain(|key| cache.contains_key(key));
+            }
+            while cache.len() >= self.cache_size {
+                match cache_order.pop_front() {
+                    Some(oldest) => cache.remove(&oldest),
+                    None => break,
+                };
+            }
+            cache_order.push_back(key.to_string());
+        }
+        cache.insert(
+            key.to_string(),
+            CachedAnswer {
+                response: response.clone(),
+                stored: Instant::now(),
+                ttl,
+            },
+        );
+    }
+}
+
+// This function checks that a message is the answer to our request: same ID and same question
+fn is_answer(request: &Message, response: &Message) -> bool {
+    response.is_response()
+        && response.id == request.id
+        && response.questions.len() == 1
+        && normalize_name(&response.questions[0].name) == normalize_name(&request.questions[0].name)
+        && response.questions[0].qtype == request.questions[0].qtype
+}
+
+// This function returns how long an answer may be cached: its smallest TTL, or for negative answers
+// the smaller of the SOA TTL and the SOA minimum (RFC 2308)
+fn cache_ttl(response: &Message) -> Option<u32> {
+    if !response.answers.is_empty() {
+        return response
+            .answers
+            .iter()
+            .chain(response.authorities.iter())
+            .map(|rr| rr.ttl)
+            .min();
+    }
+    let soa = response.authorities.iter().find(|rr| rr.rtype == TYPE_SOA)?;
+    let minimum = soa.rdata.get(soa.rdata.len().checked_sub(4)?..)?;
+    Some(soa.ttl.min(u32::from_be_bytes([minimum[0], minimum[1], minimum[2], minimum[3]])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Record, RCODE_NXDOMAIN, TYPE_A};
+    use std::net::Ipv4Addr;
+
+    fn query(name: &str) -> Message {
+        Message::request(7, OPCODE_QUERY, name, TYPE_A)
+    }
+
+    fn positive(ttls: &[u32]) -> Message {
+        let mut response = Message::response_to(&query("www.example.net."));
+        for (i, ttl) in ttls.iter().enumerate() {
+            response.answers.push(Record::a("www.example.net.", *ttl, Ipv4Addr::new(192, 0, 2, i as u8 + 1)));
+        }
+        response
+    }
+
+    fn negative(soa_ttl: u32, minimum: u32) -> Message {
+        let mut response = Message::response_to(&query("missing.example.net."));
+        response.set_rcode(RCODE_NXDOMAIN);
+        response.authorities.push(Record::soa("example.net.", soa_ttl, "ns1.example.net.", "admin.example.net.", 1, minimum));
+        response
+    }
+
+    // This function moves a cached answer back in time, as if it had been stored `secs` ago
+    async fn age(forwarder: &Forwarder, key: &str, secs: u64) {
+        let mut cache = forwarder.cache.lock().await;
+        let entry = cache.get_mut(key).unwrap();
+        entry.stored = Instant::now().checked_sub(Duration::from_secs(secs)).unwrap();
+    }
+
+    #[test]
+    fn positive_answers_use_the_smallest_ttl() {
+        assert_eq!(cache_ttl(&positive(&[300, 60, 900])), Some(60));
+
+        let mut response = positive(&[300]);
+        response.authorities.push(Record::soa("example.net.", 30, "ns1.example.net.", "admin.example.net.", 1, 600));
+        assert_eq!(cache_ttl(&response), Some(30));
+    }
+
+    #[test]
+    fn negative_answers_use_the_soa() {
+        // The smaller of the SOA TTL and the SOA minimum
+        assert_eq!(cache_ttl(&negative(3600, 300)), Some(300));
+        assert_eq!(cache_ttl(&negative(120, 300)), Some(120));
+        // Without a SOA a negative answer isn't cached
+        assert_eq!(cache_ttl(&Message::response_to(&query("missing.example.net."))), None);
+    }
+
+    #[tokio::test]
+    async fn cached_ttls_count_down() {
+        let forwarder = Forwarder::new(vec![], Duration::from_secs(1), &[], 10).unwrap();
+        let mut response = positive(&[300, 100]);
+        response.add_edns(true);
+        forwarder.store("www", &response).await;
+
+        age(&forwarder, "www", 40).await;
+        let cached = forwarder.cached("www").await.unwrap();
+        assert_eq!(cached.answers.iter().map(|rr| rr.ttl).collect::<Vec<_>>(), vec![260, 60]);
+        // The OPT TTL holds flags, not a TTL
+        assert_eq!(cached.additionals[0].ttl, response.additionals[0].ttl);
+
+        // Expired once the smallest TTL has passed
+        age(&forwarder, "www", 100).await;
+        assert!(forwarder.cached("www").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn negative_answers_expire_with_the_soa_minimum() {
+        let forwarder = Forwarder::new(vec![], Duration::from_secs(1), &[], 10).unwrap();
+        forwarder.store("missing", &negative(3600, 60)).await;
+
+        age(&forwarder, "missing", 59).await;
+        assert_eq!(forwarder.cached("missing").await.unwrap().authorities[0].ttl, 3541);
+        age(&forwarder, "missing", 60).await;
+        assert!(forwarder.cached("missing").await.is_none());
+
+        // Negative answers without a SOA aren't stored
+        forwarder.store("nosoa", &Message::response_to(&query("missing.example.net."))).await;
+        assert!(forwarder.cached("nosoa").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn full_cache_evicts_the_oldest() {
+        let forwarder = Forwarder::new(vec![], Duration::from_secs(1), &[], 2).unwrap();
+        forwarder.store("a", &positive(&[300])).await;
+        forwarder.store("b", &positive(&[300])).await;
+        forwarder.store("c", &positive(&[300])).await;
+        assert!(forwarder.cached("a").await.is_none());
+        assert!(forwarder.cached("b").await.is_some());
+        assert!(forwarder.cached("c").await.is_some());
+    }
+
+    #[test]
+    fn allows_only_listed_clients() {
+        let forwarder = Forwarder::new(vec![], Duration::from_secs(1), &["10.0.0.0/8".to_string()], 10).unwrap();
+        assert!(forwarder.allows(&"10.1.2.3".parse().unwrap()));
+        assert!(!forwarder.allows(&"192.0.2.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,106 @@
+use ring::digest;
+
+// Define the HashRing struct: a consistent-hash ring with virtual nodes
+pub struct HashRing {
+    // Points on the ring, sorted by hash
+    points: Vec<(u64, String)>,
+}
+
+impl HashRing {
+    // This function is used to build the ring, placing every node at `vnodes` points
+    pub fn new(nodes: &[String], vnodes: usize) -> Self {
+        let mut points = Vec::new();
+        for node in nodes.iter() {
+            for i in 0..vnodes {
+                points.push((stable_hash(&format!("{}#{}", node, i)), node.clone()));
+            }
+        }
+        points.sort();
+        HashRing { points }
+    }
+
+    // This function returns every node once, in ring order starting from the key's position
+    pub fn walk(&self, key: &str) -> Vec<String> {
+        let mut nodes: Vec<String> = Vec::new();
+        if self.points.is_empty() {
+            return nodes;
+        }
+        let start = self.points.partition_point(|(point, _)| *point < stable_hash(key));
+        for i in 0..self.points.len() {
+            let (_, node) = &self.points[(start + i) % self.points.len()];
+            if !nodes.contains(node) {
+                nodes.push(node.clone());
+            }
+        }
+        nodes
+    }
+}
+
+// This function hashes a string to a point on the ring. It uses SHA-256 so every DNS server
+// gets the same points, whatever its Rust version.
+pub fn stable_hash(key: &str) -> u64 {
+    let hash = digest::digest(&digest::SHA256, key.as_bytes());
+    let mut bytes = [0_u8; 8];
+    bytes.copy_from_slice(&hash.as_ref()[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn nodes(count: usize) -> Vec<String> {
+        (1..=count).map(|i| format!("10.0.0.{}", i)).collect()
+    }
+
+    #[test]
+    fn walks_every_node_once() {
+        let ring = HashRing::new(&nodes(4), 100);
+        let mut walked = ring.walk("videos");
+        assert_eq!(walked.len(), 4);
+        walked.sort();
+        assert_eq!(walked, nodes(4));
+        assert_eq!(ring.walk("videos"), ring.walk("videos"));
+        assert!(HashRing::new(&[], 100).walk("videos").is_empty());
+    }
+
+    #[test]
+    fn spreads_the_keys_evenly() {
+        let ring = HashRing::new(&nodes(4), 100);
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for i in 0..4_000 {
+            *counts.entry(ring.walk(&format!("group-{}", i))[0].clone()).or_insert(0) += 1;
+        }
+        // Each node gets a quarter of the keys, give or take a third of it
+        for node in nodes(4).iter() {
+            let count = counts[node];
+            assert!((667..=1_333).contains(&count), "{} got {} keys", node, count);
+        }
+    }
+
+    #[test]
+    fn removing_a_node_only_moves_its_keys() {
+        let before = HashRing::new(&nodes(5), 100);
+        let after = HashRing::new(&nodes(4), 100);
+        let mut moved = 0;
+        for i in 0..4_000 {
+            let key = format!("group-{}", i);
+            let (old, new) = (before.walk(&key)[0].clone(), after.walk(&key)[0].clone());
+            if old != "10.0.0.5" {
+                assert_eq!(old, new);
+            } else {
+                moved += 1;
+                // Its keys go to the next node of their walk
+                assert_eq!(new, before.walk(&key)[1]);
+            }
+        }
+        assert!(moved > 400 && moved < 1_200);
+    }
+
+    #[test]
+    fn hashes_are_stable() {
+        // The first 8 bytes of SHA-256("abc")
+        assert_eq!(stable_hash("abc"), 0xba78_16bf_8f01_cfea);
+    }
+}
@@ -1,7 +1,20 @@
 mod utils;
+mod affinity;
+mod cidr_trie;
+mod config;
 mod dns_server;
+mod dnssec;
+mod forwarder;
+mod hash_ring;
+mod message;
+mod peer_sync;
+mod secure_listeners;
+mod static_zone;
+mod tsig;
+mod update;
 
 use utils::parse_arguments;
+use config::Config;
 use dns_server::DnsServer;
 
 #[tokio::main]
@@ -10,9 +23,24 @@ async fn main() {
     let matches = parse_arguments();
     let port = matches.get_one::<String>("port").unwrap();
     let cdn = matches.get_one::<String>("cdn").unwrap();
+    // Name server of the zone, defaults to ns1.<cdn>
+    let ns_name = match matches.get_one::<String>("ns") {
+        Some(ns) => ns.to_string(),
+        None => format!("ns1.{}", cdn),
+    };
+
+    // Load the configuration file, if any
+    let config = match matches.get_one::<String>("config") {
+        Some(path) => Config::load(path).unwrap_or_else(|e| panic!("Error: {}", e)),
+        None => Config::default(),
+    };
 
     // Get the DNS server running
-    let mut dns_server = DnsServer::new(&port);
+    let mut dns_server = DnsServer::new(&port, &cdn, &ns_name, config);
+    // Sign the answers when a DNSSEC key directory is given
+    if let Some(key_dir) = matches.get_one::<String>("dnssec_keys") {
+        dns_server.enable_dnssec(key_dir);
+    }
     // Start the DNS server
     dns_server.start().await;
 }
@@ -0,0 +1,565 @@
+use crate::tsig::Tsig;
+use std::net::Ipv4Addr;
+
+// Record types the server reads or writes
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_MX: u16 = 15;
+pub const TYPE_OPT: u16 = 41;
+pub const TYPE_RRSIG: u16 = 46;
+pub const TYPE_NSEC: u16 = 47;
+pub const TYPE_DNSKEY: u16 = 48;
+pub const TYPE_TSIG: u16 = 250;
+pub const TYPE_IXFR: u16 = 251;
+pub const TYPE_AXFR: u16 = 252;
+
+pub const CLASS_IN: u16 = 1;
+pub const CLASS_ANY: u16 = 255;
+
+// Opcodes
+pub const OPCODE_QUERY: u8 = 0;
+pub const OPCODE_NOTIFY: u8 = 4;
+pub const OPCODE_UPDATE: u8 = 5;
+
+// Response codes
+pub const RCODE_FORMERR: u8 = 1;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_NOTIMP: u8 = 4;
+pub const RCODE_REFUSED: u8 = 5;
+pub const RCODE_NOTAUTH: u8 = 9;
+
+// Header flag bits
+const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+
+// EDNS "DNSSEC OK" bit, stored in the TTL field of the OPT record
+const EDNS_DO: u32 = 0x8000;
+
+// Payload size we advertise in our own OPT record
+pub const EDNS_PAYLOAD_SIZE: u16 = 1232;
+// Largest message over TCP, whose length prefix is 16 bits
+pub const MAX_TCP_MESSAGE: usize = 65535;
+
+// Define the Question struct
+#[derive(Clone, Debug)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+// Define the Record struct. The rdata is always kept in uncompressed wire format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata: Vec<u8>,
+}
+
+// Define the Message struct
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+    // TSIG of the message: the one it was received with, or the one to sign it with when encoding
+    pub tsig: Option<Tsig>,
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    Truncated,
+    BadName,
+    BadPointer,
+}
+
+impl Record {
+    // This function is used to create an A record
+    pub fn a(name: &str, ttl: u32, ip: Ipv4Addr) -> Self {
+        Record::new(name, TYPE_A, ttl, ip.octets().to_vec())
+    }
+
+    // This function is used to create an NS record
+    pub fn ns(name: &str, ttl: u32, host: &str) -> Self {
+        let mut rdata = Vec::new();
+        write_name(&mut rdata, host);
+        Record::new(name, TYPE_NS, ttl, rdata)
+    }
+
+    // This function is used to create an SOA record
+    pub fn soa(name: &str, ttl: u32, mname: &str, rname: &str, serial: u32, minimum: u32) -> Self {
+        let mut rdata = Vec::new();
+        write_name(&mut rdata, mname);
+        write_name(&mut rdata, rname);
+        rdata.extend_from_slice(&serial.to_be_bytes());
+        // refresh, retry, expire
+        rdata.extend_from_slice(&3600_u32.to_be_bytes());
+        rdata.extend_from_slice(&600_u32.to_be_bytes());
+        rdata.extend_from_slice(&86400_u32.to_be_bytes());
+        rdata.extend_from_slice(&minimum.to_be_bytes());
+        Record::new(name, TYPE_SOA, ttl, rdata)
+    }
+
+    // This function is used to create a record of any type from its wire rdata
+    pub fn new(name: &str, rtype: u16, ttl: u32, rdata: Vec<u8>) -> Self {
+        Record {
+            name: name.to_string(),
+            rtype,
+            class: CLASS_IN,
+            ttl,
+            rdata,
+        }
+    }
+}
+
+impl Message {
+    // This function is used to create an empty response to the given query
+    pub fn response_to(query: &Message) -> Self {
+        Message {
+            id: query.id,
+            flags: FLAG_QR | (query.flags & (0x7800 | FLAG_RD)),
+            questions: query.questions.clone(),
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+            tsig: None,
+        }
+    }
+
+    // This function is used to create a request, such as a NOTIFY, with one question
+    pub fn request(id: u16, opcode: u8, name: &str, qtype: u16) -> Self {
+        Message {
+            id,
+            flags: (opcode as u16) << 11,
+            questions: vec![Question {
+                name: name.to_string(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+            tsig: None,
+        }
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flags & FLAG_QR != 0
+    }
+
+    pub fn opcode(&self) -> u8 {
+        ((self.flags >> 11) & 0x0f) as u8
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x000f) as u8
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.flags & FLAG_TC != 0
+    }
+
+    pub fn recursion_desired(&self) -> bool {
+        self.flags & FLAG_RD != 0
+    }
+
+    pub fn set_recursion_desired(&mut self, rd: bool) {
+        self.set_flag(FLAG_RD, rd);
+    }
+
+    pub fn set_recursion_available(&mut self, ra: bool) {
+        self.set_flag(FLAG_RA, ra);
+    }
+
+    pub fn set_rcode(&mut self, rcode: u8) {
+        self.flags = (self.flags & !0x000f) | rcode as u16;
+    }
+
+    pub fn set_authoritative(&mut self, aa: bool) {
+        self.set_flag(FLAG_AA, aa);
+    }
+
+    fn set_flag(&mut self, flag: u16, on: bool) {
+        if on {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+
+    // This function returns the EDNS OPT record of the message, if any
+    pub fn edns(&self) -> Option<&Record> {
+        self.additionals.iter().find(|rr| rr.rtype == TYPE_OPT)
+    }
+
+    // This function checks whether the client asked for DNSSEC records
+    pub fn dnssec_ok(&self) -> bool {
+        match self.edns() {
+            Some(opt) => opt.ttl & EDNS_DO != 0,
+            None => false,
+        }
+    }
+
+    // This function returns the largest UDP response the client accepts
+    pub fn udp_payload_size(&self) -> usize {
+        match self.edns() {
+            Some(opt) => (opt.class as usize).max(512),
+            None => 512,
+        }
+    }
+
+    // This function adds our OPT record to a response, echoing the DO bit of the query
+    pub fn add_edns(&mut self, dnssec_ok: bool) {
+        let ttl = if dnssec_ok { EDNS_DO } else { 0 };
+        self.additionals.push(Record {
+            name: String::new(),
+            rtype: TYPE_OPT,
+            class: EDNS_PAYLOAD_SIZE,
+            ttl,
+            rdata: vec![],
+        });
+    }
+
+    // This function is used to decode a DNS message from wire format
+    pub fn decode(buf: &[u8]) -> Result<Self, MessageError> {
+        if buf.len() < 12 {
+            return Err(MessageError::Truncated);
+        }
+        let id = read_u16(buf, 0)?;
+        let flags = read_u16(buf, 2)?;
+        let qdcount = read_u16(buf, 4)?;
+        let ancount = read_u16(buf, 6)?;
+        let nscount = read_u16(buf, 8)?;
+        let arcount = read_u16(buf, 10)?;
+
+        let mut offset = 12;
+        let mut questions = Vec::new();
+        for _ in 0..qdcount {
+            let (name, next) = read_name(buf, offset)?;
+            let qtype = read_u16(buf, next)?;
+            let qclass = read_u16(buf, next + 2)?;
+            questions.push(Question { name, qtype, qclass });
+            offset = next + 4;
+        }
+
+        let mut sections = [vec![], vec![], vec![]];
+        let mut last_record = offset;
+        for (i, count) in [ancount, nscount, arcount].iter().enumerate() {
+            for _ in 0..*count {
+                let (record, next) = read_record(buf, offset)?;
+                sections[i].push(record);
+                last_record = offset;
+                offset = next;
+            }
+        }
+        let [answers, authorities, mut additionals] = sections;
+
+        // A TSIG is always the last record; keep it apart with the bytes it signs
+        let mut tsig = None;
+        if additionals.last().map(|rr| rr.rtype) == Some(TYPE_TSIG) {
+            let record = additionals.pop().unwrap();
+            tsig = Some(Tsig::from_record(&record, buf[..last_record].to_vec()).ok_or(MessageError::Truncated)?);
+        }
+
+        Ok(Message {
+            id,
+            flags,
+            questions,
+            answers,
+            authorities,
+            additionals,
+            tsig,
+        })
+    }
+
+    // This function is used to encode the message into wire format, without name compression.
+    // A message with a TSIG to send is signed.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(512);
+        out.extend_from_slice(&self.id.to_be_bytes());
+        out.extend_from_slice(&self.flags.to_be_bytes());
+        out.extend_from_slice(&(self.questions.len() as u16).to_be_bytes());
+        out.extend_from_slice(&(self.answers.len() as u16).to_be_bytes());
+        out.extend_from_slice(&(self.authorities.len() as u16).to_be_bytes());
+        out.extend_from_slice(&(self.additionals.len() as u16).to_be_bytes());
+
+        for question in self.questions.iter() {
+            write_name(&mut out, &question.name);
+            out.extend_from_slice(&question.qtype.to_be_bytes());
+            out.extend_from_slice(&question.qclass.to_be_bytes());
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(self.authorities.iter())
+            .chain(self.additionals.iter())
+        {
+            write_record(&mut out, record);
+        }
+        if let Some(tsig) = &self.tsig {
+            tsig.sign(&mut out);
+        }
+        out
+    }
+
+    // This function encodes the message for UDP, setting TC and dropping records when it doesn't fit
+    pub fn encode_for_udp(&self, max_size: usize) -> Vec<u8> {
+        let encoded = self.encode();
+        if encoded.len() <= max_size {
+            return encoded;
+        }
+        let mut truncated = self.clone();
+        truncated.flags |= FLAG_TC;
+        truncated.answers.clear();
+        truncated.authorities.clear();
+        truncated.additionals.retain(|rr| rr.rtype == TYPE_OPT);
+        truncated.encode()
+    }
+}
+
+// This function is used to write a record in uncompressed wire format
+pub fn write_record(out: &mut Vec<u8>, record: &Record) {
+    write_name(out, &record.name);
+    out.extend_from_slice(&record.rtype.to_be_bytes());
+    out.extend_from_slice(&record.class.to_be_bytes());
+    out.extend_from_slice(&record.ttl.to_be_bytes());
+    out.extend_from_slice(&(record.rdata.len() as u16).to_be_bytes());
+    out.extend_from_slice(&record.rdata);
+}
+
+// This function is used to write a domain name in uncompressed wire format
+pub fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+// This function counts the labels of a domain name, not counting the root
+pub fn label_count(name: &str) -> usize {
+    name.split('.').filter(|label| !label.is_empty()).count()
+}
+
+// This function normalizes a domain name: lowercase and without the trailing dot
+pub fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// This function checks if the name is equal to, or below, the given zone
+pub fn in_zone(name: &str, zone: &str) -> bool {
+    let name = normalize_name(name);
+    let zone = normalize_name(zone);
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Result<u16, MessageError> {
+    match buf.get(offset..offset + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+        None => Err(MessageError::Truncated),
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Result<u32, MessageError> {
+    match buf.get(offset..offset + 4) {
+        Some(b) => Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(MessageError::Truncated),
+    }
+}
+
+// This function reads a possibly compressed name and returns it with the offset right after it
+pub fn read_name(buf: &[u8], offset: usize) -> Result<(String, usize), MessageError> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut pos = offset;
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *buf.get(pos).ok_or(MessageError::Truncated)? as usize;
+        if len == 0 {
+            if end.is_none() {
+                end = Some(pos + 1);
+            }
+            break;
+        }
+        if len & 0xc0 == 0xc0 {
+            let pointer = (read_u16(buf, pos)? & 0x3fff) as usize;
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            jumps += 1;
+            if jumps > 64 || pointer >= buf.len() {
+                return Err(MessageError::BadPointer);
+            }
+            pos = pointer;
+            continue;
+        }
+        if len > 63 {
+            return Err(MessageError::BadName);
+        }
+        let label = buf.get(pos + 1..pos + 1 + len).ok_or(MessageError::Truncated)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        pos += 1 + len;
+    }
+
+    Ok((labels.join("."), end.unwrap()))
+}
+
+// This function reads one resource record, expanding compressed names inside the rdata
+fn read_record(buf: &[u8], offset: usize) -> Result<(Record, usize), MessageError> {
+    let (name, pos) = read_name(buf, offset)?;
+    let rtype = read_u16(buf, pos)?;
+    let class = read_u16(buf, pos + 2)?;
+    let ttl = read_u32(buf, pos + 4)?;
+    let rdlength = read_u16(buf, pos + 8)? as usize;
+    let start = pos + 10;
+    let end = start + rdlength;
+    if end > buf.len() {
+        return Err(MessageError::Truncated);
+    }
+
+    let rdata = match rtype {
+        TYPE_NS | TYPE_CNAME | TYPE_PTR if rdlength > 0 => {
+            let (target, _) = read_name(buf, start)?;
+            let mut rdata = Vec::new();
+            write_name(&mut rdata, &target);
+            rdata
+        }
+        TYPE_MX if rdlength > 2 => {
+            let (target, _) = read_name(buf, start + 2)?;
+            let mut rdata = buf[start..start + 2].to_vec();
+            write_name(&mut rdata, &target);
+            rdata
+        }
+        TYPE_SOA if rdlength > 0 => {
+            let (mname, next) = read_name(buf, start)?;
+            let (rname, next) = read_name(buf, next)?;
+            let mut rdata = Vec::new();
+            write_name(&mut rdata, &mname);
+            write_name(&mut rdata, &rname);
+            rdata.extend_from_slice(buf.get(next..next + 20).ok_or(MessageError::Truncated)?);
+            rdata
+        }
+        _ => buf[start..end].to_vec(),
+    };
+
+    Ok((
+        Record {
+            name,
+            rtype,
+            class,
+            ttl,
+            rdata,
+        },
+        end,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Query of `dig example.com`: RD and AD set, and an OPT record with a 1232 byte payload and a cookie
+    const DIG_QUERY: [u8; 52] = [
+        0x5c, 0x31, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x07, b'e', b'x', b'a', b'm', b'p',
+        b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x0c, 0x00, 0x0a, 0x00, 0x08, 0x8b, 0x3e, 0x4a, 0x19, 0xd2, 0x07, 0x6c, 0xf1,
+    ];
+
+    #[test]
+    fn decodes_a_dig_query() {
+        let query = Message::decode(&DIG_QUERY).unwrap();
+        assert_eq!(query.id, 0x5c31);
+        assert!(!query.is_response());
+        assert_eq!(query.opcode(), OPCODE_QUERY);
+        assert!(query.recursion_desired());
+        assert_eq!(query.questions.len(), 1);
+        assert_eq!(query.questions[0].name, "example.com");
+        assert_eq!(query.questions[0].qtype, TYPE_A);
+        assert_eq!(query.questions[0].qclass, CLASS_IN);
+        assert!(query.edns().is_some());
+        assert!(!query.dnssec_ok());
+        assert_eq!(query.udp_payload_size(), 1232);
+        assert_eq!(query.encode(), DIG_QUERY);
+    }
+
+    #[test]
+    fn encode_and_decode_round_trip() {
+        let query = Message::decode(&DIG_QUERY).unwrap();
+        let mut response = Message::response_to(&query);
+        response.set_authoritative(true);
+        response.answers.push(Record::a("example.com", 0, Ipv4Addr::new(192, 0, 2, 1)));
+        response.authorities.push(Record::ns("example.com", 3600, "ns1.example.com"));
+        response
+            .authorities
+            .push(Record::soa("example.com", 60, "ns1.example.com", "admin.example.com", 7, 60));
+        response.add_edns(true);
+
+        let decoded = Message::decode(&response.encode()).unwrap();
+        assert_eq!(decoded.id, query.id);
+        assert!(decoded.is_response());
+        assert!(decoded.recursion_desired());
+        assert_eq!(decoded.questions[0].name, "example.com");
+        assert_eq!(decoded.answers, response.answers);
+        assert_eq!(decoded.authorities, response.authorities);
+        assert_eq!(decoded.additionals, response.additionals);
+        assert!(decoded.dnssec_ok());
+        assert_eq!(decoded.encode(), response.encode());
+    }
+
+    #[test]
+    fn expands_compressed_names() {
+        // www.example.com CNAME example.com, both compressed against the question
+        let mut buf = vec![0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+        write_name(&mut buf, "www.example.com");
+        buf.extend_from_slice(&[0x00, 0x05, 0x00, 0x01]);
+        buf.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x02, 0xc0, 0x10]);
+
+        let message = Message::decode(&buf).unwrap();
+        assert_eq!(message.answers.len(), 1);
+        assert_eq!(message.answers[0].name, "www.example.com");
+        assert_eq!(message.answers[0].ttl, 300);
+        let mut target = Vec::new();
+        write_name(&mut target, "example.com");
+        assert_eq!(message.answers[0].rdata, target);
+    }
+
+    #[test]
+    fn rejects_pointer_loops_and_short_messages() {
+        let mut buf = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        buf.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01]);
+        assert!(matches!(Message::decode(&buf), Err(MessageError::BadPointer)));
+        assert!(matches!(Message::decode(&DIG_QUERY[..20]), Err(MessageError::Truncated)));
+        assert!(matches!(Message::decode(&DIG_QUERY[..8]), Err(MessageError::Truncated)));
+    }
+
+    #[test]
+    fn truncates_answers_that_dont_fit() {
+        let query = Message::decode(&DIG_QUERY).unwrap();
+        let mut response = Message::response_to(&query);
+        for i in 0..40 {
+            response.answers.push(Record::a("example.com", 0, Ipv4Addr::new(192, 0, 2, i)));
+        }
+        response.add_edns(false);
+        let full = response.encode();
+
+        assert_eq!(response.encode_for_udp(full.len()), full);
+        let truncated = Message::decode(&response.encode_for_udp(512)).unwrap();
+        assert!(truncated.is_truncated());
+        assert!(truncated.answers.is_empty());
+        assert_eq!(truncated.questions.len(), 1);
+        assert!(truncated.edns().is_some());
+    }
+}
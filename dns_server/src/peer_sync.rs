@@ -0,0 +1,374 @@
+use crate::cidr_trie::CidrTrie;
+use crate::config::{OverrideConfig, PeersConfig};
+use crate::dns_server::{route_override, ClientGeo, RouteOverrides};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::Mutex;
+
+// Length of the HMAC-SHA256 tag in front of every message
+const TAG_LEN: usize = 32;
+// Messages older than this (in milliseconds) are rejected
+const MAX_MESSAGE_AGE: u64 = 30_000;
+// Client geolocations sent per message, so a message fits in one datagram
+const MAX_CLIENTS_PER_MESSAGE: usize = 150;
+// Every client geolocation and override is sent again this often (in milliseconds), for lost messages
+const RESYNC_INTERVAL: u64 = 600_000;
+// A peer silent for this many intervals may have missed our messages, and gets everything again
+const MISSED_INTERVALS: u64 = 3;
+// The overrides of a peer are forgotten when we haven't heard from it for this long (in milliseconds)
+const PEER_OVERRIDE_EXPIRY: u64 = 600_000;
+
+// Define the ReplicaState struct: the last probe result of one HTTP server
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReplicaState {
+    // When the server was probed, in milliseconds since the epoch
+    pub updated_at: u64,
+    pub available: bool,
+    pub cpu_usage: f32,
+    pub throughput: Option<(f64, f64)>,
+}
+
+// Define the PeerMessage struct, sent to every peer on each sync
+#[derive(Serialize, Deserialize)]
+struct PeerMessage {
+    node_id: String,
+    // When the sender started, so a restarted peer is sent everything again
+    started_at: u64,
+    sent_at: u64,
+    replicas: HashMap<String, ReplicaState>,
+    // Client geolocations the peer hasn't been sent yet
+    clients: HashMap<String, ClientGeo>,
+    // Our overrides, when the peer hasn't been sent them yet
+    overrides: Option<Vec<OverrideConfig>>,
+    // Digest of the pools, policies and views, to detect peers with a different routing config
+    routing_digest: String,
+}
+
+// Define the PeerRouting struct: our routing config, and the override table the peers' overrides go into
+pub struct PeerRouting {
+    // Digest of the pools, policies and views
+    pub digest: String,
+    // Our own overrides, sent to the peers. They win over a peer's override of the same network.
+    pub overrides: Vec<OverrideConfig>,
+    pub pools: HashMap<String, Vec<String>>,
+    // Override table used to answer, rebuilt when the overrides of a peer change
+    pub route_overrides: Arc<Mutex<Arc<RouteOverrides>>>,
+}
+
+// Define the SentState struct: what one peer has been sent since its last full resync
+struct SentState {
+    clients: HashSet<String>,
+    overrides: bool,
+    since: u64,
+}
+
+// Define the PeerSync struct: shares the state a DNS server learns with its peers
+pub struct PeerSync {
+    config: PeersConfig,
+    key: hmac::Key,
+    started_at: u64,
+    routing: PeerRouting,
+    availability: Arc<Mutex<HashMap<String, bool>>>,
+    cpu_usage: Arc<Mutex<HashMap<String, f32>>>,
+    throughput: Arc<Mutex<HashMap<String, (f64, f64)>>>,
+    health_updated: Arc<Mutex<HashMap<String, u64>>>,
+    client_distance_cache: Arc<Mutex<HashMap<String, ClientGeo>>>,
+    // Overrides of each peer, by node ID, with when we last heard from the peer
+    peer_overrides: Mutex<BTreeMap<String, (u64, Vec<OverrideConfig>)>>,
+    // Peers that appeared, restarted or came back, to send everything again
+    resync: Mutex<HashSet<SocketAddr>>,
+}
+
+impl SentState {
+    // This function is used to start over: everything is sent on the next sync
+    fn new() -> Self {
+        SentState {
+            clients: HashSet::new(),
+            overrides: false,
+            since: now_millis(),
+        }
+    }
+}
+
+impl PeerSync {
+    // This function is used to create the peer sync from the state shared with the DNS server
+    pub fn new(
+        config: PeersConfig,
+        routing: PeerRouting,
+        availability: Arc<Mutex<HashMap<String, bool>>>,
+        cpu_usage: Arc<Mutex<HashMap<String, f32>>>,
+        throughput: Arc<Mutex<HashMap<String, (f64, f64)>>>,
+        health_updated: Arc<Mutex<HashMap<String, u64>>>,
+        client_distance_cache: Arc<Mutex<HashMap<String, ClientGeo>>>,
+    ) -> Self {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, config.secret.as_bytes());
+        PeerSync {
+            config,
+            key,
+            started_at: now_millis(),
+            routing,
+            availability,
+            cpu_usage,
+            throughput,
+            health_updated,
+            client_distance_cache,
+            peer_overrides: Mutex::new(BTreeMap::new()),
+            resync: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // This function runs the sync: it sends our state to the peers every interval and merges what they send.
+    // When peers are unreachable, the server simply keeps its own state.
+    pub async fn run(self) {
+        let socket = Arc::new(UdpSocket::bind(&self.config.listen).await.unwrap());
+        let sync = Arc::new(self);
+
+        // Resolve the peer addresses once, to know which peer a message comes from
+        let mut peers = Vec::new();
+        for address in sync.config.addresses.iter() {
+            let resolved = lookup_host(address.as_str()).await.ok().and_then(|mut found| found.next());
+            peers.push((address.clone(), resolved, SentState::new()));
+        }
+
+        let receiver = Arc::clone(&sync);
+        let receive_socket = Arc::clone(&socket);
+        tokio::spawn(async move {
+            // Node ID -> send and start times of its last message
+            let mut last_seen: HashMap<String, (u64, u64)> = HashMap::new();
+            let mut warned_digest = false;
+            let mut buf = vec![0; 65535];
+            let missed_gap = MISSED_INTERVALS * receiver.config.interval_secs * 1000;
+            loop {
+                let (amt, src) = match receive_socket.recv_from(&mut buf).await {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                };
+                let message = match receiver.open(&buf[..amt]) {
+                    Some(message) => message,
+                    None => {
+                        dbg!(format!("Error: rejected peer message from {}", src));
+                        continue;
+                    }
+                };
+                // Reject replayed or reordered messages
+                let previous = last_seen.get(&message.node_id).copied();
+                if message.sent_at <= previous.map_or(0, |(sent_at, _)| sent_at) {
+                    continue;
+                }
+                // A peer we didn't hear from, that restarted or that was silent for a while may have missed our messages
+                let missed = match previous {
+                    Some((sent_at, started_at)) => {
+                        started_at != message.started_at || message.sent_at - sent_at > missed_gap
+                    }
+                    None => true,
+                };
+                if missed {
+                    receiver.resync.lock().await.insert(src);
+                }
+                last_seen.insert(message.node_id.clone(), (message.sent_at, message.started_at));
+                if message.routing_digest != receiver.routing.digest && !warned_digest {
+                    dbg!(format!(
+                        "Warning: peer {} has different pools, policies or views, ignoring its client geolocations",
+                        message.node_id
+                    ));
+                    warned_digest = true;
+                }
+                receiver.merge(message).await;
+            }
+        });
+
+        loop {
+            let resync = std::mem::take(&mut *sync.resync.lock().await);
+            for (address, resolved, sent) in peers.iter_mut() {
+                let missed = resolved.is_some_and(|resolved| resync.contains(&resolved));
+                if missed || now_millis() - sent.since > RESYNC_INTERVAL {
+                    *sent = SentState::new();
+                }
+                let sealed = sync.seal(&sync.snapshot(sent).await);
+                // A peer that is down just misses this round, and gets everything again once it's back
+                if socket.send_to(&sealed, address.as_str()).await.is_err() {
+                    *sent = SentState::new();
+                }
+            }
+            sync.expire_overrides().await;
+
+            tokio::time::sleep(tokio::time::Duration::from_secs(sync.config.interval_secs)).await;
+        }
+    }
+
+    // This function builds the message describing our current state
+    async fn snapshot(&self, sent: &mut SentState) -> PeerMessage {
+        let availability = self.availability.lock().await.clone();
+        let cpu_usage = self.cpu_usage.lock().await.clone();
+        let throughput = self.throughput.lock().await.clone();
+        let health_updated = self.health_updated.lock().await.clone();
+
+        let mut replicas = HashMap::new();
+        for (ip, updated_at) in health_updated.iter() {
+            replicas.insert(
+                ip.clone(),
+                ReplicaState {
+                    updated_at: *updated_at,
+                    available: *availability.get(ip).unwrap_or(&false),
+                    cpu_usage: *cpu_usage.get(ip).unwrap_or(&0_f32),
+                    throughput: throughput.get(ip).cloned(),
+                },
+            );
+        }
+
+        let mut clients = HashMap::new();
+        let d_cache = self.client_distance_cache.lock().await;
+        for (client, geo) in d_cache.iter() {
+            if clients.len() >= MAX_CLIENTS_PER_MESSAGE {
+                break;
+            }
+            if sent.clients.insert(client.clone()) {
+                clients.insert(client.clone(), geo.clone());
+            }
+        }
+        drop(d_cache);
+
+        let overrides = match sent.overrides {
+            true => None,
+            false => Some(self.routing.overrides.clone()),
+        };
+        sent.overrides = true;
+
+        PeerMessage {
+            node_id: self.config.node_id.clone().unwrap_or(self.config.listen.clone()),
+            started_at: self.started_at,
+            sent_at: now_millis(),
+            replicas,
+            clients,
+            overrides,
+            routing_digest: self.routing.digest.clone(),
+        }
+    }
+
+    // This function merges a peer's state: the freshest probe of each server wins, its overrides replace the
+    // ones it sent before, and client geolocations we don't know yet are added to the cache. Pools, policies
+    // and views aren't exchanged, so the geolocations of a peer with a different routing config are left out.
+    async fn merge(&self, message: PeerMessage) {
+        let same_routing = message.routing_digest == self.routing.digest;
+        let mut health_updated = self.health_updated.lock().await;
+        let mut availability = self.availability.lock().await;
+        let mut cpu_usage = self.cpu_usage.lock().await;
+        let mut throughput = self.throughput.lock().await;
+        for (ip, state) in message.replicas.into_iter() {
+            // Only servers we know about
+            if !availability.contains_key(&ip) {
+                continue;
+            }
+            if state.updated_at <= *health_updated.get(&ip).unwrap_or(&0) {
+                continue;
+            }
+            health_updated.insert(ip.clone(), state.updated_at);
+            availability.insert(ip.clone(), state.available);
+            cpu_usage.insert(ip.clone(), state.cpu_usage);
+            if let Some(rates) = state.throughput {
+                throughput.insert(ip, rates);
+            }
+        }
+        drop(throughput);
+        drop(cpu_usage);
+        drop(availability);
+        drop(health_updated);
+
+        let mut peer_overrides = self.peer_overrides.lock().await;
+        let changed = match message.overrides {
+            Some(overrides) => {
+                let previous = peer_overrides.insert(message.node_id.clone(), (now_millis(), overrides.clone()));
+                previous.map(|(_, previous)| previous) != Some(overrides)
+            }
+            None => {
+                if let Some((heard_at, _)) = peer_overrides.get_mut(&message.node_id) {
+                    *heard_at = now_millis();
+                }
+                false
+            }
+        };
+        drop(peer_overrides);
+        if changed {
+            self.rebuild_overrides().await;
+        }
+
+        if !same_routing {
+            return;
+        }
+        let mut d_cache = self.client_distance_cache.lock().await;
+        for (client, geo) in message.clients.into_iter() {
+            d_cache.entry(client).or_insert(geo);
+        }
+        drop(d_cache);
+    }
+
+    // This function forgets the overrides of the peers we haven't heard from for a while
+    async fn expire_overrides(&self) {
+        let mut peer_overrides = self.peer_overrides.lock().await;
+        let count = peer_overrides.len();
+        peer_overrides.retain(|_, (heard_at, _)| now_millis() - *heard_at < PEER_OVERRIDE_EXPIRY);
+        let expired = peer_overrides.len() != count;
+        drop(peer_overrides);
+        if expired {
+            self.rebuild_overrides().await;
+        }
+    }
+
+    // This function rebuilds the override table from the overrides of the peers (by node ID order), then ours,
+    // so ours win for the same network. Peer overrides naming a replica or a pool we don't have are skipped.
+    async fn rebuild_overrides(&self) {
+        let replicas = self.availability.lock().await.clone();
+        let mut table = CidrTrie::new();
+        let peer_overrides = self.peer_overrides.lock().await;
+        for (node_id, (_, entries)) in peer_overrides.iter() {
+            for entry in entries.iter() {
+                let inserted = route_override(entry, &self.routing.pools, &replicas)
+                    .and_then(|route| table.insert(&entry.cidr, (entry.cidr.clone(), route)));
+                if let Err(e) = inserted {
+                    dbg!(format!("Warning: skipped an override of peer {}: {}", node_id, e));
+                }
+            }
+        }
+        drop(peer_overrides);
+        for entry in self.routing.overrides.iter() {
+            // Ours were checked at startup
+            if let Ok(route) = route_override(entry, &self.routing.pools, &replicas) {
+                let _ = table.insert(&entry.cidr, (entry.cidr.clone(), route));
+            }
+        }
+        *self.routing.route_overrides.lock().await = Arc::new(table);
+    }
+
+    // This function serializes a message and puts its HMAC in front
+    fn seal(&self, message: &PeerMessage) -> Vec<u8> {
+        let payload = serde_json::to_vec(message).unwrap();
+        let tag = hmac::sign(&self.key, &payload);
+        let mut sealed = tag.as_ref().to_vec();
+        sealed.extend_from_slice(&payload);
+        sealed
+    }
+
+    // This function checks the HMAC and the age of a message, and parses it
+    fn open(&self, sealed: &[u8]) -> Option<PeerMessage> {
+        if sealed.len() < TAG_LEN {
+            return None;
+        }
+        let (tag, payload) = sealed.split_at(TAG_LEN);
+        hmac::verify(&self.key, payload, tag).ok()?;
+        let message: PeerMessage = serde_json::from_slice(payload).ok()?;
+        if message.sent_at.abs_diff(now_millis()) > MAX_MESSAGE_AGE {
+            return None;
+        }
+        Some(message)
+    }
+}
+
+// This function returns the current time in milliseconds since the epoch
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
@@ -0,0 +1,330 @@
+use crate::config::TlsConfig;
+use crate::dns_server::DnsServer;
+use crate::message::{Message, MAX_TCP_MESSAGE, TYPE_AXFR, TYPE_IXFR};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rustls::ServerConfig;
+use std::fs::File;
+use std::io::BufReader as StdBufReader;
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::time::{timeout, Duration};
+use tokio_rustls::TlsAcceptor;
+
+// Idle connections are closed after this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+// Largest DNS message we accept over HTTP
+const MAX_DOH_BODY: usize = 65535;
+// Largest request line and headers we accept over HTTP, and most header lines
+const MAX_HEADER: usize = 8192;
+const MAX_HEADER_LINES: usize = 64;
+
+// Define the DohRequest struct: what we need from an HTTP request
+struct DohRequest {
+    method: String,
+    target: String,
+    content_type: String,
+    body: Vec<u8>,
+    close: bool,
+}
+
+// This function is used to build the TLS acceptor from the certificate and key in the config
+pub fn tls_acceptor(tls: &TlsConfig, alpn: &[u8]) -> Result<TlsAcceptor, String> {
+    let mut cert_reader = StdBufReader::new(
+        File::open(&tls.cert).map_err(|e| format!("can't open {}: {}", tls.cert, e))?,
+    );
+    let certs = rustls_pemfile::certs(&mut cert_reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("can't parse {}: {}", tls.cert, e))?;
+
+    let mut key_reader = StdBufReader::new(
+        File::open(&tls.key).map_err(|e| format!("can't open {}: {}", tls.key, e))?,
+    );
+    let key = rustls_pemfile::private_key(&mut key_reader)
+        .map_err(|e| format!("can't parse {}: {}", tls.key, e))?
+        .ok_or(format!("no private key found in {}", tls.key))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|e| e.to_string())?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .map_err(|e| e.to_string())?;
+    config.alpn_protocols = vec![alpn.to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// This function accepts plain DNS over TCP connections on the given port
+pub async fn serve_tcp(server: DnsServer, port: u16) {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await.unwrap();
+    loop {
+        let (stream, client_address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(_) => continue,
+        };
+        let cloned = server.clone();
+
+        // Spawn worker thread to serve the connection
+        tokio::spawn(serve_length_prefixed(cloned, stream, client_address.to_string()));
+    }
+}
+
+// This function accepts DNS-over-TLS connections (RFC 7858) on the given port
+pub async fn serve_dot(server: DnsServer, acceptor: TlsAcceptor, port: u16) {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await.unwrap();
+    loop {
+        let (stream, client_address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(_) => continue,
+        };
+        let acceptor = acceptor.clone();
+        let cloned = server.clone();
+
+        // Spawn worker thread to serve the connection
+        tokio::spawn(async move {
+            match timeout(IDLE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(tls_stream)) => {
+                    serve_length_prefixed(cloned, tls_stream, client_address.to_string()).await
+                }
+                _ => {
+                    dbg!(format!("Error: TLS handshake with {} failed", client_address));
+                }
+            }
+        });
+    }
+}
+
+// This function accepts DNS-over-HTTPS connections (RFC 8484) on the given port
+pub async fn serve_doh(server: DnsServer, acceptor: TlsAcceptor, port: u16) {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await.unwrap();
+    loop {
+        let (stream, client_address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(_) => continue,
+        };
+        let acceptor = acceptor.clone();
+        let cloned = server.clone();
+
+        // Spawn worker thread to serve the connection
+        tokio::spawn(async move {
+            match timeout(IDLE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(tls_stream)) => {
+                    serve_http(cloned, tls_stream, client_address.to_string()).await
+                }
+                _ => {
+                    dbg!(format!("Error: TLS handshake with {} failed", client_address));
+                }
+            }
+        });
+    }
+}
+
+// This function serves DNS messages framed with a two byte length, as used over TCP and TLS
+pub async fn serve_length_prefixed<S>(mut server: DnsServer, mut stream: S, client_address: String)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let len = match timeout(IDLE_TIMEOUT, stream.read_u16()).await {
+            Ok(Ok(len)) => len as usize,
+            _ => break,
+        };
+        let mut buf = vec![0; len];
+        match timeout(IDLE_TIMEOUT, stream.read_exact(&mut buf)).await {
+            Ok(Ok(_)) => {}
+            _ => break,
+        }
+        let query = match Message::decode(&buf) {
+            Ok(query) if !query.is_response() => query,
+            _ => break,
+        };
+
+        // Zone transfers are answered with a stream of messages
+        let answers = match query.questions.first() {
+            Some(question) if question.qtype == TYPE_AXFR || question.qtype == TYPE_IXFR => {
+                server.transfer(&client_address, &query).await
+            }
+            // An answer too large for the length prefix is truncated, like over UDP
+            _ => vec![server.handle_query(&client_address, &query).await.encode_for_udp(MAX_TCP_MESSAGE)],
+        };
+        let mut framed = Vec::new();
+        for ans in answers.iter() {
+            let len = match u16::try_from(ans.len()) {
+                Ok(len) => len,
+                Err(_) => {
+                    dbg!(format!("Error: answer of {} bytes to {} doesn't fit TCP", ans.len(), client_address));
+                    return;
+                }
+            };
+            framed.extend_from_slice(&len.to_be_bytes());
+            framed.extend_from_slice(ans);
+        }
+        if stream.write_all(&framed).await.is_err() || stream.flush().await.is_err() {
+            break;
+        }
+    }
+}
+
+// This function serves HTTP/1.1 requests on /dns-query, with GET (?dns=) and POST bodies
+async fn serve_http<S>(mut server: DnsServer, stream: S, client_address: String)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut reader = BufReader::new(stream);
+    loop {
+        // The whole request must arrive in time, so slow clients can't hold the connection
+        let request = match timeout(IDLE_TIMEOUT, read_http_request(&mut reader)).await {
+            Ok(Ok(request)) => request,
+            Ok(Err(Some(status))) => {
+                let _ = write_http(&mut reader, status, "text/plain", b"", 0, true).await;
+                break;
+            }
+            _ => break,
+        };
+        let DohRequest { method, target, content_type, body, close } = request;
+
+        // Get the DNS query out of the request
+        let (path, query_string) = match target.split_once('?') {
+            Some((path, query_string)) => (path, query_string),
+            None => (target.as_str(), ""),
+        };
+        let wire = if path != "/dns-query" {
+            Err("404 Not Found")
+        } else if method == "GET" {
+            query_string
+                .split('&')
+                .find_map(|param| param.strip_prefix("dns="))
+                .and_then(|encoded| URL_SAFE_NO_PAD.decode(encoded.trim_end_matches('=')).ok())
+                .ok_or("400 Bad Request")
+        } else if method == "POST" {
+            if content_type == "application/dns-message" {
+                Ok(body)
+            } else {
+                Err("415 Unsupported Media Type")
+            }
+        } else {
+            Err("405 Method Not Allowed")
+        };
+
+        let result = match wire.map(|wire| Message::decode(&wire)) {
+            Ok(Ok(query)) if !query.is_response() => {
+                let response = server.handle_query(&client_address, &query).await;
+                // The response may be cached for as long as its shortest TTL
+                let max_age = response
+                    .answers
+                    .iter()
+                    .chain(response.authorities.iter())
+                    .map(|rr| rr.ttl)
+                    .min()
+                    .unwrap_or(0);
+                let ans = response.encode();
+                write_http(&mut reader, "200 OK", "application/dns-message", &ans, max_age, close).await
+            }
+            Ok(_) => write_http(&mut reader, "400 Bad Request", "text/plain", b"", 0, close).await,
+            Err(status) => write_http(&mut reader, status, "text/plain", b"", 0, close).await,
+        };
+        if result.is_err() || close {
+            break;
+        }
+    }
+}
+
+// This function reads one HTTP/1.1 request: the request line, the headers we care about and the body.
+// The request line and headers are read through a MAX_HEADER byte budget. Err holds the status to answer
+// with before closing the connection, or None when it's closed without an answer.
+async fn read_http_request<R>(reader: &mut R) -> Result<DohRequest, Option<&'static str>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut head = (&mut *reader).take(MAX_HEADER as u64);
+
+    // Read the request line
+    let mut request_line = String::new();
+    match head.read_line(&mut request_line).await {
+        Ok(n) if n > 0 => {}
+        _ => return Err(None),
+    }
+    if !request_line.ends_with('\n') {
+        return Err(Some("431 Request Header Fields Too Large"));
+    }
+    let parts: Vec<&str> = request_line.split_whitespace().collect();
+    if parts.len() != 3 {
+        return Err(Some("400 Bad Request"));
+    }
+    let mut request = DohRequest {
+        method: parts[0].to_string(),
+        target: parts[1].to_string(),
+        content_type: String::new(),
+        body: Vec::new(),
+        close: parts[2] == "HTTP/1.0",
+    };
+
+    // Read the headers we care about
+    let mut content_length = 0;
+    let mut lines = 0;
+    loop {
+        let mut line = String::new();
+        match head.read_line(&mut line).await {
+            Ok(n) if n > 0 => {}
+            _ => return Err(None),
+        }
+        lines += 1;
+        if !line.ends_with('\n') || lines > MAX_HEADER_LINES {
+            return Err(Some("431 Request Header Fields Too Large"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse().unwrap_or(usize::MAX),
+                "content-type" => request.content_type = value.to_ascii_lowercase(),
+                "connection" => request.close = value.eq_ignore_ascii_case("close"),
+                _ => {}
+            }
+        }
+    }
+    if content_length > MAX_DOH_BODY {
+        return Err(Some("413 Payload Too Large"));
+    }
+    request.body = vec![0; content_length];
+    if reader.read_exact(&mut request.body).await.is_err() {
+        return Err(None);
+    }
+    Ok(request)
+}
+
+// This function writes one HTTP/1.1 response
+async fn write_http<S>(
+    stream: &mut S,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+    max_age: u32,
+    close: bool,
+) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: max-age={}\r\n",
+        status,
+        content_type,
+        body.len(),
+        max_age
+    );
+    if close {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await
+}
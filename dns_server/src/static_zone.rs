@@ -0,0 +1,699 @@
+use crate::message::{in_zone, normalize_name, read_name, write_name, Record, TYPE_A, TYPE_CNAME, TYPE_MX, TYPE_NS, TYPE_PTR, TYPE_SOA};
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+// Record types only written from zone files
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+
+// Number of zone changes kept to answer IXFR
+const MAX_HISTORY: usize = 20;
+// TTL of records without one, when the zone file has no $TTL
+const DEFAULT_TTL: u32 = 3600;
+
+// Define the ZoneChange struct: the difference between two versions of the zone, as sent in IXFR
+#[derive(Clone)]
+pub struct ZoneChange {
+    pub old_soa: Record,
+    pub removed: Vec<Record>,
+    pub new_soa: Record,
+    pub added: Vec<Record>,
+}
+
+// Define the StaticZone struct: the records of the zone loaded from a zone file, served
+// next to the dynamic CDN answers and transferred to the secondaries
+#[derive(Clone)]
+pub struct StaticZone {
+    zone: String,
+    // Zone file with the static records
+    zone_file: Option<String>,
+    // Journal of the dynamic updates, applied on top of the zone file
+    journal: Option<String>,
+    serial: u32,
+    // SOA of the zone; its serial is replaced by `serial` when served
+    soa: Record,
+    // Serial written in the zone file, if it has an SOA
+    file_serial: Option<u32>,
+    // Every record but the SOA, sorted
+    records: Vec<Record>,
+    // Latest changes, oldest first
+    history: VecDeque<ZoneChange>,
+}
+
+impl StaticZone {
+    // This function is used to create a zone without static records, served with the given SOA
+    pub fn new(zone: &str, soa: Record, zone_file: Option<String>, journal: Option<String>) -> Self {
+        StaticZone {
+            zone: normalize_name(zone),
+            zone_file,
+            journal,
+            serial: soa_serial(&soa).unwrap_or(0),
+            soa,
+            file_serial: None,
+            records: vec![],
+            history: VecDeque::new(),
+        }
+    }
+
+    // This function loads the zone file and the journal at startup. The SOA of the file, if any, replaces this one,
+    // and the last serial of the journal is used when it's greater.
+    pub fn load(&self) -> Result<StaticZone, String> {
+        let (soa, records, journal_serial) = self.read()?;
+        let mut loaded = self.clone();
+        loaded.file_serial = soa.as_ref().and_then(soa_serial);
+        loaded.serial = loaded.file_serial.unwrap_or(self.serial);
+        if let Some(serial) = journal_serial {
+            if serial_greater(serial, loaded.serial) {
+                loaded.serial = serial;
+            }
+        }
+        loaded.soa = soa.unwrap_or(self.soa.clone());
+        loaded.records = records;
+        Ok(loaded)
+    }
+
+    // This function reloads the zone file, and returns the new version of the zone when it changed.
+    // The serial of the file is used when it was increased, otherwise ours is bumped.
+    pub fn reload(&self) -> Result<Option<StaticZone>, String> {
+        let (soa, records, _) = self.read()?;
+        let file_serial = soa.as_ref().and_then(soa_serial);
+        let soa = soa.unwrap_or(self.soa.clone());
+        if records == self.records && same_soa(&soa, &self.soa) && file_serial == self.file_serial {
+            return Ok(None);
+        }
+
+        let serial = match file_serial {
+            Some(serial) if serial_greater(serial, self.serial) => serial,
+            Some(serial) => {
+                dbg!(format!(
+                    "Warning: zone file serial {} wasn't increased, serving {}",
+                    serial,
+                    self.serial.wrapping_add(1)
+                ));
+                self.serial.wrapping_add(1)
+            }
+            None => self.serial.wrapping_add(1),
+        };
+
+        let mut reloaded = self.clone();
+        reloaded.serial = serial;
+        reloaded.soa = soa;
+        reloaded.file_serial = file_serial;
+        reloaded.records = records;
+        reloaded.add_history(self);
+        Ok(Some(reloaded))
+    }
+
+    // This function applies a dynamic update: the change is written to the journal first,
+    // then the new version of the zone is returned with the next serial.
+    pub fn commit(&self, removed: &[Record], added: &[Record]) -> Result<StaticZone, String> {
+        let path = self.journal.as_ref().ok_or("no journal is configured".to_string())?;
+        let serial = self.serial.wrapping_add(1);
+
+        let mut entry = format!("serial {}\n", serial);
+        for record in removed.iter() {
+            entry.push_str(&format!("del {}\n", generic_record(record)));
+        }
+        for record in added.iter() {
+            entry.push_str(&format!("add {}\n", generic_record(record)));
+        }
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("can't open {}: {}", path, e))?;
+        journal
+            .write_all(entry.as_bytes())
+            .and_then(|_| journal.sync_data())
+            .map_err(|e| format!("can't write {}: {}", path, e))?;
+
+        let mut updated = self.clone();
+        updated.serial = serial;
+        updated.records = apply_changes(self.records.clone(), removed, added);
+        updated.add_history(self);
+        Ok(updated)
+    }
+
+    // This function records the change from the previous version of the zone, for IXFR
+    fn add_history(&mut self, previous: &StaticZone) {
+        self.history.push_back(ZoneChange {
+            old_soa: previous.soa(),
+            removed: previous.records.iter().filter(|rr| !self.records.contains(rr)).cloned().collect(),
+            new_soa: self.soa(),
+            added: self.records.iter().filter(|rr| !previous.records.contains(rr)).cloned().collect(),
+        });
+        if self.history.len() > MAX_HISTORY {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    // This function returns the SOA record with the current serial
+    pub fn soa(&self) -> Record {
+        let mut soa = self.soa.clone();
+        if let Some(at) = soa.rdata.len().checked_sub(20) {
+            soa.rdata[at..at + 4].copy_from_slice(&self.serial.to_be_bytes());
+        }
+        soa
+    }
+
+    // This function returns every static record but the SOA
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    // This function returns the static records of a name
+    pub fn lookup(&self, name: &str) -> Vec<Record> {
+        let name = normalize_name(name);
+        self.records.iter().filter(|rr| rr.name == name).cloned().collect()
+    }
+
+    // This function checks whether a name has static records, or static records below it
+    pub fn has_name(&self, name: &str) -> bool {
+        let name = normalize_name(name);
+        let suffix = format!(".{}", name);
+        self.records.iter().any(|rr| rr.name == name || rr.name.ends_with(&suffix))
+    }
+
+    // This function returns the changes from the given serial to the current one, if we still have them all
+    pub fn changes_since(&self, serial: u32) -> Option<Vec<ZoneChange>> {
+        let start = self
+            .history
+            .iter()
+            .position(|change| soa_serial(&change.old_soa) == Some(serial))?;
+        Some(self.history.iter().skip(start).cloned().collect())
+    }
+
+    // This function reads the zone file and replays the journal on it. It returns the SOA of the file (if any),
+    // the other records and the last serial of the journal.
+    fn read(&self) -> Result<(Option<Record>, Vec<Record>, Option<u32>), String> {
+        let (soa, records) = match &self.zone_file {
+            Some(path) => self.read_file(path)?,
+            None => (None, vec![]),
+        };
+        let path = match &self.journal {
+            Some(path) => path,
+            None => return Ok((soa, records, None)),
+        };
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("can't read {}: {}", path, e)),
+        };
+
+        // One "serial N" line per update, followed by its "del <record>" and "add <record>" lines, replayed in order
+        let mut records = records;
+        let mut serial = None;
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| format!("{} line {}: {}", path, i + 1, message);
+            match line.trim().split_once(' ') {
+                Some(("serial", value)) => {
+                    serial = Some(value.trim().parse::<u32>().map_err(|_| error("bad serial".to_string()))?);
+                }
+                Some(("del", record)) => {
+                    let removed = parse_zone_file(record, &self.zone).map_err(error)?;
+                    records = apply_changes(records, &removed, &[]);
+                }
+                Some(("add", record)) => {
+                    let added = parse_zone_file(record, &self.zone).map_err(error)?;
+                    records = apply_changes(records, &[], &added);
+                }
+                _ if line.trim().is_empty() => {}
+                _ => return Err(error("expected serial, del or add".to_string())),
+            }
+        }
+        Ok((soa, records, serial))
+    }
+
+    // This function reads the zone file, and returns its SOA (if any) and its other records
+    fn read_file(&self, path: &str) -> Result<(Option<Record>, Vec<Record>), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+        let mut soa = None;
+        let mut records = Vec::new();
+        for record in parse_zone_file(&text, &self.zone).map_err(|e| format!("{}: {}", path, e))? {
+            if record.rtype == TYPE_SOA {
+                if record.name != self.zone {
+                    return Err(format!("{}: the SOA record must be at {}", path, self.zone));
+                }
+                if !valid_soa_rdata(&record.rdata) {
+                    return Err(format!("{}: the SOA record must hold two names and 20 bytes of numbers", path));
+                }
+                soa = Some(record);
+            } else if record.name == self.zone && record.rtype == TYPE_A {
+                // The A record of the apex is always the CDN answer
+                dbg!(format!("Warning: ignoring the static A record of {}", self.zone));
+            } else {
+                records.push(record);
+            }
+        }
+        records.sort_by(|a, b| (&a.name, a.rtype, &a.rdata).cmp(&(&b.name, b.rtype, &b.rdata)));
+        records.dedup();
+        Ok((soa, records))
+    }
+}
+
+// This function reads the serial of an SOA record
+pub fn soa_serial(soa: &Record) -> Option<u32> {
+    let at = soa.rdata.len().checked_sub(20)?;
+    let b = &soa.rdata[at..at + 4];
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+// This function compares two serials with serial number arithmetic (RFC 1982)
+pub fn serial_greater(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000_0000
+}
+
+// This function checks the rdata of an SOA record: the MNAME and RNAME, then exactly 20 bytes
+// (serial, refresh, retry, expire and minimum)
+fn valid_soa_rdata(rdata: &[u8]) -> bool {
+    let names_end = read_name(rdata, 0).and_then(|(_, pos)| read_name(rdata, pos));
+    matches!(names_end, Ok((_, end)) if rdata.len().checked_sub(20) == Some(end))
+}
+
+// This function checks whether two SOA records are the same but for their serial
+fn same_soa(a: &Record, b: &Record) -> bool {
+    let len = a.rdata.len();
+    let at = match len.checked_sub(20) {
+        Some(at) => at,
+        None => return a == b,
+    };
+    a.ttl == b.ttl
+        && len == b.rdata.len()
+        && a.rdata[..at] == b.rdata[..at]
+        && a.rdata[at + 4..] == b.rdata[at + 4..]
+}
+
+// This function removes and adds records. Removed records are matched on name, type and rdata.
+fn apply_changes(mut records: Vec<Record>, removed: &[Record], added: &[Record]) -> Vec<Record> {
+    records.retain(|rr| {
+        !removed
+            .iter()
+            .any(|del| del.name == rr.name && del.rtype == rr.rtype && del.rdata == rr.rdata)
+    });
+    for record in added.iter() {
+        if !records.contains(record) {
+            records.push(record.clone());
+        }
+    }
+    records.sort_by(|a, b| (&a.name, a.rtype, &a.rdata).cmp(&(&b.name, b.rtype, &b.rdata)));
+    records
+}
+
+// This function writes a record as a zone file line, with the generic rdata syntax of RFC 3597
+fn generic_record(record: &Record) -> String {
+    let hex: String = record.rdata.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}. {} TYPE{} \\# {} {}", record.name, record.ttl, record.rtype, record.rdata.len(), hex)
+}
+
+// This function parses a zone file (RFC 1035 master file format) with the usual record types:
+// SOA, NS, A, AAAA, CNAME, MX, TXT, SRV and PTR, and any type in the generic syntax of RFC 3597.
+// Every record must be in the zone.
+pub fn parse_zone_file(text: &str, zone: &str) -> Result<Vec<Record>, String> {
+    let mut origin = normalize_name(zone);
+    let mut default_ttl = DEFAULT_TTL;
+    let mut owner = origin.clone();
+    let mut records = Vec::new();
+
+    for (line_number, starts_blank, tokens) in logical_lines(text)? {
+        let error = |message: String| format!("line {}: {}", line_number, message);
+        let mut tokens = tokens.into_iter().peekable();
+
+        // Directives
+        match tokens.peek().map(|token| token.as_str()) {
+            Some("$ORIGIN") => {
+                tokens.next();
+                let name = tokens.next().ok_or(error("$ORIGIN needs a name".to_string()))?;
+                origin = absolute_name(&name, &origin);
+                continue;
+            }
+            Some("$TTL") => {
+                tokens.next();
+                let ttl = tokens.next().ok_or(error("$TTL needs a value".to_string()))?;
+                default_ttl = parse_ttl(&ttl).ok_or(error(format!("{} isn't a valid TTL", ttl)))?;
+                continue;
+            }
+            Some(directive) if directive.starts_with('$') => {
+                return Err(error(format!("{} isn't supported", directive)));
+            }
+            _ => {}
+        }
+
+        // Owner, which is the previous one when the line starts with a blank
+        if !starts_blank {
+            owner = absolute_name(&tokens.next().unwrap(), &origin);
+        }
+        if !in_zone(&owner, zone) {
+            return Err(error(format!("{} isn't in the zone {}", owner, zone)));
+        }
+
+        // Optional TTL and class, in any order, then the type
+        let mut ttl = default_ttl;
+        let rtype = loop {
+            let token = tokens.next().ok_or(error("missing record type".to_string()))?;
+            let upper = token.to_ascii_uppercase();
+            if upper == "IN" {
+                continue;
+            }
+            if let Some(value) = parse_ttl(&token) {
+                ttl = value;
+                continue;
+            }
+            break upper;
+        };
+
+        let fields: Vec<String> = tokens.collect();
+        let (rtype, rdata) = parse_rdata(&rtype, &fields, &origin).map_err(error)?;
+        records.push(Record::new(&owner, rtype, ttl, rdata));
+    }
+    Ok(records)
+}
+
+// This function parses the rdata of one record into wire format
+fn parse_rdata(rtype: &str, fields: &[String], origin: &str) -> Result<(u16, Vec<u8>), String> {
+    let count = |n: usize| match fields.len() == n {
+        true => Ok(()),
+        false => Err(format!("{} records need {} fields", rtype, n)),
+    };
+    let number = |field: &String| field.parse::<u32>().map_err(|_| format!("{} isn't a number", field));
+    let mut rdata = Vec::new();
+
+    // Generic rdata (RFC 3597): \# <length> <hex>
+    if fields.first().map(|field| field.as_str()) == Some("\\#") {
+        let code = type_code(rtype).ok_or(format!("{} isn't a record type", rtype))?;
+        let length = number(fields.get(1).ok_or("\\# needs a length".to_string())?)? as usize;
+        let hex: String = fields[2..].concat();
+        if !hex.is_ascii() || hex.len() != length * 2 {
+            return Err(format!("\\# data doesn't have {} bytes", length));
+        }
+        for i in (0..hex.len()).step_by(2) {
+            let byte = u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("{} isn't hexadecimal", hex))?;
+            rdata.push(byte);
+        }
+        return Ok((code, rdata));
+    }
+
+    let code = match rtype {
+        "A" => {
+            count(1)?;
+            let ip: Ipv4Addr = fields[0].parse().map_err(|_| format!("{} isn't an IPv4 address", fields[0]))?;
+            rdata.extend_from_slice(&ip.octets());
+            TYPE_A
+        }
+        "AAAA" => {
+            count(1)?;
+            let ip: Ipv6Addr = fields[0].parse().map_err(|_| format!("{} isn't an IPv6 address", fields[0]))?;
+            rdata.extend_from_slice(&ip.octets());
+            TYPE_AAAA
+        }
+        "NS" | "CNAME" | "PTR" => {
+            count(1)?;
+            write_name(&mut rdata, &absolute_name(&fields[0], origin));
+            match rtype {
+                "NS" => TYPE_NS,
+                "CNAME" => TYPE_CNAME,
+                _ => TYPE_PTR,
+            }
+        }
+        "MX" => {
+            count(2)?;
+            rdata.extend_from_slice(&(number(&fields[0])? as u16).to_be_bytes());
+            write_name(&mut rdata, &absolute_name(&fields[1], origin));
+            TYPE_MX
+        }
+        "SRV" => {
+            count(4)?;
+            for field in fields[..3].iter() {
+                rdata.extend_from_slice(&(number(field)? as u16).to_be_bytes());
+            }
+            write_name(&mut rdata, &absolute_name(&fields[3], origin));
+            TYPE_SRV
+        }
+        "TXT" => {
+            if fields.is_empty() {
+                return Err("TXT records need at least one string".to_string());
+            }
+            for field in fields.iter() {
+                let text = field.as_bytes();
+                if text.len() > 255 {
+                    return Err("TXT strings are limited to 255 characters".to_string());
+                }
+                rdata.push(text.len() as u8);
+                rdata.extend_from_slice(text);
+            }
+            TYPE_TXT
+        }
+        "SOA" => {
+            count(7)?;
+            write_name(&mut rdata, &absolute_name(&fields[0], origin));
+            write_name(&mut rdata, &absolute_name(&fields[1], origin));
+            rdata.extend_from_slice(&number(&fields[2])?.to_be_bytes());
+            for field in fields[3..].iter() {
+                let value = parse_ttl(field).ok_or(format!("{} isn't a valid time", field))?;
+                rdata.extend_from_slice(&value.to_be_bytes());
+            }
+            TYPE_SOA
+        }
+        other => return Err(format!("{} records aren't supported", other)),
+    };
+    Ok((code, rdata))
+}
+
+// This function returns the code of a record type, by name or as TYPE<number>
+fn type_code(rtype: &str) -> Option<u16> {
+    match rtype {
+        "A" => Some(TYPE_A),
+        "NS" => Some(TYPE_NS),
+        "CNAME" => Some(TYPE_CNAME),
+        "SOA" => Some(TYPE_SOA),
+        "PTR" => Some(TYPE_PTR),
+        "MX" => Some(TYPE_MX),
+        "TXT" => Some(TYPE_TXT),
+        "AAAA" => Some(TYPE_AAAA),
+        "SRV" => Some(TYPE_SRV),
+        other => other.strip_prefix("TYPE")?.parse().ok(),
+    }
+}
+
+// This function splits the zone file into logical lines: comments are removed, parentheses join lines,
+// and quoted strings are single tokens. It returns the line number, whether the line starts with a blank, and the tokens.
+fn logical_lines(text: &str) -> Result<Vec<(usize, bool, Vec<String>)>, String> {
+    let mut lines = Vec::new();
+    let mut tokens: Vec<String> = Vec::new();
+    let mut start = (0, false);
+    let mut depth = 0;
+
+    for (i, line) in text.lines().enumerate() {
+        if depth == 0 {
+            start = (i + 1, line.starts_with(' ') || line.starts_with('\t'));
+        }
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                ';' => break,
+                '(' => depth += 1,
+                ')' => {
+                    if depth == 0 {
+                        return Err(format!("line {}: unbalanced parenthesis", i + 1));
+                    }
+                    depth -= 1;
+                }
+                '"' => {
+                    let mut quoted = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => quoted.extend(chars.next()),
+                            Some(c) => quoted.push(c),
+                            None => return Err(format!("line {}: unterminated string", i + 1)),
+                        }
+                    }
+                    tokens.push(quoted);
+                }
+                c if c.is_whitespace() => {}
+                c => {
+                    let mut token = c.to_string();
+                    while let Some(&next) = chars.peek() {
+                        if next.is_whitespace() || "();\"".contains(next) {
+                            break;
+                        }
+                        token.push(next);
+                        chars.next();
+                    }
+                    tokens.push(token);
+                }
+            }
+        }
+        if depth == 0 && !tokens.is_empty() {
+            lines.push((start.0, start.1, std::mem::take(&mut tokens)));
+        }
+    }
+    if depth != 0 {
+        return Err("unbalanced parenthesis at the end of the file".to_string());
+    }
+    Ok(lines)
+}
+
+// This function makes a name of the zone file absolute: "@" is the origin, and names without a trailing dot are relative to it
+fn absolute_name(name: &str, origin: &str) -> String {
+    if name == "@" {
+        origin.to_string()
+    } else if name.ends_with('.') {
+        normalize_name(name)
+    } else {
+        normalize_name(&format!("{}.{}", name, origin))
+    }
+}
+
+// This function parses a TTL in seconds, with optional units such as "1h30m" or "2d"
+fn parse_ttl(value: &str) -> Option<u32> {
+    if !value.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let mut total: u32 = 0;
+    let mut current: u32 = 0;
+    let mut unit_seen = false;
+    for c in value.to_ascii_lowercase().chars() {
+        if let Some(digit) = c.to_digit(10) {
+            current = current.checked_mul(10)?.checked_add(digit)?;
+            unit_seen = false;
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None,
+        };
+        total = total.checked_add(current.checked_mul(unit)?)?;
+        current = 0;
+        unit_seen = true;
+    }
+    if !unit_seen {
+        total = total.checked_add(current)?;
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = "cdn.example";
+
+    // This function returns the paths of a zone file and a journal in a fresh temporary directory
+    fn paths(name: &str) -> (String, String) {
+        let dir = std::env::temp_dir().join(format!("static-zone-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = |file: &str| dir.join(file).to_string_lossy().to_string();
+        (path("zone.db"), path("journal"))
+    }
+
+    fn zone(zone_file: &str, journal: &str) -> StaticZone {
+        let soa = Record::soa(ZONE, 3600, "ns1.cdn.example", "admin.cdn.example", 1, 60);
+        StaticZone::new(ZONE, soa, Some(zone_file.to_string()), Some(journal.to_string()))
+    }
+
+    const TEXT: &str = "$TTL 1h
+@   IN SOA ns1 admin ( 2024010100 ; serial
+                       1h 10m 1w 5m )
+    IN NS  ns1
+ns1 300 A 192.0.2.53
+www CNAME host.other.example.
+txt TXT \"hello world\" two
+$ORIGIN sub.cdn.example.
+mx  2h IN MX 10 mail
+gen TYPE99 \\# 3 0a0b0c
+";
+
+    #[test]
+    fn parses_a_zone_file() {
+        let records = parse_zone_file(TEXT, ZONE).unwrap();
+        assert_eq!(records.len(), 7);
+
+        let soa = &records[0];
+        assert_eq!((soa.name.as_str(), soa.rtype, soa.ttl), (ZONE, TYPE_SOA, 3600));
+        assert!(valid_soa_rdata(&soa.rdata));
+        assert_eq!(soa_serial(soa), Some(2024010100));
+        assert_eq!(soa.rdata[soa.rdata.len() - 4..], 300_u32.to_be_bytes());
+
+        // The owner of a line starting with a blank is the previous one
+        assert_eq!((records[1].name.as_str(), records[1].rtype), (ZONE, TYPE_NS));
+        assert_eq!(records[2], Record::a("ns1.cdn.example", 300, Ipv4Addr::new(192, 0, 2, 53)));
+        let mut cname = Vec::new();
+        write_name(&mut cname, "host.other.example");
+        assert_eq!(records[3], Record::new("www.cdn.example", TYPE_CNAME, 3600, cname));
+        assert_eq!(records[4].rdata, b"\x0bhello world\x03two".to_vec());
+        // $ORIGIN changes the suffix of relative names
+        assert_eq!((records[5].name.as_str(), records[5].ttl), ("mx.sub.cdn.example", 7200));
+        assert_eq!(records[6], Record::new("gen.sub.cdn.example", 99, 3600, vec![10, 11, 12]));
+    }
+
+    #[test]
+    fn rejects_bad_zone_files() {
+        assert!(parse_zone_file("www.other.example. A 192.0.2.1", ZONE).is_err());
+        assert!(parse_zone_file("www A 192.0.2.1 (", ZONE).is_err());
+        assert!(parse_zone_file("www A 300.0.2.1", ZONE).is_err());
+        assert!(parse_zone_file("www TYPE99 \\# 2 0a", ZONE).is_err());
+        assert!(parse_zone_file("$INCLUDE other.db", ZONE).is_err());
+        assert!(parse_zone_file("www LOC 1 2 3", ZONE).is_err());
+    }
+
+    #[test]
+    fn replays_the_journal_and_keeps_its_serial() {
+        let (zone_file, journal) = paths("journal");
+        fs::write(&zone_file, TEXT).unwrap();
+        let loaded = zone(&zone_file, &journal).load().unwrap();
+        assert_eq!(loaded.serial(), 2024010100);
+
+        let www = loaded.lookup("www.cdn.example");
+        let added = parse_zone_file("www 60 A 192.0.2.80", ZONE).unwrap();
+        let updated = loaded.commit(&www, &added).unwrap();
+        assert_eq!(updated.serial(), 2024010101);
+        let updated = updated.commit(&[], &parse_zone_file("api 60 A 192.0.2.81", ZONE).unwrap()).unwrap();
+
+        // A restart replays both updates on the zone file, and serves the journal serial
+        let replayed = zone(&zone_file, &journal).load().unwrap();
+        assert_eq!(replayed.serial(), 2024010102);
+        assert_eq!(replayed.records(), updated.records());
+        assert_eq!(replayed.lookup("www.cdn.example"), added);
+        // The zone file serial wins once it's greater than the journal one
+        fs::write(&zone_file, TEXT.replace("2024010100", "2024020100")).unwrap();
+        assert_eq!(zone(&zone_file, &journal).load().unwrap().serial(), 2024020100);
+    }
+
+    #[test]
+    fn reload_bumps_the_serial_and_keeps_the_changes() {
+        let (zone_file, journal) = paths("reload");
+        fs::write(&zone_file, TEXT).unwrap();
+        let loaded = zone(&zone_file, &journal).load().unwrap();
+        assert!(loaded.reload().unwrap().is_none());
+
+        // The serial of the file wasn't increased, so ours is bumped
+        fs::write(&zone_file, format!("{}extra A 192.0.2.99\n", TEXT)).unwrap();
+        let reloaded = loaded.reload().unwrap().unwrap();
+        assert_eq!(reloaded.serial(), 2024010101);
+        let changes = reloaded.changes_since(2024010100).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].removed.is_empty());
+        assert_eq!(changes[0].added, parse_zone_file("extra.sub.cdn.example. A 192.0.2.99", ZONE).unwrap());
+        assert!(reloaded.changes_since(2023).is_none());
+    }
+
+    #[test]
+    fn compares_serials_with_rfc_1982_arithmetic() {
+        assert!(serial_greater(2, 1));
+        assert!(serial_greater(0, u32::MAX));
+        assert!(!serial_greater(1, 1));
+        assert!(!serial_greater(1, 0x8000_0001));
+    }
+}
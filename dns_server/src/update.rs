@@ -0,0 +1,295 @@
+use crate::message::{
+    in_zone, normalize_name, Message, Record, CLASS_ANY, CLASS_IN, RCODE_FORMERR, RCODE_NXDOMAIN,
+    RCODE_REFUSED, TYPE_AXFR, TYPE_CNAME, TYPE_DNSKEY, TYPE_IXFR, TYPE_NS, TYPE_NSEC, TYPE_OPT,
+    TYPE_RRSIG, TYPE_SOA, TYPE_TSIG,
+};
+use crate::static_zone::StaticZone;
+
+// Response codes of RFC 2136
+pub const RCODE_YXDOMAIN: u8 = 6;
+pub const RCODE_YXRRSET: u8 = 7;
+pub const RCODE_NXRRSET: u8 = 8;
+pub const RCODE_NOTZONE: u8 = 10;
+
+// Class NONE, used to delete single records and in "doesn't exist" prerequisites
+const CLASS_NONE: u16 = 254;
+// Type ANY, meaning every type of a name
+pub const TYPE_ANY: u16 = 255;
+
+// This function checks the prerequisites of an UPDATE (RFC 2136) against the static records, and returns
+// the records it removes and adds, or the response code. `dynamic` tells whether a name and type are answered
+// by the CDN selection (TYPE_ANY for any type of the name); those can't be updated.
+pub fn prepare_update(
+    static_zone: &StaticZone,
+    zone: &str,
+    update: &Message,
+    dynamic: &dyn Fn(&str, u16) -> bool,
+) -> Result<(Vec<Record>, Vec<Record>), u8> {
+    let rrset = |name: &str, rtype: u16| -> Vec<Record> {
+        let mut records: Vec<Record> = static_zone
+            .lookup(name)
+            .into_iter()
+            .filter(|rr| rtype == TYPE_ANY || rr.rtype == rtype)
+            .collect();
+        if name == zone && (rtype == TYPE_SOA || rtype == TYPE_ANY) {
+            records.push(static_zone.soa());
+        }
+        records
+    };
+    // The apex always has an NS RRset, from the zone file or made up from the server names
+    let in_use = |name: &str, rtype: u16| {
+        !rrset(name, rtype).is_empty() || dynamic(name, rtype) || (name == zone && rtype == TYPE_NS)
+    };
+
+    // Prerequisites (section 3.2)
+    let mut required: Vec<Record> = Vec::new();
+    for prereq in update.answers.iter() {
+        let name = normalize_name(&prereq.name);
+        if prereq.ttl != 0 {
+            return Err(RCODE_FORMERR);
+        }
+        if !in_zone(&name, zone) {
+            return Err(RCODE_NOTZONE);
+        }
+        match (prereq.class, prereq.rtype) {
+            (CLASS_ANY, TYPE_ANY) if !in_use(&name, TYPE_ANY) => return Err(RCODE_NXDOMAIN),
+            (CLASS_ANY, rtype) if rtype != TYPE_ANY && !in_use(&name, rtype) => return Err(RCODE_NXRRSET),
+            (CLASS_NONE, TYPE_ANY) if in_use(&name, TYPE_ANY) => return Err(RCODE_YXDOMAIN),
+            (CLASS_NONE, rtype) if rtype != TYPE_ANY && in_use(&name, rtype) => return Err(RCODE_YXRRSET),
+            (CLASS_ANY, _) | (CLASS_NONE, _) => {}
+            (CLASS_IN, _) => {
+                let mut record = prereq.clone();
+                record.name = name;
+                required.push(record);
+            }
+            _ => return Err(RCODE_FORMERR),
+        }
+    }
+    // Value-dependent prerequisites: each RRset must match exactly
+    for prereq in required.iter() {
+        let wanted: Vec<&Vec<u8>> = required
+            .iter()
+            .filter(|rr| rr.name == prereq.name && rr.rtype == prereq.rtype)
+            .map(|rr| &rr.rdata)
+            .collect();
+        let existing = rrset(&prereq.name, prereq.rtype);
+        if existing.len() != wanted.len() || existing.iter().any(|rr| !wanted.contains(&&rr.rdata)) {
+            return Err(RCODE_NXRRSET);
+        }
+    }
+
+    // Prescan of the updates (section 3.4.1)
+    for change in update.authorities.iter() {
+        let name = normalize_name(&change.name);
+        if !in_zone(&name, zone) {
+            return Err(RCODE_NOTZONE);
+        }
+        let meta = [TYPE_ANY, TYPE_AXFR, TYPE_IXFR, TYPE_TSIG, TYPE_OPT].contains(&change.rtype);
+        match change.class {
+            CLASS_IN if meta => return Err(RCODE_FORMERR),
+            CLASS_ANY if change.ttl != 0 || !change.rdata.is_empty() || (meta && change.rtype != TYPE_ANY) => {
+                return Err(RCODE_FORMERR)
+            }
+            CLASS_NONE if change.ttl != 0 || meta => return Err(RCODE_FORMERR),
+            CLASS_IN | CLASS_ANY | CLASS_NONE => {}
+            _ => return Err(RCODE_FORMERR),
+        }
+        // DNSSEC records are made on the fly, and CDN answers aren't static records. Deleting every type
+        // at the apex is fine, as it never touches the SOA, the NS or the CDN answer. A CNAME can't be added
+        // at the apex or at a CDN name, as they always have other data.
+        let protected = match change.rtype {
+            TYPE_ANY => name != zone && dynamic(&name, TYPE_ANY),
+            TYPE_CNAME if change.class == CLASS_IN => name == zone || dynamic(&name, TYPE_ANY),
+            rtype => dynamic(&name, rtype),
+        };
+        if [TYPE_RRSIG, TYPE_NSEC, TYPE_DNSKEY].contains(&change.rtype) || protected {
+            return Err(RCODE_REFUSED);
+        }
+    }
+
+    // Apply the updates in order (section 3.4.2) to a copy of the records
+    let original: Vec<Record> = static_zone.records().to_vec();
+    let mut records = original.clone();
+    for change in update.authorities.iter() {
+        let name = normalize_name(&change.name);
+        let apex = name == zone;
+        match change.class {
+            CLASS_IN => {
+                // The SOA and its serial are ours
+                if change.rtype == TYPE_SOA {
+                    continue;
+                }
+                let has_cname = records.iter().any(|rr| rr.name == name && rr.rtype == TYPE_CNAME);
+                // The SOA, the NS and the CDN answers count as other data too
+                let has_other = apex
+                    || dynamic(&name, TYPE_ANY)
+                    || records.iter().any(|rr| rr.name == name && rr.rtype != TYPE_CNAME);
+                if (change.rtype == TYPE_CNAME && has_other) || (change.rtype != TYPE_CNAME && has_cname) {
+                    continue;
+                }
+                if change.rtype == TYPE_CNAME {
+                    records.retain(|rr| !(rr.name == name && rr.rtype == TYPE_CNAME));
+                }
+                let mut record = Record::new(&name, change.rtype, change.ttl, change.rdata.clone());
+                record.class = CLASS_IN;
+                if !records.iter().any(|rr| rr.name == name && rr.rtype == record.rtype && rr.rdata == record.rdata) {
+                    records.push(record);
+                }
+            }
+            CLASS_ANY => records.retain(|rr| {
+                rr.name != name
+                    || (change.rtype != TYPE_ANY && rr.rtype != change.rtype)
+                    || (apex && rr.rtype == TYPE_NS)
+            }),
+            _ => {
+                // Keep at least one NS at the apex
+                let last_ns = apex
+                    && change.rtype == TYPE_NS
+                    && records.iter().filter(|rr| rr.name == name && rr.rtype == TYPE_NS).count() == 1;
+                if !last_ns {
+                    records.retain(|rr| !(rr.name == name && rr.rtype == change.rtype && rr.rdata == change.rdata));
+                }
+            }
+        }
+    }
+
+    let removed = original.iter().filter(|rr| !records.contains(rr)).cloned().collect();
+    let added = records.iter().filter(|rr| !original.contains(rr)).cloned().collect();
+    Ok((removed, added))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Question, OPCODE_UPDATE, TYPE_A, TYPE_MX};
+    use crate::static_zone::{parse_zone_file, soa_serial};
+    use std::fs;
+
+    const ZONE: &str = "cdn.example";
+
+    // This function loads a zone from zone file text, with a journal in a temporary directory
+    fn zone(name: &str, text: &str) -> StaticZone {
+        let dir = std::env::temp_dir().join(format!("update-test-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let zone_file = dir.join("zone.db");
+        let journal = dir.join("journal");
+        fs::write(&zone_file, text).unwrap();
+        let _ = fs::remove_file(&journal);
+        let soa = Record::soa(ZONE, 3600, "ns1.cdn.example", "admin.cdn.example", 1, 60);
+        let paths = (zone_file.to_string_lossy().to_string(), journal.to_string_lossy().to_string());
+        StaticZone::new(ZONE, soa, Some(paths.0), Some(paths.1)).load().unwrap()
+    }
+
+    // This function builds an UPDATE with the given prerequisites and updates
+    fn update(prereqs: Vec<Record>, changes: Vec<Record>) -> Message {
+        let mut message = Message::request(1, OPCODE_UPDATE, ZONE, TYPE_SOA);
+        message.questions = vec![Question { name: ZONE.to_string(), qtype: TYPE_SOA, qclass: CLASS_IN }];
+        message.answers = prereqs;
+        message.authorities = changes;
+        message
+    }
+
+    fn record(text: &str) -> Record {
+        parse_zone_file(text, ZONE).unwrap().remove(0)
+    }
+
+    fn meta(name: &str, class: u16, rtype: u16) -> Record {
+        let mut record = Record::new(name, rtype, 0, vec![]);
+        record.class = class;
+        record
+    }
+
+    // Bucket names are answered by the CDN selection
+    fn dynamic(name: &str, rtype: u16) -> bool {
+        (name == ZONE && (rtype == TYPE_A || rtype == TYPE_ANY)) || name == "video.cdn.example"
+    }
+
+    const TEXT: &str = "www 300 IN CNAME host\nhost 300 IN A 192.0.2.1\nmail 300 IN MX 10 host\n";
+
+    #[test]
+    fn prerequisites_give_the_rfc_2136_rcodes() {
+        let static_zone = zone("prereq", TEXT);
+        let check = |prereq: Record| prepare_update(&static_zone, ZONE, &update(vec![prereq], vec![]), &dynamic);
+
+        assert!(check(meta("host.cdn.example", CLASS_ANY, TYPE_ANY)).is_ok());
+        assert_eq!(check(meta("nope.cdn.example", CLASS_ANY, TYPE_ANY)), Err(RCODE_NXDOMAIN));
+        assert_eq!(check(meta("host.cdn.example", CLASS_ANY, TYPE_MX)), Err(RCODE_NXRRSET));
+        assert_eq!(check(meta("host.cdn.example", CLASS_NONE, TYPE_ANY)), Err(RCODE_YXDOMAIN));
+        assert_eq!(check(meta("host.cdn.example", CLASS_NONE, TYPE_A)), Err(RCODE_YXRRSET));
+        assert_eq!(check(meta("host.example.org", CLASS_ANY, TYPE_ANY)), Err(RCODE_NOTZONE));
+        // The SOA, the NS and the CDN answers exist even without static records
+        assert!(check(meta(ZONE, CLASS_ANY, TYPE_NS)).is_ok());
+        assert!(check(meta(ZONE, CLASS_ANY, TYPE_SOA)).is_ok());
+        assert_eq!(check(meta("video.cdn.example", CLASS_NONE, TYPE_ANY)), Err(RCODE_YXDOMAIN));
+
+        let mut ttl = meta("host.cdn.example", CLASS_ANY, TYPE_ANY);
+        ttl.ttl = 1;
+        assert_eq!(check(ttl), Err(RCODE_FORMERR));
+
+        // Value-dependent prerequisites must match the whole RRset
+        let mut same = record("host 300 IN A 192.0.2.1");
+        same.ttl = 0;
+        assert!(check(same).is_ok());
+        let mut other = record("host 300 IN A 192.0.2.2");
+        other.ttl = 0;
+        assert_eq!(check(other), Err(RCODE_NXRRSET));
+    }
+
+    #[test]
+    fn cname_conflicts_are_ignored_or_refused() {
+        let static_zone = zone("cname", TEXT);
+        let apply = |change: Record| prepare_update(&static_zone, ZONE, &update(vec![], vec![change]), &dynamic);
+
+        // A CNAME next to other data, or other data next to a CNAME, is silently ignored
+        assert_eq!(apply(record("host 300 IN CNAME www")), Ok((vec![], vec![])));
+        assert_eq!(apply(record("www 300 IN A 192.0.2.9")), Ok((vec![], vec![])));
+        // A CNAME replaces the previous one
+        let (removed, added) = apply(record("www 300 IN CNAME mail")).unwrap();
+        assert_eq!(removed, vec![record("www 300 IN CNAME host")]);
+        assert_eq!(added, vec![record("www 300 IN CNAME mail")]);
+        // The apex and the CDN names always have other data
+        assert_eq!(apply(record("@ 300 IN CNAME host")), Err(RCODE_REFUSED));
+        assert_eq!(apply(record("video 300 IN CNAME host")), Err(RCODE_REFUSED));
+    }
+
+    #[test]
+    fn protected_names_and_types_are_refused() {
+        let static_zone = zone("protected", TEXT);
+        let apply = |change: Record| prepare_update(&static_zone, ZONE, &update(vec![], vec![change]), &dynamic);
+
+        assert_eq!(apply(record("@ 300 IN A 192.0.2.9")), Err(RCODE_REFUSED));
+        assert_eq!(apply(record("video 300 IN A 192.0.2.9")), Err(RCODE_REFUSED));
+        assert_eq!(apply(meta("video.cdn.example", CLASS_ANY, TYPE_ANY)), Err(RCODE_REFUSED));
+        assert_eq!(apply(meta("host.cdn.example", CLASS_ANY, TYPE_RRSIG)), Err(RCODE_REFUSED));
+        assert_eq!(apply(record("host 300 IN A 198.51.100.1")).map(|(_, added)| added.len()), Ok(1));
+        assert_eq!(apply(meta("other.example.org", CLASS_ANY, TYPE_A)), Err(RCODE_NOTZONE));
+    }
+
+    #[test]
+    fn apex_keeps_its_soa_and_last_ns() {
+        let static_zone = zone("apex", "@ 300 IN NS ns1\nns1 300 IN A 192.0.2.53\n");
+        let apply = |change: Record| prepare_update(&static_zone, ZONE, &update(vec![], vec![change]), &dynamic);
+
+        assert_eq!(apply(meta(ZONE, CLASS_ANY, TYPE_ANY)), Ok((vec![], vec![])));
+        assert_eq!(apply(meta(ZONE, CLASS_ANY, TYPE_NS)), Ok((vec![], vec![])));
+        let mut last_ns = record("@ 300 IN NS ns1");
+        last_ns.class = CLASS_NONE;
+        last_ns.ttl = 0;
+        assert_eq!(apply(last_ns), Ok((vec![], vec![])));
+        assert_eq!(apply(record("@ 300 IN SOA ns1 admin 99 1h 10m 1d 1m")), Ok((vec![], vec![])));
+    }
+
+    #[test]
+    fn committing_an_update_bumps_the_serial() {
+        let static_zone = zone("serial", TEXT);
+        let serial = static_zone.serial();
+        let message = update(vec![], vec![record("txt 300 IN TXT \"hello\"")]);
+        let (removed, added) = prepare_update(&static_zone, ZONE, &message, &dynamic).unwrap();
+        let updated = static_zone.commit(&removed, &added).unwrap();
+
+        assert_eq!(updated.serial(), serial.wrapping_add(1));
+        assert_eq!(soa_serial(&updated.soa()), Some(serial.wrapping_add(1)));
+        assert_eq!(updated.lookup("txt.cdn.example"), added);
+        assert_eq!(updated.changes_since(serial).unwrap().len(), 1);
+    }
+}
@@ -13,6 +13,21 @@ pub fn parse_arguments() -> clap::ArgMatches {
                 .short('n')
                 .default_value("cs5700cdn.example.com")
         )
+        .arg(
+            Arg::new("ns")
+                .short('s')
+                .long("ns")
+        )
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+        )
+        .arg(
+            Arg::new("dnssec_keys")
+                .short('k')
+                .long("dnssec-keys")
+        )
         .get_matches();
 
     matches
@@ -1,13 +1,29 @@
 mod util;
 
-use actix_web::{get, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web::body::{BodySize, BodyStream, MessageBody};
+use actix_web::dev::{Extensions, Service};
+use actix_web::rt::net::TcpStream;
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{get, web, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder};
 use awc::http::StatusCode;
 use clap::Parser;
+use futures_util::stream::{self, Stream, StreamExt};
+use std::any::Any;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
-use util::cache_system::CacheSystem;
-use util::cl_parser::Cli;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use util::cache_key::CacheKey;
+use util::cache_system::{CacheSystem, CACHE_CAPACITY};
+use util::cl_parser::{Cli, RangeMiss};
+use util::conditional::{is_not_modified, not_modified_headers, with_etag};
+use util::encoding::{accepts_gzip, gzip_headers, vary_on_encoding};
+use util::freshness::{vary_headers, Freshness};
+use util::headers::{header_list, replayable_headers};
+use util::latency::LatencyTable;
+use util::range::{self, Ranges};
+use util::single_flight::{wait, Flight, Leader, Outcome, SingleFlight};
+use util::throughput::Throughput;
 use sysinfo::System;
 
 #[macro_use]
@@ -15,54 +31,387 @@ extern crate lazy_static;
 
 lazy_static! {
     // Create the cache system with Mutex so it's thread-safe.
-    static ref CACHE: Arc<Mutex<CacheSystem>> = Arc::new(Mutex::new(CacheSystem::new(18_000_000)));
+    static ref CACHE: Arc<Mutex<CacheSystem>> = Arc::new(Mutex::new(CacheSystem::new(CACHE_CAPACITY)));
+    // RTT of the clients per prefix. It's filled from the synchronous on_connect hook, so it uses a std Mutex.
+    static ref LATENCY: Arc<std::sync::Mutex<LatencyTable>> = Arc::new(std::sync::Mutex::new(LatencyTable::new()));
+    // Requests and bytes served to clients, reported to the DNS server for capacity-aware balancing.
+    static ref THROUGHPUT: Throughput = Throughput::new();
+    // Origin fetches in progress, by cache key, shared by the clients missing the same content at once.
+    static ref FLIGHTS: SingleFlight<Fetched> = SingleFlight::new();
 }
 
+// Define the Fetched enum: the result of an origin fetch, sent to the clients waiting for it
+#[derive(Clone)]
+enum Fetched {
+    // The content, the headers to send and its age
+    Content(Bytes, Vec<(String, String)>, Duration),
+    // The content is larger than the cache admits, so every client streams it from the origin
+    PassThrough,
+    // An origin answer that isn't cached, such as a 404 or a redirect: its status, the headers to send and its body
+    Response(StatusCode, Vec<(String, String)>, Bytes),
+    // The status to answer with
+    Failed(StatusCode),
+}
+
+// Status, headers to send and age of the content being streamed from the origin, or the status to answer with
+type Head = Result<(StatusCode, Vec<(String, String)>, Duration), StatusCode>;
+// Chunks of the content being streamed from the origin
+type Chunk = Result<Bytes, std::io::Error>;
+
 struct AppState {
     origin: String,
+    cache_key: CacheKey,
+    // How long a client waits for the origin fetch of the content
+    coalesce_timeout: Duration,
+    // Largest content stored in the cache, in bytes. Larger contents are streamed without being cached.
+    max_object_size: usize,
+    // How a range request for a content that isn't cached is answered
+    range_miss: RangeMiss,
 }
 
 // This function is used to fetch the content either from the cache or origin.
-#[get("/{content_path}")]
+// Any path is served, with its query string, except the ones of the other services, which are registered first.
+#[get("/{content_path:.*}")]
 async fn serve_content(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
-    // Extract the content string
-    let content_path: String = req
-        .match_info()
-        .get("content_path")
-        .unwrap()
-        .parse()
-        .unwrap();
-
-    // Check if the content exists in the cache.
-    if CACHE.lock().await.contains_key(&content_path) {
+    // The path and query are kept as sent (still percent-encoded) for the origin, and normalized for the cache
+    let path_and_query = req.uri().path_and_query().map_or("/", |path| path.as_str()).to_string();
+    let content_path = state.cache_key.of(req.uri().path(), req.uri().query().unwrap_or(""));
+
+    // Check if a fresh copy of the content exists in the cache.
+    // It's sent as stored (gzip) to clients accepting it, except for ranges, which are of the uncompressed content.
+    let zipped = accepts_gzip(req.headers()) && !req.headers().contains_key("range");
+    let cached = CACHE.lock().await.get(&content_path, req.headers(), zipped);
+    if let Some((content, headers, age)) = cached {
         dbg!("cache!!!");
 
-        HttpResponse::Ok().body(CACHE.lock().await.get(&content_path))
-    } else { // Fetch the content from the origin.
-        let client = awc::Client::default();
-        let response = client
-            .get(format!("http://{}:8080/{}", state.origin, content_path)) // <- Create request builder
+        if zipped {
+            return respond(&req, &gzip_headers(&headers), age, content);
+        }
+        return respond_content(&req, &headers, age, Bytes::from(content));
+    }
+
+    // A range of a content that isn't cached is forwarded to the origin, or cut from the whole content once it's fetched
+    let ranged = req.headers().contains_key("range");
+    if ranged && state.range_miss == RangeMiss::Forward {
+        return forward_range(&req, &state, &path_and_query).await;
+    }
+
+    // Fetch the content from the origin. Concurrent misses of the same content share one origin request.
+    let flight_key = CACHE.lock().await.cache_key(&content_path, req.headers());
+    let receiver = match FLIGHTS.join(&flight_key) {
+        // The whole content is fetched in the background, and this client waits for it like the followers
+        Flight::Leader(leader) if ranged => {
+            let receiver = leader.subscribe();
+            let (head_sender, _) = oneshot::channel();
+            let (body_sender, _) = mpsc::channel(1);
+            actix_web::rt::spawn(fetch_from_origin(
+                req.clone(),
+                state.clone(),
+                path_and_query.clone(),
+                content_path.clone(),
+                Some(leader),
+                head_sender,
+                body_sender,
+            ));
+            receiver
+        }
+        Flight::Leader(leader) => return stream_from_origin(&req, &state, &path_and_query, &content_path, Some(leader)).await,
+        Flight::Follower(receiver) => receiver,
+    };
+
+    match wait(receiver, state.coalesce_timeout).await {
+        // A response varying on other request headers may be for another variant, so it isn't shared
+        Outcome::Done(Fetched::Content(content, headers, age)) if !varies(&headers) => respond_content(&req, &headers, age, content),
+        Outcome::Done(Fetched::Response(status, headers, content)) if !varies(&headers) => pass_through(status, &headers, content),
+        // A range of a content too large for the cache is asked to the origin
+        Outcome::Done(Fetched::Content(..)) | Outcome::Done(Fetched::Response(..)) | Outcome::Done(Fetched::PassThrough) if ranged => {
+            forward_range(&req, &state, &path_and_query).await
+        }
+        Outcome::Done(Fetched::Content(..)) | Outcome::Done(Fetched::Response(..)) | Outcome::Done(Fetched::PassThrough) => {
+            stream_from_origin(&req, &state, &path_and_query, &content_path, None).await
+        }
+        Outcome::Done(Fetched::Failed(status)) => HttpResponse::build(status).body(""),
+        Outcome::Abandoned => HttpResponse::BadGateway().body(""),
+        Outcome::TimedOut => HttpResponse::GatewayTimeout().body(""),
+    }
+}
+
+// This function is used to answer a client with the content of the origin, streamed as it arrives.
+// The fetch runs in its own task, so the content is still cached, and sent to the clients waiting for it, if this client leaves.
+async fn stream_from_origin(
+    req: &HttpRequest,
+    state: &web::Data<AppState>,
+    path_and_query: &str,
+    content_path: &str,
+    leader: Option<Leader<Fetched>>,
+) -> HttpResponse {
+    let (head_sender, head) = oneshot::channel();
+    let (body_sender, body) = mpsc::channel(64);
+    actix_web::rt::spawn(fetch_from_origin(
+        req.clone(),
+        state.clone(),
+        path_and_query.to_string(),
+        content_path.to_string(),
+        leader,
+        head_sender,
+        body_sender,
+    ));
+
+    match tokio::time::timeout(state.coalesce_timeout, head).await {
+        Ok(Ok(Ok((StatusCode::OK, headers, age)))) => respond(req, &headers, age, BodyStream::new(counted(received(body)))),
+        Ok(Ok(Ok((status, headers, _)))) => pass_through(status, &headers, BodyStream::new(counted(received(body)))),
+        Ok(Ok(Err(status))) => HttpResponse::build(status).body(""),
+        // The fetch task panicked
+        Ok(Err(_)) => HttpResponse::BadGateway().body(""),
+        Err(_) => HttpResponse::GatewayTimeout().body(""),
+    }
+}
+
+// This function is used to fetch the content from the origin. The headers and the chunks of the body are sent to the client
+// as they arrive, and the body is kept for the cache system unless it's larger than the cache admits.
+// Origin answers other than 200 and 304 are passed on as they are, without being cached, and a failed connection is a 502.
+// The result is sent to the clients waiting for the leader.
+async fn fetch_from_origin(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path_and_query: String,
+    content_path: String,
+    mut leader: Option<Leader<Fetched>>,
+    head: oneshot::Sender<Head>,
+    body: mpsc::Sender<Chunk>,
+) {
+    // When a stale copy is cached, ask the origin to only send the content if it changed
+    let mut conditions = CACHE.lock().await.validators(&content_path, req.headers());
+    let client = awc::Client::default();
+    loop {
+        let mut request = client
+            .get(format!("http://{}:8080{}", state.origin, path_and_query)) // <- Create request builder
             .insert_header(("Accept-Encoding", "gzip"))
-            .insert_header(("User-Agent", "Actix-web"))
-            .send() // <- Send http request
-            .await;
-
-        match response {
-            Ok(mut res) => match res.status() {
-                StatusCode::OK => { // When the content was successfully fetched from the origin, serve it to the client.
-                    let body_bytes = res.body().limit(20_000_000).await.expect("failed!!!");
-                    let body = String::from_utf8_lossy(&body_bytes).to_string();
-                    // Pass the content to the cache system and let it decides whether the content should be stored or not.
-                    CACHE.lock().await.add(&content_path, &body);
-                    HttpResponse::Ok().body(body)
+            .insert_header(("User-Agent", "Actix-web"));
+        for (name, value) in conditions.iter() {
+            request = request.insert_header((*name, value.as_str()));
+        }
+        let mut res = match request.send().await { // <- Send http request
+            Ok(res) => res,
+            Err(e) => {
+                dbg!(format!("Error: origin request for {} failed: {}", path_and_query, e));
+                return fail(leader, head, StatusCode::BAD_GATEWAY);
+            }
+        };
+
+        let status = res.status();
+        match status {
+            StatusCode::OK => {}
+            StatusCode::NOT_MODIFIED if !conditions.is_empty() => { // The stale copy is still valid, serve it again.
+                let revalidated = CACHE.lock().await.revalidate(&content_path, req.headers(), res.headers());
+                match revalidated {
+                    Some((content, headers, age)) => {
+                        let content = Bytes::from(content);
+                        let _ = head.send(Ok((StatusCode::OK, headers.clone(), age)));
+                        let _ = body.send(Ok(content.clone())).await;
+                        return finish(&mut leader, Fetched::Content(content, headers, age));
+                    }
+                    None => {
+                        // The copy was evicted while the origin answered, so fetch the whole content
+                        conditions.clear();
+                        continue;
+                    }
                 }
-                _ => HttpResponse::NotFound().body(""),
-            },
-            Err(_) => HttpResponse::NotFound().body(""),
+            }
+            // Errors, redirects and the like are streamed to the client as the origin sent them
+            _ => {}
         }
+
+        // When the content was successfully fetched from the origin, stream it to the client.
+        // The freshness is computed from all the origin headers, before the ones we don't replay are removed
+        let freshness = Freshness::from_headers(&header_list(res.headers()));
+        let headers = replayable_headers(res.headers());
+        // The headers go out before the body arrives, so a content the origin sends without an ETag is streamed
+        // without one. The ETag generated from the body (see with_etag below) only comes with the cached copy.
+        let _ = head.send(Ok((status, headers.clone(), freshness.age())));
+
+        // Keep the body as raw bytes, so images, fonts and other binary content aren't altered.
+        // A Content-Length over the limit (of the body as sent, which may be compressed) means the content can't be cached.
+        let announced = res
+            .headers()
+            .get("content-length")
+            .and_then(|length| length.to_str().ok()?.parse::<usize>().ok())
+            .unwrap_or(0);
+        let mut kept = Some(BytesMut::new());
+        if announced > state.max_object_size {
+            kept = None;
+            finish(&mut leader, Fetched::PassThrough);
+        }
+        while let Some(chunk) = res.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    dbg!(format!("Error: origin body of {} failed: {}", path_and_query, e));
+                    let _ = body.send(Err(std::io::Error::other(e.to_string()))).await;
+                    return finish(&mut leader, Fetched::Failed(StatusCode::BAD_GATEWAY));
+                }
+            };
+            if let Some(buffer) = kept.as_mut() {
+                if buffer.len() + chunk.len() > state.max_object_size {
+                    kept = None;
+                    finish(&mut leader, Fetched::PassThrough);
+                } else {
+                    buffer.extend_from_slice(&chunk);
+                }
+            }
+            // When the client left and the content isn't cached, nobody needs the rest
+            if body.send(Ok(chunk)).await.is_err() && kept.is_none() {
+                return;
+            }
+        }
+
+        if let Some(buffer) = kept {
+            let content = buffer.freeze();
+            if status != StatusCode::OK {
+                return finish(&mut leader, Fetched::Response(status, headers, content));
+            }
+            // Only known now that the whole body is here, so the client the content was just streamed to didn't get it
+            let headers = with_etag(headers, &content);
+            // Pass the content to the cache system and let it decides whether the content should be stored or not.
+            CACHE.lock().await.add(&content_path, req.headers(), &content, &headers, freshness);
+            finish(&mut leader, Fetched::Content(content, headers, freshness.age()));
+        }
+        return;
+    }
+}
+
+// This function is used to send the result of a fetch to the clients waiting for the leader.
+fn finish(leader: &mut Option<Leader<Fetched>>, fetched: Fetched) {
+    if let Some(leader) = leader.take() {
+        leader.finish(fetched);
     }
 }
 
+// This function is used to answer the client and the clients waiting for the leader with an error status.
+fn fail(mut leader: Option<Leader<Fetched>>, head: oneshot::Sender<Head>, status: StatusCode) {
+    let _ = head.send(Err(status));
+    finish(&mut leader, Fetched::Failed(status));
+}
+
+// This function is used to forward a range request to the origin, and stream its answer (206, 416, the whole content or an error) to the client.
+// The ranges are of the content as the origin stores it, so the origin is asked not to compress it.
+async fn forward_range(req: &HttpRequest, state: &web::Data<AppState>, path_and_query: &str) -> HttpResponse {
+    let client = awc::Client::default();
+    let mut request = client
+        .get(format!("http://{}:8080{}", state.origin, path_and_query))
+        .insert_header(("Accept-Encoding", "identity"))
+        .insert_header(("User-Agent", "Actix-web"));
+    for name in ["range", "if-range"] {
+        if let Some(value) = req.headers().get(name) {
+            request = request.insert_header((name, value.clone()));
+        }
+    }
+
+    match request.send().await {
+        Ok(res) => pass_through(res.status(), &replayable_headers(res.headers()), BodyStream::new(counted(res))),
+        Err(e) => {
+            dbg!(format!("Error: origin range request for {} failed: {}", path_and_query, e));
+            HttpResponse::BadGateway().body("")
+        }
+    }
+}
+
+// This function is used to answer a client with an origin answer that isn't cached, such as a 404 or a redirect, as the origin sent it.
+fn pass_through(status: StatusCode, headers: &[(String, String)], body: impl MessageBody + 'static) -> HttpResponse {
+    let mut response = HttpResponse::build(status);
+    for (name, value) in headers.iter() {
+        response.append_header((name.as_str(), value.as_str()));
+    }
+    response.body(body)
+}
+
+// This function is used to turn the chunks sent by the fetch task into a stream.
+fn received(body: mpsc::Receiver<Chunk>) -> impl Stream<Item = Chunk> {
+    stream::unfold(body, |mut body| async move { Some((body.recv().await?, body)) })
+}
+
+// This function is used to count the bytes of a streamed response body.
+// Streamed bodies have no size for the throughput middleware, so their bytes are counted here.
+fn counted<E>(body: impl Stream<Item = Result<Bytes, E>>) -> impl Stream<Item = Result<Bytes, E>> {
+    body.inspect(|chunk| {
+        if let Ok(bytes) = chunk {
+            THROUGHPUT.add_bytes(bytes.len() as u64);
+        }
+    })
+}
+
+// This function is used to check if a response varies on request headers, other than Accept-Encoding (see CacheSystem::cache_key)
+fn varies(headers: &[(String, String)]) -> bool {
+    vary_headers(headers).iter().any(|name| name != "accept-encoding")
+}
+
+// This function is used to answer a client with the content and the Age of the content (RFC 9111 section 5.1).
+// It's a 304 Not Modified when the client's conditional headers match the content, and a 200 with the stored origin headers otherwise.
+fn respond(req: &HttpRequest, headers: &[(String, String)], age: Duration, body: impl MessageBody + 'static) -> HttpResponse {
+    if is_not_modified(req.headers(), headers) {
+        let mut response = HttpResponse::NotModified();
+        add_headers(&mut response, &not_modified_headers(headers), age);
+        return response.finish();
+    }
+
+    let mut response = HttpResponse::Ok();
+    add_headers(&mut response, headers, age);
+    response.body(body)
+}
+
+// This function is used to answer a client with a whole content, such as a cached one. Unless it's a 304 Not Modified,
+// the Range header is honored with a 206 Partial Content (multipart/byteranges for several ranges) or a 416 Range Not Satisfiable.
+fn respond_content(req: &HttpRequest, headers: &[(String, String)], age: Duration, content: Bytes) -> HttpResponse {
+    let ranges = match is_not_modified(req.headers(), headers) {
+        true => Ranges::Ignored,
+        false => range::resolve(req.headers(), headers, content.len()),
+    };
+
+    match ranges {
+        Ranges::Ignored => {
+            let mut response = respond(req, headers, age, content);
+            response
+                .headers_mut()
+                .insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+            response
+        }
+        Ranges::Satisfiable(ranges) if ranges.len() == 1 => {
+            let (first, last) = ranges[0];
+            let mut response = HttpResponse::PartialContent();
+            add_headers(&mut response, headers, age);
+            response.insert_header(("Content-Range", range::content_range(first, last, content.len())));
+            response.body(content.slice(first..=last))
+        }
+        Ranges::Satisfiable(ranges) => {
+            let content_type = headers
+                .iter()
+                .find(|(name, _)| name == "content-type")
+                .map(|(_, value)| value.as_str());
+            let (boundary, body) = range::multipart(&content, &ranges, content_type);
+            let headers: Vec<(String, String)> = headers
+                .iter()
+                .filter(|(name, _)| name != "content-type")
+                .cloned()
+                .collect();
+            let mut response = HttpResponse::PartialContent();
+            add_headers(&mut response, &headers, age);
+            response.insert_header(("Content-Type", format!("multipart/byteranges; boundary={}", boundary)));
+            response.body(body)
+        }
+        Ranges::Unsatisfiable => HttpResponse::RangeNotSatisfiable()
+            .insert_header(("Content-Range", format!("bytes */{}", content.len())))
+            .finish(),
+    }
+}
+
+// This function is used to add the stored origin headers and the Age of the content to a response.
+fn add_headers(response: &mut HttpResponseBuilder, headers: &[(String, String)], age: Duration) {
+    for (name, value) in vary_on_encoding(headers).iter() {
+        response.append_header((name.as_str(), value.as_str()));
+    }
+    response.insert_header(("Age", age.as_secs().to_string()));
+}
+
 // This function is used to report the CPU usage of the HTTP server when the DNS server request it.
 #[get("/api/getUsage")]
 async fn get_usage() -> impl Responder {
@@ -87,6 +436,56 @@ async fn get_usage() -> impl Responder {
     HttpResponse::Ok().body(format!("{}", usage))
 }
 
+// This function is used to report the RTT of the clients, per prefix, when the DNS server request it.
+#[get("/api/getLatency")]
+async fn get_latency() -> impl Responder {
+    let report = LATENCY.lock().unwrap().report();
+    HttpResponse::Ok().body(report)
+}
+
+// This function is used to report the throughput of the HTTP server ("requests_per_second egress_mbps") when the DNS server request it.
+#[get("/api/getThroughput")]
+async fn get_throughput() -> impl Responder {
+    HttpResponse::Ok().body(THROUGHPUT.report())
+}
+
+// This function is used to record the RTT measured by the kernel during the TCP handshake of a new connection.
+fn record_handshake_rtt(connection: &dyn Any, _data: &mut Extensions) {
+    if let Some(stream) = connection.downcast_ref::<TcpStream>() {
+        if let (Ok(peer), Some(rtt)) = (stream.peer_addr(), tcp_rtt(stream)) {
+            LATENCY.lock().unwrap().record(peer.ip(), rtt);
+        }
+    }
+}
+
+// This function reads the smoothed RTT of a TCP connection from TCP_INFO.
+#[cfg(target_os = "linux")]
+fn tcp_rtt(stream: &TcpStream) -> Option<Duration> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == 0 && info.tcpi_rtt > 0 {
+        Some(Duration::from_micros(info.tcpi_rtt as u64))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tcp_rtt(_stream: &TcpStream) -> Option<Duration> {
+    None
+}
+
 // This function is used to respond to the grading beacon.
 #[get("/grading/beacon")]
 async fn respond_beacon() -> impl Responder {
@@ -98,15 +497,53 @@ async fn main() -> std::io::Result<()> {
     let cli = Cli::parse();
 
     // Used web::Data to pass the origin to each thread.
-    let app_state = web::Data::new(AppState { origin: cli.origin });
+    let app_state = web::Data::new(AppState {
+        origin: cli.origin,
+        cache_key: CacheKey {
+            sort_query: cli.sort_query,
+            ignored_params: cli.ignore_param,
+            ignore_case: cli.ignore_case,
+        },
+        coalesce_timeout: Duration::from_secs(cli.coalesce_timeout),
+        // A content larger than the cache could never be stored
+        max_object_size: cli.max_object_size.min(CACHE_CAPACITY as usize),
+        range_miss: cli.range_miss,
+    });
+
+    // Update the throughput rates every 5 seconds
+    actix_web::rt::spawn(async {
+        loop {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            THROUGHPUT.update();
+        }
+    });
 
     HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            // Count the client requests and the bytes sent back, but not the DNS server probes
+            .wrap_fn(|req, srv| {
+                let counted = !req.path().starts_with("/api/");
+                if counted {
+                    THROUGHPUT.add_request();
+                }
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await?;
+                    if let (true, BodySize::Sized(bytes)) = (counted, res.response().body().size()) {
+                        THROUGHPUT.add_bytes(bytes);
+                    }
+                    Ok(res)
+                }
+            })
             .service(respond_beacon)
-            .service(serve_content)
             .service(get_usage)
+            .service(get_latency)
+            .service(get_throughput)
+            // Registered last, because it matches every path
+            .service(serve_content)
     })
+    .on_connect(record_handshake_rtt)
     .keep_alive(Duration::from_secs(25))
     .bind(("0.0.0.0", cli.port))?
     .bind(("0.0.0.0", cli.port + 1))?
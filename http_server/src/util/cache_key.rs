@@ -0,0 +1,101 @@
+// Define the CacheKey struct: how the URL of a request is normalized into its cache key,
+// so requests for the same content share one cache entry.
+pub struct CacheKey {
+    // Sort the query parameters by name
+    pub sort_query: bool,
+    // Query parameters left out of the key, such as tracking parameters. A trailing * matches a prefix.
+    pub ignored_params: Vec<String>,
+    // Lowercase the path and the query parameter names
+    pub ignore_case: bool,
+}
+
+impl CacheKey {
+    // This function is used to build the cache key of a path and its query string (without the "?").
+    pub fn of(&self, path: &str, query: &str) -> String {
+        let mut params: Vec<(String, &str)> = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                match self.ignore_case {
+                    true => (name.to_lowercase(), value),
+                    false => (name.to_string(), value),
+                }
+            })
+            .filter(|(name, _)| !self.is_ignored(name))
+            .collect();
+        if self.sort_query {
+            // The sort is stable, so repeated parameters keep their order
+            params.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+
+        let mut key = match self.ignore_case {
+            true => path.to_lowercase(),
+            false => path.to_string(),
+        };
+        for (i, (name, value)) in params.iter().enumerate() {
+            key.push(if i == 0 { '?' } else { '&' });
+            key.push_str(&format!("{}={}", name, value));
+        }
+        key
+    }
+
+    // This function is used to check if a query parameter is left out of the key. Names are matched case-insensitively.
+    fn is_ignored(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.ignored_params.iter().any(|ignored| {
+            let ignored = ignored.to_lowercase();
+            match ignored.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == ignored,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache_key(sort_query: bool, ignore_case: bool) -> CacheKey {
+        CacheKey {
+            sort_query,
+            ignored_params: vec!["utm_*".to_string(), "gclid".to_string()],
+            ignore_case,
+        }
+    }
+
+    #[test]
+    fn keeps_the_path_and_query_as_is_by_default() {
+        let key = cache_key(false, false);
+        assert_eq!(key.of("/a/B/c.html", ""), "/a/B/c.html");
+        assert_eq!(key.of("/search", "q=x&page=2"), "/search?q=x&page=2");
+        assert_eq!(key.of("/search", "page=2&q=x"), "/search?page=2&q=x");
+        assert_eq!(key.of("/search", "&&q=x&"), "/search?q=x");
+    }
+
+    #[test]
+    fn sorts_the_query_stably() {
+        let key = cache_key(true, false);
+        assert_eq!(key.of("/search", "q=x&page=2"), key.of("/search", "page=2&q=x"));
+        // Repeated parameters keep their order
+        assert_eq!(key.of("/list", "b=1&a=2&a=1"), "/list?a=2&a=1&b=1");
+        assert_ne!(key.of("/list", "a=1&a=2"), key.of("/list", "a=2&a=1"));
+    }
+
+    #[test]
+    fn leaves_out_ignored_parameters_with_wildcards() {
+        let key = cache_key(true, false);
+        assert_eq!(key.of("/page", "utm_source=x&id=3&UTM_Medium=y&gclid=z"), "/page?id=3");
+        assert_eq!(key.of("/page", "utm_source=x"), "/page");
+        // Only a trailing * is a wildcard, and names must match entirely otherwise
+        assert_eq!(key.of("/page", "gclid2=z&xutm_a=1"), "/page?gclid2=z&xutm_a=1");
+    }
+
+    #[test]
+    fn ignore_case_lowercases_the_path_and_names_but_not_values() {
+        let key = cache_key(true, true);
+        assert_eq!(key.of("/Docs/Index.HTML", "Lang=EN&Id=7"), "/docs/index.html?id=7&lang=EN");
+        assert_eq!(key.of("/docs", "ID=7"), key.of("/DOCS", "id=7"));
+    }
+}
@@ -1,20 +1,56 @@
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
+use super::conditional::is_generated_etag;
+use super::freshness::{self, Freshness};
+use super::headers::{header_list, replayable_headers};
+use actix_web::http::header::HeaderMap;
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::io::prelude::*;
+use std::time::Duration;
 
 const MAX_FREQUENCY: i32 = 500;
+// Size of the cache, in bytes
+pub const CACHE_CAPACITY: u64 = 18_000_000;
+
+// Content served from the cache: the unzipped content, its origin headers and its age
+pub type CachedContent = (Vec<u8>, Vec<(String, String)>, Duration);
+
+// Define the CacheEntry struct: the zipped content, the origin headers sent with it and how long it stays fresh
+struct CacheEntry {
+    content: Vec<u8>,
+    headers: Vec<(String, String)>,
+    freshness: Freshness,
+}
+
+impl CacheEntry {
+    // This function is used to get the space the entry takes in the cache
+    fn size(&self) -> u64 {
+        let headers: usize = self.headers.iter().map(|(name, value)| name.len() + value.len()).sum();
+        (self.content.len() + headers) as u64
+    }
+
+    // This function is used to unzip the content, and get it with its headers and its current age
+    fn unzip(&self) -> CachedContent {
+        let mut decoder = GzDecoder::new(self.content.as_slice());
+        let mut content = Vec::new();
+        decoder.read_to_end(&mut content).unwrap();
+        (content, self.headers.clone(), self.freshness.age())
+    }
+}
+
 pub struct CacheSystem {
     // Use hashmap to store the cache content
-    cache: HashMap<String, Vec<u8>>,
+    cache: HashMap<String, CacheEntry>,
     // Maximum size of the cache
     max_size: u64,
     // Current size of the cache
     cur_size: u64,
     // Use hashmap to track the request frequency of each content
     frequency: HashMap<String, i32>,
+    // Request headers named in the Vary header of the last response of each path
+    vary: HashMap<String, Vec<String>>,
 }
 
 impl CacheSystem {
@@ -25,56 +61,180 @@ impl CacheSystem {
             max_size,
             cur_size: 0,
             frequency: HashMap::new(),
+            vary: HashMap::new(),
         }
     }
 
-    // This function is used to add content to the cache system.
+    // This function is used to add content to the cache system, with the origin headers to send along.
+    // The content is raw bytes, so any content type can be cached.
+    // Responses the origin marks as uncacheable are not stored, and a response with Vary is stored
+    // under a secondary key made of the request headers it varies on.
     // If the cache system is full, if will delete the least frequent content in the cache.
-    pub fn add(&mut self, path: &str, content: &str) {
-        self.add_frequency(path);
+    pub fn add(
+        &mut self,
+        path: &str,
+        request: &HeaderMap,
+        content: &[u8],
+        headers: &[(String, String)],
+        freshness: Freshness,
+    ) {
+        if !freshness::is_storable(headers) {
+            // The stale copy of a content that became uncacheable isn't kept either
+            let key = self.cache_key(path, request);
+            if self.cache.contains_key(&key) {
+                self.delete_cache(&key);
+            }
+            return;
+        }
+        // The variants stored under another Vary can't be looked up anymore, so they are deleted
+        let vary = freshness::vary_headers(headers);
+        if self.vary.get(path).is_some_and(|previous| *previous != vary) {
+            self.delete_variants(path);
+        }
+        self.vary.insert(path.to_string(), vary);
+        let key = self.cache_key(path, request);
+        // Replace the stale entry of the same key
+        if self.cache.contains_key(&key) {
+            self.delete_cache(&key);
+        }
+        self.add_frequency(&key);
 
         if self.is_full() {
-            let least_path = self.least_frequent(path);
+            let least_path = self.least_frequent(&key);
             dbg!(&least_path);
-            if least_path != path {
+            if least_path != key {
                 self.delete_cache(&least_path);
                 if !self.is_full() {
-                    self.add_cache(path, content);
+                    self.add_cache(&key, content, headers, freshness);
                 }
             }
         } else {
-            self.add_cache(path, content);
+            self.add_cache(&key, content, headers, freshness);
+        }
+    }
+
+    // This function is used to retrieve the cache content, its headers and its age.
+    // With zipped, the content is returned as stored (gzip), for clients accepting it.
+    // It returns None when the content isn't in the cache system, or isn't fresh anymore.
+    pub fn get(&mut self, path: &str, request: &HeaderMap, zipped: bool) -> Option<CachedContent> {
+        let key = self.cache_key(path, request);
+        let entry = self.cache.get(&key)?;
+        if !entry.freshness.is_fresh() {
+            return None;
+        }
+        let cached = match zipped {
+            true => (entry.content.clone(), entry.headers.clone(), entry.freshness.age()),
+            false => entry.unzip(),
+        };
+        self.add_frequency(&key);
+        Some(cached)
+    }
+
+    // This function is used to get the conditional request headers to revalidate a stale entry with the origin
+    // (RFC 9110 section 13.1): If-None-Match with its ETag, and If-Modified-Since with its Last-Modified.
+    // ETags we generated from the content are unknown to the origin, so they aren't sent.
+    pub fn validators(&self, path: &str, request: &HeaderMap) -> Vec<(&'static str, String)> {
+        let key = self.cache_key(path, request);
+        let mut conditions = Vec::new();
+        if let Some(entry) = self.cache.get(&key) {
+            for (name, value) in entry.headers.iter() {
+                match name.as_str() {
+                    "etag" if !is_generated_etag(value) => conditions.push(("If-None-Match", value.clone())),
+                    "last-modified" => conditions.push(("If-Modified-Since", value.clone())),
+                    _ => {}
+                }
+            }
         }
+        conditions
     }
 
-    // This function is used to retrieve the cache content.
-    // It will be call when we know the content is in the cache system.
-    pub fn get(&mut self, path: &str) -> String {
-        let bytes = self.cache.get(path).unwrap();
-        // Unzip the content
-        let mut decoder = GzDecoder::new(bytes.as_slice());
-        let mut s = String::new();
-        decoder.read_to_string(&mut s).unwrap();
-        self.add_frequency(path);
-        s
+    // This function is used to refresh a stale entry after the origin answered 304 Not Modified (RFC 9111 section 4.3.4).
+    // The headers of the 304 replace the stored ones, and the freshness is computed again.
+    // It returns None when the entry was evicted in the meantime.
+    pub fn revalidate(&mut self, path: &str, request: &HeaderMap, not_modified: &HeaderMap) -> Option<CachedContent> {
+        let key = self.cache_key(path, request);
+        let entry = self.cache.get_mut(&key)?;
+        let old_size = entry.size();
+
+        let updates = replayable_headers(not_modified);
+        entry.headers.retain(|(name, _)| !updates.iter().any(|(updated, _)| updated == name));
+        entry.headers.extend(updates);
+        // Date and Age aren't stored, but they are needed to compute the age of the refreshed entry
+        let mut dated = entry.headers.clone();
+        dated.extend(
+            header_list(not_modified)
+                .into_iter()
+                .filter(|(name, _)| name == "date" || name == "age"),
+        );
+        entry.freshness = Freshness::from_headers(&dated);
+
+        let new_size = entry.size();
+        let cached = entry.unzip();
+        self.cur_size = self.cur_size - old_size + new_size;
+        self.add_frequency(&key);
+        Some(cached)
+    }
+
+    // This function is used to build the cache key of a request: the path, and the values of the
+    // request headers the cached response varies on (RFC 9111 section 4.1).
+    // Accept-Encoding is left out, because the content is stored once and sent zipped or not depending on the client.
+    pub fn cache_key(&self, path: &str, request: &HeaderMap) -> String {
+        let mut key = path.to_string();
+        let names = match self.vary.get(path) {
+            Some(names) => names,
+            None => return key,
+        };
+        for name in names.iter().filter(|name| *name != "accept-encoding") {
+            let values: Vec<String> = request
+                .get_all(name.as_str())
+                .filter_map(|value| value.to_str().ok())
+                .map(|value| value.split_whitespace().collect::<Vec<_>>().join(" "))
+                .collect();
+            key.push_str(&format!("\n{}: {}", name, values.join(", ")));
+        }
+        key
     }
 
     // This function is used to delete contents in the cache system.
     fn delete_cache(&mut self, path: &str) {
         let deleted = self.cache.remove(path).unwrap();
         // Reduce the content size from current size of the cache system
-        self.cur_size -= deleted.len() as u64;
+        self.cur_size -= deleted.size();
+    }
+
+    // This function is used to delete every variant of a path in the cache system.
+    fn delete_variants(&mut self, path: &str) {
+        let prefix = format!("{}\n", path);
+        let keys: Vec<String> = self
+            .cache
+            .keys()
+            .filter(|key| *key == path || key.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for key in keys.iter() {
+            self.delete_cache(key);
+        }
     }
 
     // This function is used to add contents to the cache system.
-    fn add_cache(&mut self, path: &str, content: &str) {
+    fn add_cache(
+        &mut self,
+        path: &str,
+        content: &[u8],
+        headers: &[(String, String)],
+        freshness: Freshness,
+    ) {
         // Zip the content
         let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-        encoder.write_all(content.as_bytes()).unwrap();
-        let bytes = encoder.finish().unwrap();
+        encoder.write_all(content).unwrap();
+        let entry = CacheEntry {
+            content: encoder.finish().unwrap(),
+            headers: headers.to_vec(),
+            freshness,
+        };
         // Add the content size to the current size of the cache system
-        self.cur_size += bytes.len() as u64;
-        self.cache.insert(path.to_string(), bytes);
+        self.cur_size += entry.size();
+        self.cache.insert(path.to_string(), entry);
     }
 
     // This function is used to add frequency of the given content.
@@ -149,13 +309,138 @@ impl CacheSystem {
             }
         }
     }
+}
 
-    // This function is used to check if the content exists in the cahce system.
-    pub fn contains_key(&self, path: &str) -> bool {
-        if self.cache.contains_key(path) {
-            true
-        } else {
-            false
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn header_map(headers: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers.iter() {
+            map.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
         }
+        map
+    }
+
+    fn headers(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    // This function stores a content with the given origin headers, for a request with the given headers
+    fn add(cache: &mut CacheSystem, path: &str, request: &[(&str, &str)], content: &[u8], origin: &[(&str, &str)]) {
+        let origin = headers(origin);
+        cache.add(path, &header_map(request), content, &origin, Freshness::from_headers(&origin));
+    }
+
+    const FRESH: [(&str, &str); 1] = [("cache-control", "max-age=60")];
+
+    #[test]
+    fn stores_binary_content_compressed() {
+        let mut cache = CacheSystem::new(1_000_000);
+        let content: Vec<u8> = (0..=255).cycle().take(4096).collect();
+        add(&mut cache, "/image.png", &[], &content, &FRESH);
+
+        let (unzipped, stored_headers, _) = cache.get("/image.png", &HeaderMap::new(), false).unwrap();
+        assert_eq!(unzipped, content);
+        assert_eq!(stored_headers, headers(&FRESH));
+
+        let (zipped, _, _) = cache.get("/image.png", &HeaderMap::new(), true).unwrap();
+        let mut decoded = Vec::new();
+        GzDecoder::new(zipped.as_slice()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, content);
+        assert!(cache.get("/other.png", &HeaderMap::new(), false).is_none());
+    }
+
+    #[test]
+    fn uncacheable_responses_replace_nothing() {
+        let mut cache = CacheSystem::new(1_000_000);
+        add(&mut cache, "/page", &[], b"v1", &FRESH);
+        add(&mut cache, "/page", &[], b"v2", &[("cache-control", "no-store")]);
+        assert!(cache.get("/page", &HeaderMap::new(), false).is_none());
+        assert_eq!(cache.cur_size, 0);
+    }
+
+    #[test]
+    fn stale_entries_are_revalidated() {
+        let mut cache = CacheSystem::new(1_000_000);
+        let origin = [("cache-control", "max-age=0"), ("etag", "\"v1\""), ("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT")];
+        add(&mut cache, "/page", &[], b"body", &origin);
+        assert!(cache.get("/page", &HeaderMap::new(), false).is_none());
+
+        let conditions = cache.validators("/page", &HeaderMap::new());
+        assert_eq!(
+            conditions,
+            vec![("If-None-Match", "\"v1\"".to_string()), ("If-Modified-Since", "Wed, 21 Oct 2015 07:28:00 GMT".to_string())]
+        );
+
+        let not_modified = header_map(&[("cache-control", "max-age=60")]);
+        let (content, refreshed, _) = cache.revalidate("/page", &HeaderMap::new(), &not_modified).unwrap();
+        assert_eq!(content, b"body");
+        assert!(refreshed.contains(&("cache-control".to_string(), "max-age=60".to_string())));
+        assert!(refreshed.contains(&("etag".to_string(), "\"v1\"".to_string())));
+        assert!(cache.get("/page", &HeaderMap::new(), false).is_some());
+        assert!(cache.revalidate("/other", &HeaderMap::new(), &not_modified).is_none());
+    }
+
+    #[test]
+    fn generated_etags_are_not_sent_to_the_origin() {
+        let mut cache = CacheSystem::new(1_000_000);
+        add(&mut cache, "/page", &[], b"body", &[("cache-control", "max-age=0"), ("etag", "\"sha256-00\"")]);
+        assert!(cache.validators("/page", &HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn variants_are_keyed_by_the_vary_headers() {
+        let mut cache = CacheSystem::new(1_000_000);
+        let vary = [("cache-control", "max-age=60"), ("vary", "Accept-Language, Accept-Encoding")];
+        add(&mut cache, "/page", &[("accept-language", "en")], b"hello", &vary);
+        add(&mut cache, "/page", &[("accept-language", "fr")], b"bonjour", &vary);
+
+        let get = |cache: &mut CacheSystem, language: &str| {
+            let request = header_map(&[("accept-language", language), ("accept-encoding", "gzip")]);
+            cache.get("/page", &request, false).map(|(content, _, _)| content)
+        };
+        assert_eq!(get(&mut cache, "en"), Some(b"hello".to_vec()));
+        assert_eq!(get(&mut cache, "fr"), Some(b"bonjour".to_vec()));
+        assert_eq!(get(&mut cache, "de"), None);
+        // Accept-Encoding isn't part of the key
+        assert_eq!(cache.cache_key("/page", &header_map(&[("accept-language", "en")])), "/page\naccept-language: en");
+    }
+
+    #[test]
+    fn a_new_vary_deletes_the_previous_variants() {
+        let mut cache = CacheSystem::new(1_000_000);
+        let by_language = [("cache-control", "max-age=60"), ("vary", "Accept-Language")];
+        add(&mut cache, "/page", &[("accept-language", "en")], b"hello", &by_language);
+        add(&mut cache, "/page", &[("accept-language", "fr")], b"bonjour", &by_language);
+        add(&mut cache, "/pages", &[], b"other", &FRESH);
+
+        add(&mut cache, "/page", &[("cookie", "a")], b"plain", &FRESH);
+        let keys: Vec<&String> = cache.cache.keys().collect();
+        assert_eq!(keys.len(), 2);
+        assert!(cache.cache.contains_key("/page") && cache.cache.contains_key("/pages"));
+        let sizes: u64 = cache.cache.values().map(|entry| entry.size()).sum();
+        assert_eq!(cache.cur_size, sizes);
+    }
+
+    #[test]
+    fn evicts_the_least_frequent_content_when_full() {
+        let content: Vec<u8> = (0..4000_u32).flat_map(|i| i.wrapping_mul(2_654_435_761).to_be_bytes()).collect();
+        let mut cache = CacheSystem::new(20_000);
+        add(&mut cache, "/popular", &[], &content, &FRESH);
+        for _ in 0..5 {
+            cache.get("/popular", &HeaderMap::new(), false);
+        }
+        add(&mut cache, "/rare", &[], &content, &FRESH);
+        assert!(cache.cur_size >= cache.max_size);
+
+        // The second request of the new content makes it more frequent than the rare one
+        add(&mut cache, "/new", &[], &content, &FRESH);
+        add(&mut cache, "/new", &[], &content, &FRESH);
+        assert!(cache.cache.contains_key("/popular"));
+        assert!(!cache.cache.contains_key("/rare"));
+        assert!(cache.cache.contains_key("/new"));
     }
 }
@@ -1,4 +1,5 @@
-use clap::Parser;
+use super::cache_system::CACHE_CAPACITY;
+use clap::{Parser, ValueEnum};
 
 #[derive(Parser, Debug)]
 #[command(version, long_about = None)]
@@ -12,6 +13,39 @@ pub struct Cli {
     /// Origin domain/IP address where this server fetch the contents
     #[arg(short, default_value_t = {"cs5700cdnorigin.ccs.neu.edu".to_string()})]
     pub origin: String,
+
+    /// Sort the query parameters by name in cache keys, so "?a=1&b=2" and "?b=2&a=1" share an entry
+    #[arg(long)]
+    pub sort_query: bool,
+
+    /// Query parameters left out of cache keys (a trailing * matches a prefix), comma-separated
+    #[arg(long, value_delimiter = ',', default_values_t = ["utm_*".to_string(), "gclid".to_string(), "fbclid".to_string()])]
+    pub ignore_param: Vec<String>,
+
+    /// Compare paths and query parameter names case-insensitively in cache keys
+    #[arg(long)]
+    pub ignore_case: bool,
+
+    /// Seconds a client waits for the origin fetch of a missed content before getting 504
+    #[arg(long, default_value_t = 20)]
+    pub coalesce_timeout: u64,
+
+    /// Largest content stored in the cache, in bytes, at most the cache capacity. Larger contents are streamed
+    /// to the client without being cached.
+    #[arg(long, default_value_t = CACHE_CAPACITY as usize)]
+    pub max_object_size: usize,
+
+    /// How a range request for a content that isn't cached is answered: fetch the whole content for the cache
+    /// and cut the range from it, or forward the range to the origin
+    #[arg(long, value_enum, default_value_t = RangeMiss::Fetch)]
+    pub range_miss: RangeMiss,
+}
+
+// Define the RangeMiss enum: how a range request for a content that isn't cached is answered
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum RangeMiss {
+    Fetch,
+    Forward,
 }
 
 // This funtion is used to check if the given port number is valid.
@@ -0,0 +1,160 @@
+use actix_web::http::header::{HeaderMap, HttpDate};
+use openssl::sha::sha256;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+// Prefix of the ETags we generate, so they are never sent to the origin as validators
+const GENERATED_ETAG_PREFIX: &str = "\"sha256-";
+
+// Headers sent with a 304 Not Modified (RFC 9110 section 15.4.5)
+const NOT_MODIFIED_HEADERS: [&str; 6] = [
+    "cache-control",
+    "content-location",
+    "etag",
+    "expires",
+    "last-modified",
+    "vary",
+];
+
+// This function is used to add a strong ETag made of a hash of the content, when the origin didn't send one.
+// It needs the whole content, so it's added to the cached copy: the response streamed from the origin has no ETag.
+pub fn with_etag(mut headers: Vec<(String, String)>, content: &[u8]) -> Vec<(String, String)> {
+    if !headers.iter().any(|(name, _)| name == "etag") {
+        let hash: String = sha256(content)[..16]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        headers.push(("etag".to_string(), format!("{}{}\"", GENERATED_ETAG_PREFIX, hash)));
+    }
+    headers
+}
+
+// This function is used to check if an ETag was generated by us rather than by the origin
+pub fn is_generated_etag(etag: &str) -> bool {
+    etag.starts_with(GENERATED_ETAG_PREFIX)
+}
+
+// This function is used to evaluate the conditional headers of a client request against the content's headers
+// (RFC 9110 section 13.2.2). If-None-Match is used when present, and If-Modified-Since otherwise.
+pub fn is_not_modified(request: &HeaderMap, headers: &[(String, String)]) -> bool {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let if_none_match: Vec<&str> = request
+        .get_all("if-none-match")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    if !if_none_match.is_empty() {
+        // Weak comparison: W/"x" matches "x"
+        let opaque = |tag: &str| tag.trim_start_matches("W/").to_string();
+        return match header("etag") {
+            Some(etag) => if_none_match
+                .iter()
+                .any(|tag| *tag == "*" || opaque(tag) == opaque(etag)),
+            None => if_none_match.contains(&"*"),
+        };
+    }
+
+    let date = |value: &str| HttpDate::from_str(value.trim()).ok().map(SystemTime::from);
+    let if_modified_since = request
+        .get("if-modified-since")
+        .and_then(|value| value.to_str().ok())
+        .and_then(date);
+    match (if_modified_since, header("last-modified").and_then(date)) {
+        (Some(since), Some(last_modified)) => last_modified <= since,
+        _ => false,
+    }
+}
+
+// This function is used to keep the headers sent with a 304 Not Modified
+pub fn not_modified_headers(headers: &[(String, String)]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| NOT_MODIFIED_HEADERS.contains(&name.as_str()))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    const LAST_MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+    fn request(headers: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers.iter() {
+            map.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    fn content(etag: Option<&str>) -> Vec<(String, String)> {
+        let mut headers = vec![("last-modified".to_string(), LAST_MODIFIED.to_string())];
+        headers.extend(etag.map(|etag| ("etag".to_string(), etag.to_string())));
+        headers
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+        let matches = |if_none_match: &str, etag: Option<&str>| {
+            is_not_modified(&request(&[("if-none-match", if_none_match)]), &content(etag))
+        };
+        assert!(matches("\"a\"", Some("\"a\"")));
+        assert!(matches("W/\"a\"", Some("\"a\"")));
+        assert!(matches("\"a\"", Some("W/\"a\"")));
+        assert!(matches("\"x\", \"a\"", Some("\"a\"")));
+        assert!(!matches("\"b\"", Some("\"a\"")));
+        assert!(!matches("\"a\"", None));
+    }
+
+    #[test]
+    fn if_none_match_star_matches_any_content() {
+        let star = request(&[("if-none-match", "*")]);
+        assert!(is_not_modified(&star, &content(Some("\"a\""))));
+        assert!(is_not_modified(&star, &content(None)));
+    }
+
+    #[test]
+    fn if_modified_since_is_only_used_without_if_none_match() {
+        let since = |date: &str| request(&[("if-modified-since", date)]);
+        assert!(is_not_modified(&since(LAST_MODIFIED), &content(None)));
+        assert!(is_not_modified(&since("Thu, 22 Oct 2015 07:28:00 GMT"), &content(None)));
+        assert!(!is_not_modified(&since("Tue, 20 Oct 2015 07:28:00 GMT"), &content(None)));
+        assert!(!is_not_modified(&since("yesterday"), &content(None)));
+
+        let both = request(&[("if-none-match", "\"b\""), ("if-modified-since", LAST_MODIFIED)]);
+        assert!(!is_not_modified(&both, &content(Some("\"a\""))));
+    }
+
+    #[test]
+    fn generates_a_strong_etag_only_when_missing() {
+        let generated = with_etag(vec![], b"hello");
+        let etag = &generated[0].1;
+        assert!(is_generated_etag(etag));
+        assert!(etag.ends_with('"') && !etag.starts_with("W/"));
+        assert_eq!(with_etag(vec![], b"hello"), generated);
+        assert_ne!(with_etag(vec![], b"other"), generated);
+
+        let origin = content(Some("\"origin\""));
+        assert_eq!(with_etag(origin.clone(), b"hello"), origin);
+        assert!(!is_generated_etag("\"origin\""));
+    }
+
+    #[test]
+    fn keeps_the_304_headers() {
+        let mut headers = content(Some("\"a\""));
+        headers.push(("content-type".to_string(), "text/plain".to_string()));
+        headers.push(("cache-control".to_string(), "max-age=60".to_string()));
+        let kept: Vec<String> = not_modified_headers(&headers).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(kept, vec!["last-modified", "etag", "cache-control"]);
+    }
+}
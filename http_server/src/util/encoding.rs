@@ -0,0 +1,112 @@
+use actix_web::http::header::HeaderMap;
+
+// This function is used to check if a client accepts gzip content (RFC 9110 section 12.5.3).
+// A coding with q=0 is refused, and * stands for every coding not listed.
+pub fn accepts_gzip(request: &HeaderMap) -> bool {
+    let mut gzip = None;
+    let mut any = None;
+    for coding in request
+        .get_all("accept-encoding")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+    {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let accepted = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .all(|q| q.trim().parse::<f32>().is_ok_and(|q| q > 0.0));
+        match name.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(accepted),
+            "*" => any = Some(accepted),
+            _ => {}
+        }
+    }
+    gzip.or(any).unwrap_or(false)
+}
+
+// This function is used to get the headers of the gzip content. Its ETag is made weak, because a strong ETag
+// identifies the bytes of the uncompressed content.
+pub fn gzip_headers(headers: &[(String, String)]) -> Vec<(String, String)> {
+    let mut zipped: Vec<(String, String)> = headers
+        .iter()
+        .map(|(name, value)| match name.as_str() {
+            "etag" if !value.starts_with("W/") => (name.clone(), format!("W/{}", value)),
+            _ => (name.clone(), value.clone()),
+        })
+        .collect();
+    zipped.push(("content-encoding".to_string(), "gzip".to_string()));
+    zipped
+}
+
+// This function is used to add Accept-Encoding to the Vary header, since the same content is sent zipped or not
+pub fn vary_on_encoding(headers: &[(String, String)]) -> Vec<(String, String)> {
+    let mut headers = headers.to_vec();
+    let listed = headers
+        .iter()
+        .filter(|(name, _)| name == "vary")
+        .flat_map(|(_, value)| value.split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case("accept-encoding") || name.trim() == "*");
+    if !listed {
+        headers.push(("vary".to_string(), "Accept-Encoding".to_string()));
+    }
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn accepts(accept_encoding: &[&str]) -> bool {
+        let mut map = HeaderMap::new();
+        for value in accept_encoding.iter() {
+            map.append(HeaderName::from_static("accept-encoding"), HeaderValue::from_str(value).unwrap());
+        }
+        accepts_gzip(&map)
+    }
+
+    #[test]
+    fn q_zero_refuses_a_coding() {
+        assert!(accepts(&["gzip, deflate"]));
+        assert!(accepts(&["br;q=1.0, GZIP;q=0.5"]));
+        assert!(accepts(&["deflate", "x-gzip"]));
+        assert!(!accepts(&["gzip;q=0"]));
+        assert!(!accepts(&["gzip; q=0.0, br"]));
+        assert!(!accepts(&["gzip;q=abc"]));
+        assert!(!accepts(&[]));
+        assert!(!accepts(&["identity"]));
+    }
+
+    #[test]
+    fn star_stands_for_the_codings_not_listed() {
+        assert!(accepts(&["*"]));
+        assert!(!accepts(&["*;q=0"]));
+        // gzip is listed, so * doesn't apply to it
+        assert!(!accepts(&["gzip;q=0, *"]));
+        assert!(accepts(&["gzip, *;q=0"]));
+    }
+
+    #[test]
+    fn gzip_headers_make_the_etag_weak() {
+        let headers = vec![("etag".to_string(), "\"a\"".to_string())];
+        let zipped = gzip_headers(&headers);
+        assert!(zipped.contains(&("etag".to_string(), "W/\"a\"".to_string())));
+        assert!(zipped.contains(&("content-encoding".to_string(), "gzip".to_string())));
+        let weak = vec![("etag".to_string(), "W/\"a\"".to_string())];
+        assert_eq!(gzip_headers(&weak)[0].1, "W/\"a\"");
+    }
+
+    #[test]
+    fn vary_lists_accept_encoding_once() {
+        let vary = |headers: Vec<(String, String)>| -> Vec<String> {
+            vary_on_encoding(&headers).into_iter().filter(|(name, _)| name == "vary").map(|(_, value)| value).collect()
+        };
+        assert_eq!(vary(vec![]), vec!["Accept-Encoding"]);
+        let listed = vec![("vary".to_string(), "Cookie, accept-encoding".to_string())];
+        assert_eq!(vary(listed), vec!["Cookie, accept-encoding"]);
+        let cookie = vec![("vary".to_string(), "Cookie".to_string())];
+        assert_eq!(vary(cookie), vec!["Cookie", "Accept-Encoding"]);
+        let star = vec![("vary".to_string(), "*".to_string())];
+        assert_eq!(vary(star), vec!["*"]);
+    }
+}
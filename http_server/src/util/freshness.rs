@@ -0,0 +1,221 @@
+use actix_web::http::header::HttpDate;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+// Share of the time since Last-Modified used as heuristic freshness (RFC 9111 section 4.2.2)
+const HEURISTIC_FRACTION: f64 = 0.1;
+// Heuristic freshness is capped, so content that didn't change for years is still checked daily
+const MAX_HEURISTIC_LIFETIME: Duration = Duration::from_secs(86_400);
+
+// Define the Freshness struct: how long a response may be served from the cache (RFC 9111 section 4.2)
+#[derive(Clone, Copy, Debug)]
+pub struct Freshness {
+    // Freshness lifetime given by the origin, or computed heuristically
+    pub lifetime: Duration,
+    // Age of the response when it was received: the Age header, or the time since its Date
+    pub initial_age: Duration,
+    // When the response was received
+    pub received: SystemTime,
+    // no-cache: the response may be stored, but must be validated with the origin before each use
+    pub no_cache: bool,
+}
+
+impl Freshness {
+    // This function is used to compute the freshness of a response from its headers, when it's received
+    pub fn from_headers(headers: &[(String, String)]) -> Self {
+        let received = SystemTime::now();
+        let directives = cache_control(headers);
+        let date = header_date(headers, "date").unwrap_or(received);
+
+        // Age (section 4.2.3): the larger of the Age header and the apparent age
+        let age_header = header(headers, "age")
+            .and_then(|age| age.trim().parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        let apparent_age = received.duration_since(date).unwrap_or_default();
+        let initial_age = age_header.max(apparent_age);
+
+        // Lifetime (section 4.2.1): s-maxage, then max-age, then Expires, then the heuristic
+        let seconds = |name: &str| {
+            directives
+                .iter()
+                .find(|(directive, _)| directive == name)
+                .and_then(|(_, value)| value.parse::<u64>().ok())
+                .map(Duration::from_secs)
+        };
+        let lifetime = if let Some(lifetime) = seconds("s-maxage").or_else(|| seconds("max-age")) {
+            lifetime
+        } else if let Some(expires) = header(headers, "expires") {
+            // An invalid Expires, such as "0", means already expired
+            match HttpDate::from_str(expires.trim()) {
+                Ok(expires) => SystemTime::from(expires).duration_since(date).unwrap_or_default(),
+                Err(_) => Duration::ZERO,
+            }
+        } else if let Some(last_modified) = header_date(headers, "last-modified") {
+            let unchanged = date.duration_since(last_modified).unwrap_or_default();
+            unchanged.mul_f64(HEURISTIC_FRACTION).min(MAX_HEURISTIC_LIFETIME)
+        } else {
+            Duration::ZERO
+        };
+
+        let has = |name: &str| directives.iter().any(|(directive, _)| directive == name);
+        Freshness {
+            lifetime,
+            initial_age,
+            received,
+            no_cache: has("no-cache"),
+        }
+    }
+
+    // This function is used to get the current age of the response
+    pub fn age(&self) -> Duration {
+        self.initial_age + self.received.elapsed().unwrap_or_default()
+    }
+
+    // This function is used to check if the response can be served without asking the origin.
+    // Stale responses are never served, so must-revalidate and proxy-revalidate always hold.
+    pub fn is_fresh(&self) -> bool {
+        !self.no_cache && self.age() < self.lifetime
+    }
+}
+
+// This function is used to check if a shared cache may store a response (RFC 9111 section 3):
+// not when the origin forbids it, and not when Vary makes every request different
+pub fn is_storable(headers: &[(String, String)]) -> bool {
+    let directives = cache_control(headers);
+    let forbidden = directives
+        .iter()
+        .any(|(directive, _)| directive == "no-store" || directive == "private");
+    let vary_all = vary_headers(headers).iter().any(|name| name == "*");
+    !forbidden && !vary_all
+}
+
+// This function is used to get the request headers a response varies on, in lowercase
+pub fn vary_headers(headers: &[(String, String)]) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(name, _)| name == "vary")
+        .flat_map(|(_, value)| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+// This function is used to parse the Cache-Control directives of a response, as lowercase names and unquoted values
+fn cache_control(headers: &[(String, String)]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| name == "cache-control")
+        .flat_map(|(_, value)| value.split(','))
+        .filter_map(|directive| {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            let name = name.trim().to_ascii_lowercase();
+            match name.is_empty() {
+                true => None,
+                false => Some((name, value.trim().trim_matches('"').to_string())),
+            }
+        })
+        .collect()
+}
+
+// This function is used to get the value of a header
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header == name)
+        .map(|(_, value)| value.as_str())
+}
+
+// This function is used to get the value of a date header
+fn header_date(headers: &[(String, String)], name: &str) -> Option<SystemTime> {
+    let date = HttpDate::from_str(header(headers, name)?.trim()).ok()?;
+    Some(SystemTime::from(date))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    // This function formats a date the given number of seconds from now (in the past when negative)
+    fn date(offset_secs: i64) -> String {
+        let now = SystemTime::now();
+        let time = match offset_secs >= 0 {
+            true => now + Duration::from_secs(offset_secs as u64),
+            false => now - Duration::from_secs(offset_secs.unsigned_abs()),
+        };
+        HttpDate::from(time).to_string()
+    }
+
+    #[test]
+    fn cache_control_gives_the_lifetime() {
+        let max_age = Freshness::from_headers(&headers(&[("cache-control", "public, max-age=60")]));
+        assert_eq!(max_age.lifetime, Duration::from_secs(60));
+        assert!(max_age.is_fresh());
+
+        // s-maxage wins over max-age in a shared cache, and over Expires
+        let expires = date(3600);
+        let shared = Freshness::from_headers(&headers(&[
+            ("cache-control", "max-age=60, s-maxage=\"120\""),
+            ("expires", &expires),
+        ]));
+        assert_eq!(shared.lifetime, Duration::from_secs(120));
+
+        let no_cache = Freshness::from_headers(&headers(&[("cache-control", "no-cache, max-age=60")]));
+        assert!(no_cache.no_cache && !no_cache.is_fresh());
+        assert!(!Freshness::from_headers(&headers(&[("cache-control", "max-age=0")])).is_fresh());
+    }
+
+    #[test]
+    fn age_comes_from_the_age_header_or_the_date() {
+        let aged = Freshness::from_headers(&headers(&[("cache-control", "max-age=60"), ("age", "100")]));
+        assert!(aged.initial_age >= Duration::from_secs(100));
+        assert!(!aged.is_fresh());
+
+        let dated = date(-30);
+        let old = Freshness::from_headers(&headers(&[("cache-control", "max-age=60"), ("date", &dated)]));
+        assert!(old.initial_age >= Duration::from_secs(29) && old.initial_age <= Duration::from_secs(31));
+        assert!(old.is_fresh());
+    }
+
+    #[test]
+    fn expires_is_relative_to_the_date() {
+        let (now, expires) = (date(0), date(600));
+        let fresh = Freshness::from_headers(&headers(&[("date", &now), ("expires", &expires)]));
+        assert!(fresh.lifetime >= Duration::from_secs(599) && fresh.lifetime <= Duration::from_secs(601));
+        assert!(fresh.is_fresh());
+
+        // An invalid or past Expires means already expired
+        assert!(!Freshness::from_headers(&headers(&[("expires", "0")])).is_fresh());
+        let past = date(-60);
+        assert!(!Freshness::from_headers(&headers(&[("expires", &past)])).is_fresh());
+    }
+
+    #[test]
+    fn heuristic_lifetime_is_a_capped_share_of_the_time_since_last_modified() {
+        let (now, ten_hours_ago) = (date(0), date(-36_000));
+        let recent = Freshness::from_headers(&headers(&[("date", &now), ("last-modified", &ten_hours_ago)]));
+        assert!(recent.lifetime >= Duration::from_secs(3599) && recent.lifetime <= Duration::from_secs(3601));
+
+        let year_ago = date(-365 * 86_400);
+        let old = Freshness::from_headers(&headers(&[("date", &now), ("last-modified", &year_ago)]));
+        assert_eq!(old.lifetime, MAX_HEURISTIC_LIFETIME);
+
+        assert_eq!(Freshness::from_headers(&[]).lifetime, Duration::ZERO);
+    }
+
+    #[test]
+    fn storability_and_vary() {
+        assert!(is_storable(&headers(&[("cache-control", "public, max-age=60")])));
+        assert!(!is_storable(&headers(&[("cache-control", "No-Store")])));
+        assert!(!is_storable(&headers(&[("cache-control", "private")])));
+        assert!(!is_storable(&headers(&[("vary", "Accept-Language, *")])));
+
+        let vary = headers(&[("vary", "Accept-Language, Cookie"), ("vary", "X-Device")]);
+        assert_eq!(vary_headers(&vary), vec!["accept-language", "cookie", "x-device"]);
+        assert!(is_storable(&vary));
+    }
+}
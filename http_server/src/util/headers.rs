@@ -0,0 +1,99 @@
+use actix_web::http::header::HeaderMap;
+
+// Hop-by-hop headers (RFC 9110 section 7.6.1): they only describe the connection to the origin
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// Headers we don't replay: the body is decompressed and re-framed by us, actix sets its own Date,
+// we send our own Age, and cookies of one client must never be served to another from the cache
+const NOT_REPLAYED: [&str; 5] = ["content-length", "content-encoding", "date", "age", "set-cookie"];
+
+// This function is used to get all the headers of a response, with lowercase names.
+pub fn header_list(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str().to_ascii_lowercase(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+// This function is used to keep the origin headers that can be stored with the content and sent to clients.
+// Hop-by-hop headers are removed, including the ones named in the Connection header.
+pub fn replayable_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    let connection_options: Vec<String> = headers
+        .get_all("connection")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .collect();
+
+    header_list(headers)
+        .into_iter()
+        .filter(|(name, _)| {
+            !HOP_BY_HOP.contains(&name.as_str())
+                && !NOT_REPLAYED.contains(&name.as_str())
+                && !connection_options.contains(name)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn response(headers: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers.iter() {
+            map.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    fn names(headers: Vec<(String, String)>) -> Vec<String> {
+        let mut names: Vec<String> = headers.into_iter().map(|(name, _)| name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn lists_every_header_in_lowercase() {
+        let headers = response(&[("Content-Type", "text/html"), ("X-Tag", "a"), ("x-tag", "b")]);
+        let mut list = header_list(&headers);
+        list.sort();
+        assert_eq!(
+            list,
+            vec![
+                ("content-type".to_string(), "text/html".to_string()),
+                ("x-tag".to_string(), "a".to_string()),
+                ("x-tag".to_string(), "b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_hop_by_hop_and_framing_headers() {
+        let headers = response(&[
+            ("content-type", "image/png"),
+            ("etag", "\"a\""),
+            ("connection", "keep-alive, X-Internal"),
+            ("keep-alive", "timeout=5"),
+            ("transfer-encoding", "chunked"),
+            ("x-internal", "secret"),
+            ("content-length", "10"),
+            ("content-encoding", "gzip"),
+            ("set-cookie", "session=1"),
+            ("age", "3"),
+            ("date", "Wed, 21 Oct 2015 07:28:00 GMT"),
+        ]);
+        assert_eq!(names(replayable_headers(&headers)), vec!["content-type", "etag"]);
+    }
+}
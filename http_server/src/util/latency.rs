@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Weight of a new sample in the moving average
+const SMOOTHING: f64 = 0.2;
+// Prefixes without a sample for this long are dropped
+const ENTRY_TTL: Duration = Duration::from_secs(3600);
+// Maximum number of client prefixes tracked
+const MAX_PREFIXES: usize = 100_000;
+
+struct LatencyEntry {
+    // Number of samples seen for the prefix
+    samples: u32,
+    // Smoothed RTT in milliseconds
+    rtt_ms: f64,
+    last_seen: Instant,
+}
+
+// Define the LatencyTable struct: the RTT of clients, aggregated per /24 (IPv4) or /48 (IPv6) prefix
+pub struct LatencyTable {
+    entries: HashMap<String, LatencyEntry>,
+}
+
+impl LatencyTable {
+    // This function is used to create an empty latency table
+    pub fn new() -> Self {
+        LatencyTable {
+            entries: HashMap::new(),
+        }
+    }
+
+    // This function is used to record one RTT sample of a client
+    pub fn record(&mut self, client_ip: IpAddr, rtt: Duration) {
+        let prefix = client_prefix(client_ip);
+        let rtt_ms = rtt.as_secs_f64() * 1000.0;
+
+        if !self.entries.contains_key(&prefix) && self.entries.len() >= MAX_PREFIXES {
+            self.remove_stale();
+            if self.entries.len() >= MAX_PREFIXES {
+                return;
+            }
+        }
+
+        let entry = self.entries.entry(prefix).or_insert(LatencyEntry {
+            samples: 0,
+            rtt_ms,
+            last_seen: Instant::now(),
+        });
+        entry.rtt_ms = entry.rtt_ms * (1.0 - SMOOTHING) + rtt_ms * SMOOTHING;
+        entry.samples = entry.samples.saturating_add(1);
+        entry.last_seen = Instant::now();
+    }
+
+    // This function is used to report the table, one "prefix samples rtt_ms" line per prefix
+    pub fn report(&mut self) -> String {
+        self.remove_stale();
+        let mut report = String::new();
+        for (prefix, entry) in self.entries.iter() {
+            report.push_str(&format!("{} {} {:.2}\n", prefix, entry.samples, entry.rtt_ms));
+        }
+        report
+    }
+
+    // This function is used to drop the prefixes that haven't been seen recently
+    fn remove_stale(&mut self) {
+        self.entries
+            .retain(|_, entry| entry.last_seen.elapsed() < ENTRY_TTL);
+    }
+}
+
+// This function returns the /24 (IPv4) or /48 (IPv6) prefix of an address
+fn client_prefix(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.0/24", o[0], o[1], o[2])
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2])
+        }
+    }
+}
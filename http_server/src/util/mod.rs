@@ -1,2 +1,11 @@
+pub mod cache_key;
 pub mod cache_system;
-pub mod cl_parser;
\ No newline at end of file
+pub mod cl_parser;
+pub mod conditional;
+pub mod encoding;
+pub mod freshness;
+pub mod headers;
+pub mod latency;
+pub mod range;
+pub mod single_flight;
+pub mod throughput;
\ No newline at end of file
@@ -0,0 +1,204 @@
+use actix_web::http::header::HeaderMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// More ranges than this in one request are answered with the whole content, so a client can't make us
+// build a huge multipart body out of a small content
+const MAX_RANGES: usize = 32;
+
+// Counter mixed into the multipart boundaries
+static BOUNDARIES: AtomicU64 = AtomicU64::new(0);
+
+// Define the Ranges enum: how a request with a Range header is answered (RFC 9110 section 14)
+pub enum Ranges {
+    // No Range header, an invalid one, a failed If-Range or too many ranges: the whole content is sent
+    Ignored,
+    // The satisfiable ranges, as first and last byte positions: a 206 Partial Content
+    Satisfiable(Vec<(usize, usize)>),
+    // None of the ranges is in the content: a 416 Range Not Satisfiable
+    Unsatisfiable,
+}
+
+// This function is used to resolve the Range header of a request against a content of the given length
+pub fn resolve(request: &HeaderMap, headers: &[(String, String)], length: usize) -> Ranges {
+    let range = match request.get("range").and_then(|value| value.to_str().ok()) {
+        Some(range) => range,
+        None => return Ranges::Ignored,
+    };
+    if !if_range_holds(request, headers) {
+        return Ranges::Ignored;
+    }
+    let specs = match range.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return Ranges::Ignored,
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(|spec| spec.trim()).filter(|spec| !spec.is_empty()) {
+        let (first, last) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return Ranges::Ignored,
+        };
+        let range = match (first.parse::<usize>(), last.parse::<usize>()) {
+            // "first-last" and "first-": up to the end when last is past it
+            (Ok(first), Ok(last)) if first <= last => Some((first, last.min(length.saturating_sub(1)))),
+            (Ok(first), Err(_)) if last.is_empty() => Some((first, length.saturating_sub(1))),
+            // "-suffix": the last bytes of the content
+            (Err(_), Ok(suffix)) if first.is_empty() => match suffix {
+                0 => None,
+                _ => Some((length.saturating_sub(suffix), length.saturating_sub(1))),
+            },
+            _ => return Ranges::Ignored,
+        };
+        if let Some((first, last)) = range {
+            if first < length {
+                ranges.push((first, last));
+            }
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        Ranges::Ignored
+    } else if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Satisfiable(ranges)
+    }
+}
+
+// This function is used to check the If-Range header (RFC 9110 section 13.1.5): the ranges are only sent
+// when the client's copy is still the content, by strong comparison of the ETag or the exact Last-Modified date
+fn if_range_holds(request: &HeaderMap, headers: &[(String, String)]) -> bool {
+    let if_range = match request.get("if-range").and_then(|value| value.to_str().ok()) {
+        Some(if_range) => if_range.trim(),
+        None => return true,
+    };
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.trim())
+    };
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        // Weak ETags never match
+        !if_range.starts_with("W/") && header("etag") == Some(if_range)
+    } else {
+        header("last-modified") == Some(if_range)
+    }
+}
+
+// This function is used to build the body of a multipart/byteranges response (RFC 9110 section 14.6).
+// It returns the boundary and the body.
+pub fn multipart(content: &[u8], ranges: &[(usize, usize)], content_type: Option<&str>) -> (String, Vec<u8>) {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let boundary = format!("{:016x}{:08x}", nanos, BOUNDARIES.fetch_add(1, Ordering::Relaxed));
+
+    let mut body = Vec::new();
+    for (first, last) in ranges.iter() {
+        body.extend_from_slice(format!("\r\n--{}\r\n", boundary).as_bytes());
+        if let Some(content_type) = content_type {
+            body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+        }
+        let range = content_range(*first, *last, content.len());
+        body.extend_from_slice(format!("Content-Range: {}\r\n\r\n", range).as_bytes());
+        body.extend_from_slice(&content[*first..=*last]);
+    }
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    (boundary, body)
+}
+
+// This function is used to format the Content-Range of a range
+pub fn content_range(first: usize, last: usize, length: usize) -> String {
+    format!("bytes {}-{}/{}", first, last, length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn request(headers: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers.iter() {
+            map.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    fn ranges(range: &str, length: usize) -> Ranges {
+        resolve(&request(&[("range", range)]), &[], length)
+    }
+
+    fn satisfiable(ranges: Ranges) -> Vec<(usize, usize)> {
+        match ranges {
+            Ranges::Satisfiable(ranges) => ranges,
+            _ => panic!("expected satisfiable ranges"),
+        }
+    }
+
+    #[test]
+    fn resolves_closed_open_ended_and_suffix_ranges() {
+        assert_eq!(satisfiable(ranges("bytes=0-9", 100)), vec![(0, 9)]);
+        assert_eq!(satisfiable(ranges("bytes=90-", 100)), vec![(90, 99)]);
+        assert_eq!(satisfiable(ranges("bytes=-10", 100)), vec![(90, 99)]);
+        // A suffix longer than the content is the whole content
+        assert_eq!(satisfiable(ranges("bytes=-500", 100)), vec![(0, 99)]);
+        // The last position is cut at the end of the content
+        assert_eq!(satisfiable(ranges("bytes=95-200", 100)), vec![(95, 99)]);
+        assert_eq!(satisfiable(ranges("bytes=0-0, 5-9", 100)), vec![(0, 0), (5, 9)]);
+    }
+
+    #[test]
+    fn ignores_invalid_ranges() {
+        assert!(matches!(resolve(&request(&[]), &[], 100), Ranges::Ignored));
+        assert!(matches!(ranges("bytes=9-0", 100), Ranges::Ignored));
+        assert!(matches!(ranges("items=0-9", 100), Ranges::Ignored));
+        assert!(matches!(ranges("bytes=abc", 100), Ranges::Ignored));
+        assert!(matches!(ranges("bytes=-", 100), Ranges::Ignored));
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert!(matches!(ranges("bytes=100-", 100), Ranges::Unsatisfiable));
+        assert!(matches!(ranges("bytes=-0", 100), Ranges::Unsatisfiable));
+        // Only the satisfiable ranges are kept
+        assert_eq!(satisfiable(ranges("bytes=200-300, 0-1", 100)), vec![(0, 1)]);
+    }
+
+    #[test]
+    fn too_many_ranges_get_the_whole_content() {
+        let specs: Vec<String> = (0..=MAX_RANGES).map(|i| format!("{}-{}", i, i)).collect();
+        assert!(matches!(ranges(&format!("bytes={}", specs.join(",")), 100), Ranges::Ignored));
+        let specs = &specs[..MAX_RANGES];
+        assert_eq!(satisfiable(ranges(&format!("bytes={}", specs.join(",")), 100)).len(), MAX_RANGES);
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_etag_or_the_exact_date() {
+        let headers = vec![
+            ("etag".to_string(), "\"v1\"".to_string()),
+            ("last-modified".to_string(), "Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+        ];
+        let resolve_if = |if_range: &str| resolve(&request(&[("range", "bytes=0-0"), ("if-range", if_range)]), &headers, 10);
+
+        assert!(matches!(resolve_if("\"v1\""), Ranges::Satisfiable(_)));
+        assert!(matches!(resolve_if("\"v2\""), Ranges::Ignored));
+        assert!(matches!(resolve_if("W/\"v1\""), Ranges::Ignored));
+        assert!(matches!(resolve_if("Wed, 21 Oct 2015 07:28:00 GMT"), Ranges::Satisfiable(_)));
+        assert!(matches!(resolve_if("Thu, 22 Oct 2015 07:28:00 GMT"), Ranges::Ignored));
+    }
+
+    #[test]
+    fn builds_a_multipart_body() {
+        let (boundary, body) = multipart(b"0123456789", &[(0, 1), (8, 9)], Some("text/plain"));
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(String::from_utf8(body).unwrap(), expected);
+    }
+}
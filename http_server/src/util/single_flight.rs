@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+
+// Define the Flight enum: what a request for a key does when it misses the cache
+pub enum Flight<T: 'static> {
+    // The first request of the key fetches it, and sends the result to the others
+    Leader(Leader<T>),
+    // The other requests wait for the result of the leader
+    Follower(watch::Receiver<Option<T>>),
+}
+
+// Define the Outcome enum: how waiting for the leader ended
+pub enum Outcome<T> {
+    // The leader sent its result
+    Done(T),
+    // The leader gave up without a result (it panicked)
+    Abandoned,
+    // The leader didn't finish in time
+    TimedOut,
+}
+
+// Define the SingleFlight struct: the fetches in progress, so concurrent misses of the same key
+// only send one request to the origin.
+pub struct SingleFlight<T> {
+    flights: Mutex<HashMap<String, watch::Receiver<Option<T>>>>,
+}
+
+// Define the Leader struct: the fetch of a key. The key is released when it's dropped, with or without a result.
+pub struct Leader<T: 'static> {
+    key: String,
+    sender: watch::Sender<Option<T>>,
+    flights: &'static SingleFlight<T>,
+}
+
+impl<T: Clone> SingleFlight<T> {
+    // This function is used to create an empty set of fetches
+    pub fn new() -> Self {
+        SingleFlight {
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // This function is used to join the fetch of a key, or to start it when there is none
+    pub fn join(&'static self, key: &str) -> Flight<T> {
+        let mut flights = self.flights.lock().unwrap();
+        if let Some(receiver) = flights.get(key) {
+            return Flight::Follower(receiver.clone());
+        }
+        let (sender, receiver) = watch::channel(None);
+        flights.insert(key.to_string(), receiver);
+        Flight::Leader(Leader {
+            key: key.to_string(),
+            sender,
+            flights: self,
+        })
+    }
+}
+
+impl<T> Leader<T> {
+    // This function is used to get a receiver of the result, for the leader to wait like the followers
+    pub fn subscribe(&self) -> watch::Receiver<Option<T>> {
+        self.sender.subscribe()
+    }
+
+    // This function is used to send the result to every waiting request
+    pub fn finish(self, result: T) {
+        self.sender.send_replace(Some(result));
+    }
+}
+
+impl<T> Drop for Leader<T> {
+    // The key is released, so the next miss starts a new fetch
+    fn drop(&mut self) {
+        self.flights.flights.lock().unwrap().remove(&self.key);
+    }
+}
+
+// This function is used to wait for the result of a fetch, for at most the given time
+pub async fn wait<T: Clone>(mut receiver: watch::Receiver<Option<T>>, timeout: Duration) -> Outcome<T> {
+    match tokio::time::timeout(timeout, receiver.wait_for(|result| result.is_some())).await {
+        Ok(Ok(result)) => Outcome::Done(result.clone().unwrap()),
+        Ok(Err(_)) => Outcome::Abandoned,
+        Err(_) => Outcome::TimedOut,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flights() -> &'static SingleFlight<u32> {
+        Box::leak(Box::new(SingleFlight::new()))
+    }
+
+    fn leader(flight: Flight<u32>) -> Leader<u32> {
+        match flight {
+            Flight::Leader(leader) => leader,
+            Flight::Follower(_) => panic!("expected the leader"),
+        }
+    }
+
+    fn follower(flight: Flight<u32>) -> watch::Receiver<Option<u32>> {
+        match flight {
+            Flight::Follower(receiver) => receiver,
+            Flight::Leader(_) => panic!("expected a follower"),
+        }
+    }
+
+    #[tokio::test]
+    async fn followers_get_the_result_of_the_leader() {
+        let flights = flights();
+        let first = leader(flights.join("a"));
+        let own = first.subscribe();
+        let second = follower(flights.join("a"));
+        // Another key has its own fetch
+        let _other = leader(flights.join("b"));
+
+        first.finish(7);
+        assert!(matches!(wait(second, Duration::from_secs(1)).await, Outcome::Done(7)));
+        assert!(matches!(wait(own, Duration::from_secs(1)).await, Outcome::Done(7)));
+        // The key is released, so the next miss fetches again
+        let _next = leader(flights.join("a"));
+    }
+
+    #[tokio::test]
+    async fn followers_see_an_abandoned_fetch() {
+        let flights = flights();
+        let first = leader(flights.join("a"));
+        let second = follower(flights.join("a"));
+        drop(first);
+        assert!(matches!(wait(second, Duration::from_secs(1)).await, Outcome::Abandoned));
+        let _next = leader(flights.join("a"));
+    }
+
+    #[tokio::test]
+    async fn followers_stop_waiting_after_the_timeout() {
+        let flights = flights();
+        let first = leader(flights.join("a"));
+        let second = follower(flights.join("a"));
+        assert!(matches!(wait(second, Duration::from_millis(10)).await, Outcome::TimedOut));
+        // The fetch is still in progress for the others
+        let third = follower(flights.join("a"));
+        first.finish(1);
+        assert!(matches!(wait(third, Duration::from_secs(1)).await, Outcome::Done(1)));
+    }
+}
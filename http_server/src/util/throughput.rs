@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Define the Throughput struct: counts the requests and bytes served, and the rates over the last interval
+pub struct Throughput {
+    requests: AtomicU64,
+    bytes: AtomicU64,
+    // Counters and time of the last update
+    last: Mutex<(u64, u64, Instant)>,
+    // Requests per second and egress Mbps over the last interval
+    rates: Mutex<(f64, f64)>,
+}
+
+impl Throughput {
+    // This function is used to create the counters
+    pub fn new() -> Self {
+        Throughput {
+            requests: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            last: Mutex::new((0, 0, Instant::now())),
+            rates: Mutex::new((0.0, 0.0)),
+        }
+    }
+
+    // This function is used to count one request
+    pub fn add_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // This function is used to count bytes sent to clients
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // This function is used to compute the rates since the last update. It's called periodically.
+    pub fn update(&self) {
+        let requests = self.requests.load(Ordering::Relaxed);
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let mut last = self.last.lock().unwrap();
+        let elapsed = last.2.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            let rps = (requests - last.0) as f64 / elapsed;
+            let mbps = (bytes - last.1) as f64 * 8.0 / 1_000_000.0 / elapsed;
+            *self.rates.lock().unwrap() = (rps, mbps);
+        }
+        *last = (requests, bytes, Instant::now());
+    }
+
+    // This function is used to report the rates as "requests_per_second egress_mbps"
+    pub fn report(&self) -> String {
+        let (rps, mbps) = *self.rates.lock().unwrap();
+        format!("{:.2} {:.3}", rps, mbps)
+    }
+}